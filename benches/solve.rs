@@ -0,0 +1,42 @@
+use battery_optimisation::{calculation, Config, Data};
+use chrono::{TimeDelta, Utc};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+// a day of 15 minutes intervals with a morning and an evening peak above the grid limit
+fn one_day() -> (Vec<Data>, Config) {
+  let start = Utc::now();
+  let quarter = TimeDelta::minutes(15);
+  let data = (0..96)
+    .map(|i| {
+      let hour = i as f64 / 4.0;
+      let peak = |at: f64| 3.0 * (-(hour - at) * (hour - at) / 2.0).exp();
+      Data {
+        start: start + quarter * i,
+        end: start + quarter * (i + 1),
+        power: 5.0 + peak(8.0) + peak(19.0),
+        price: 0.2 + 0.1 * (hour / 24.0 * std::f64::consts::TAU).sin().abs() + 0.01 * (i % 4) as f64,
+        ..Default::default()
+      }
+    })
+    .collect();
+  let config = toml::from_str(
+    "max_consumption = 7.0
+battery_capacity = 4.0
+battery_max_charge = 2.0
+battery_initial_charge = 2.0
+battery_efficiency = 0.9
+battery_final_charge = 2.0
+",
+  )
+  .unwrap();
+  (data, config)
+}
+
+fn solve_one_day(c: &mut Criterion) {
+  let (data, config) = one_day();
+  assert!(calculation(&data, &config).is_ok(), "the synthetic day has to be solvable");
+  c.bench_function("calculation of 96 intervals", |b| b.iter(|| calculation(black_box(&data), black_box(&config))));
+}
+
+criterion_group!(benches, solve_one_day);
+criterion_main!(benches);
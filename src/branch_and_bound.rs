@@ -0,0 +1,146 @@
+//! Branch and bound over the simplex in `dual_simplex`, for variables that are either off or fully on.
+//! The linear relaxation is solved first, a variable in between is then fixed at zero in one branch and
+//! at least its on value in the other. The branches double with every variable, fine for short horizons.
+use log::debug;
+
+use crate::dual_simplex::{Matrix, SolveError};
+
+// values closer than this to zero or to the on value are taken as being there
+const TOLERANCE: f64 = 0.0001;
+
+/// A column of the tableau that can only be zero or `on`
+#[derive(Clone, Copy, Debug)]
+pub struct OnOff {
+  pub col: usize,
+  pub on: f64,
+}
+
+/// Minimises the tableau, laid out as by `build_tableau`, with every `on_off` variable at zero or at its on value.
+/// Returns the values of the variables like `Matrix::get_solution`.
+pub fn solve_on_off(
+  tableau: Vec<Vec<f64>>,
+  variables: usize,
+  artificials: usize,
+  on_off: &[OnOff],
+) -> Result<Vec<f64>, SolveError> {
+  let mut best = None;
+  branch(tableau, variables, artificials, on_off, &mut best)?;
+  best.map(|(_, solution)| solution).ok_or(SolveError::Infeasible)
+}
+
+fn branch(
+  tableau: Vec<Vec<f64>>,
+  variables: usize,
+  artificials: usize,
+  on_off: &[OnOff],
+  best: &mut Option<(f64, Vec<f64>)>,
+) -> Result<(), SolveError> {
+  let mut matrix = Matrix::new(tableau.clone(), variables, artificials);
+  match matrix.solve_with_stats() {
+    Ok(_) => {}
+    Err(SolveError::Infeasible) => return Ok(()),
+    Err(e) => return Err(e),
+  }
+  let objective = matrix.get_objective_value();
+  // the relaxation is the best this branch can do
+  if best.as_ref().is_some_and(|(incumbent, _)| objective >= incumbent - TOLERANCE) {
+    return Ok(());
+  }
+  let solution = matrix.get_solution();
+  let between = on_off.iter().find(|v| solution[v.col] > TOLERANCE && solution[v.col] < v.on - TOLERANCE);
+  match between {
+    None => {
+      debug!("New best solution with objective {objective}");
+      *best = Some((objective, solution));
+    }
+    Some(v) => {
+      debug!("Branching on column {} at {}", v.col, solution[v.col]);
+      branch(with_bound(&tableau, artificials, v.col, 0.0, false), variables, artificials, on_off, best)?;
+      branch(with_bound(&tableau, artificials, v.col, v.on, true), variables, artificials + 1, on_off, best)?;
+    }
+  }
+  Ok(())
+}
+
+// a copy of the tableau with one more constraint, `col` at most or at least `value`.
+// The slack goes after the other slacks and an at least row gets an artificial after the other artificials.
+fn with_bound(tableau: &[Vec<f64>], artificials: usize, col: usize, value: f64, at_least: bool) -> Vec<Vec<f64>> {
+  let slack = tableau[0].len() - 1 - artificials;
+  let mut result: Vec<Vec<f64>> = tableau
+    .iter()
+    .map(|row| {
+      let mut row = row.clone();
+      row.insert(slack, 0.0);
+      if at_least {
+        row.insert(row.len() - 1, 0.0);
+      }
+      row
+    })
+    .collect();
+  let cols = result[0].len();
+  let mut equation = vec![0.0; cols];
+  equation[col] = 1.0;
+  equation[cols - 1] = value;
+  if at_least {
+    // the s
+    equation[slack] = -1.0;
+    let intermediate = result.last_mut().expect("the tableau has an intermediate row");
+    for (i, e) in intermediate.iter_mut().zip(&equation) {
+      *i += e;
+    }
+    // the a
+    equation[cols - 2] = 1.0;
+  } else {
+    // the s
+    equation[slack] = 1.0;
+  }
+  // the constraints come before the objective and the intermediate rows
+  result.insert(result.len() - 2, equation);
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::dual_simplex::TableauBuilder;
+
+  #[test]
+  fn bounds_are_added_as_constraints() {
+    // x <= 3, the objective maximises x
+    let (tableau, variables, artificials) = TableauBuilder::new(1).add_le(&[1.0], 3.0).objective(&[-1.0]).build();
+    let at_most = with_bound(&tableau, artificials, 0, 2.0, false);
+    let mut matrix = Matrix::new(at_most, variables, artificials);
+    matrix.solve_with_stats().unwrap();
+    assert_eq!(matrix.get_solution(), vec![2.0]);
+
+    // with the objective turned around x still has to reach the bound
+    let (tableau, variables, artificials) = TableauBuilder::new(1).add_le(&[1.0], 3.0).objective(&[1.0]).build();
+    let at_least = with_bound(&tableau, artificials, 0, 1.0, true);
+    let mut matrix = Matrix::new(at_least, variables, artificials + 1);
+    matrix.solve_with_stats().unwrap();
+    assert_eq!(matrix.get_solution(), vec![1.0]);
+  }
+
+  #[test]
+  fn on_or_off() {
+    // x + y >= 1.5 with x, y in {0, 1}, x cheaper than y
+    let (tableau, variables, artificials) = TableauBuilder::new(2)
+      .add_le(&[1.0, 0.0], 1.0)
+      .add_le(&[0.0, 1.0], 1.0)
+      .add_ge(&[1.0, 1.0], 1.5)
+      .objective(&[1.0, 2.0])
+      .build();
+    let on_off = [OnOff { col: 0, on: 1.0 }, OnOff { col: 1, on: 1.0 }];
+    let solution = solve_on_off(tableau.clone(), variables, artificials, &on_off).unwrap();
+    assert_eq!(solution, vec![1.0, 1.0]);
+
+    // more than both can give
+    let (tableau, variables, artificials) = TableauBuilder::new(2)
+      .add_le(&[1.0, 0.0], 1.0)
+      .add_le(&[0.0, 1.0], 1.0)
+      .add_ge(&[1.0, 1.0], 2.5)
+      .objective(&[1.0, 2.0])
+      .build();
+    assert_eq!(solve_on_off(tableau, variables, artificials, &on_off), Err(SolveError::Infeasible));
+  }
+}
@@ -1,52 +1,797 @@
-use log::debug;
+use log::{debug, info, log_enabled, Level};
+use rayon::prelude::*;
 
+#[cfg(feature = "milp")]
+use crate::branch_and_bound::{solve_on_off, OnOff};
+#[cfg(feature = "revised")]
+use crate::revised_simplex::RevisedSimplex;
 use crate::{
-  data::{Config, Data, Plan},
-  dual_simplex::Matrix,
-  tableau_creation::build_tableau,
+  data::{Battery, BatteryPlan, Config, Data, Objective, Plan, Planning, WH_PER_KWH},
+  dual_simplex::{Matrix, SolveError, SolveStats},
+  tableau_creation::{
+    battery_columns, build_tableau, build_tableau_with_kinds, build_tableau_with_rows, count_exports, flow_columns,
+    ConstraintKind, Flow, Row,
+  },
 };
 
-pub fn calculation(data: &[Data], config: &Config) -> Result<Vec<Plan>, String> {
+/// The constraint that makes the problem impossible, `required` is what its left hand side has to reach
+/// and `achievable` the closest the search got. Battery constraints are in Wh, the peak in W.
+#[derive(Debug, PartialEq)]
+pub struct Infeasibility {
+  pub constraint: ConstraintKind,
+  pub required: f64,
+  pub achievable: f64,
+}
+
+impl std::fmt::Display for Infeasibility {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{:?} constraint needs {} but at most {} is possible", self.constraint, self.required, self.achievable)
+  }
+}
+
+/// Builds the tableau and runs both phases on it. The matrix is returned even when solving fails,
+/// its last state helps finding out why.
+pub fn solve(data: &[Data], config: &Config) -> (Matrix, Result<SolveStats, SolveError>) {
   let (tableau, variables, artificials) = build_tableau(data, config);
   let mut matrix = Matrix::new(tableau, variables, artificials);
+  let stats = matrix.solve_with_stats();
+  (matrix, stats)
+}
+
+/// Looks for the reason there is no plan, None when there is one.
+/// Phase one leaves an artificial above zero for every constraint it could not meet,
+/// the one with the largest shortfall is reported.
+pub fn explain(data: &[Data], config: &Config) -> Option<Infeasibility> {
+  let (tableau, variables, artificials, kinds) = build_tableau_with_kinds(data, config);
+  let original = tableau.clone();
+  let mut matrix = Matrix::new(tableau, variables, artificials);
+  if matrix.solve() != Err(SolveError::Infeasible) {
+    return None;
+  }
+  let (col, shortfall) = matrix.remaining_artificials().into_iter().max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+  // the artificial of a row has a 1 in that row only
+  let row = original.iter().position(|r| r[col] == 1.0)?;
+  let limit = original[row][original[row].len() - 1];
+  let constraint = kinds[row];
+  let scale = match constraint {
+    ConstraintKind::Peak
+    | ConstraintKind::MinGrid
+    | ConstraintKind::Overload
+    | ConstraintKind::DischargeRate
+    | ConstraintKind::GridLimit
+    | ConstraintKind::Balance
+    | ConstraintKind::ForcedCharge
+    | ConstraintKind::Ramp => 1.0,
+    _ => config.intervals_per_hour,
+  };
+  Some(Infeasibility { constraint, required: limit / scale, achievable: (limit - shortfall) / scale })
+}
+
+/// Converts a value of the objective row into currency. The tableau has the power of every interval in W
+/// and the prices per kWh, an interval lasting 1 / intervals_per_hour hours. The prices are not scaled
+/// in the tableau itself, the cost of a W in a single interval would fall below the tolerance of the solver.
+pub fn objective_in_currency(objective_value: f64, config: &Config) -> f64 {
+  objective_value / config.intervals_per_hour / WH_PER_KWH
+}
+
+pub fn calculation(data: &[Data], config: &Config) -> Result<Planning, SolveError> {
+  calculation_with_dump(data, config, None)
+}
+
+/// Same as `calculation`, `dump` gets the state of the solver at the end of this very solve, see
+/// `Matrix::to_debug_json`. It is filled even when solving fails, not when nothing was solved.
+pub fn calculation_with_dump(
+  data: &[Data],
+  config: &Config,
+  dump: Option<&mut serde_json::Value>,
+) -> Result<Planning, SolveError> {
+  check_overloads(data, config)?;
+  let (solution, binding) = solve_for_solution(data, config, dump)?;
+  let mut planning = make_planning(data, config, &solution);
+  for (plan, binding) in planning.plans.iter_mut().zip(binding) {
+    plan.binding_constraints = binding;
+  }
+  if log_enabled!(Level::Info) {
+    for line in plan_log_lines(data, config, &planning.plans) {
+      info!("{line}");
+    }
+  }
+  Ok(planning)
+}
+
+// the values of the variables and the binding constraints of every interval
+#[cfg(not(feature = "revised"))]
+fn solve_for_solution(
+  data: &[Data],
+  config: &Config,
+  dump: Option<&mut serde_json::Value>,
+) -> Result<(Vec<f64>, Vec<Vec<String>>), SolveError> {
+  let (tableau, variables, artificials, rows) = build_tableau_with_rows(data, config);
+  let slack_rows = slack_rows(&tableau, variables, artificials, &rows);
+  let mut matrix = Matrix::new(tableau, variables, artificials);
+  let stats = matrix.solve_with_stats();
+  if let Some(dump) = dump {
+    *dump = matrix.to_debug_json();
+  }
+  let stats = stats?;
+  debug!("{:?}", stats);
+  Ok((matrix.get_solution(), binding_constraints(data.len(), &slack_rows, &matrix.get_slacks())))
+}
+
+// the same program, solved by the revised simplex
+#[cfg(feature = "revised")]
+fn solve_for_solution(
+  data: &[Data],
+  config: &Config,
+  dump: Option<&mut serde_json::Value>,
+) -> Result<(Vec<f64>, Vec<Vec<String>>), SolveError> {
+  let (tableau, variables, artificials, rows) = build_tableau_with_rows(data, config);
+  let slack_rows = slack_rows(&tableau, variables, artificials, &rows);
+  let mut revised = RevisedSimplex::new(tableau, variables, artificials);
+  let stats = revised.solve_with_stats();
+  if let Some(dump) = dump {
+    *dump = revised.to_debug_json();
+  }
+  let stats = stats?;
+  debug!("{:?}", stats);
+  Ok((revised.get_solution(), binding_constraints(data.len(), &slack_rows, &revised.get_slacks())))
+}
+
+// the kind and the interval of the rows with a slack column, in the order of the slacks, the equalities have none
+fn slack_rows(tableau: &[Vec<f64>], variables: usize, artificials: usize, rows: &[Row]) -> Vec<Row> {
+  let slacks = variables..tableau[0].len() - artificials - 1;
+  rows.iter().zip(tableau).filter(|(_, row)| row[slacks.clone()].iter().any(|&v| v != 0.0)).map(|(&row, _)| row).collect()
+}
+
+// the kinds of the constraints left without slack, interval by interval, every kind once
+fn binding_constraints(intervals: usize, rows: &[Row], slacks: &[f64]) -> Vec<Vec<String>> {
+  let mut binding = vec![Vec::new(); intervals];
+  for (&(kind, interval), &slack) in rows.iter().zip(slacks) {
+    let name = format!("{kind:?}");
+    if let Some(binding) = binding.get_mut(interval).filter(|b| slack.abs() < VERIFY_TOLERANCE && !b.contains(&name)) {
+      binding.push(name);
+    }
+  }
+  binding
+}
+
+// one line per interval for the operators tailing the logs: when, what the battery does, how much and at what price
+fn plan_log_lines(data: &[Data], config: &Config, plans: &[Plan]) -> Vec<String> {
+  data
+    .iter()
+    .zip(plans)
+    .map(|(d, plan)| {
+      let (action, energy) = match Mode::of(plan) {
+        Mode::Charge => ("charge", plan.energy_to_battery_wh),
+        Mode::Discharge => ("discharge", plan.energy_from_battery_wh + plan.energy_exported_wh),
+        Mode::Idle => ("idle", 0.0),
+      };
+      format!(
+        "{} {action} {:.1} {} at {} {}/kWh",
+        plan.start.to_rfc3339(),
+        energy / config.energy_unit.wh_per_unit(),
+        config.energy_unit.label(),
+        config.grid_price(d.price),
+        config.currency
+      )
+    })
+    .collect()
+}
+
+/// Same as `calculation`, except that the battery charges at its highest rate or not at all, for inverters
+/// that can not modulate. The highest rate is `battery_max_charge`, or less when the grid limit leaves less.
+#[cfg(feature = "milp")]
+pub fn calculation_binary(data: &[Data], config: &Config) -> Result<Planning, SolveError> {
+  check_overloads(data, config)?;
+  let (tableau, variables, artificials, kinds) = build_tableau_with_kinds(data, config);
+  // every charge has a max charge row with a 1 in its column, the limit of the row is the charge when on
+  let on_off: Vec<OnOff> = tableau
+    .iter()
+    .zip(&kinds)
+    .filter(|(_, kind)| **kind == ConstraintKind::MaxCharge)
+    // the grid limit of several batteries is a max charge row for all of them
+    .filter(|(row, _)| row[..variables].iter().filter(|&&v| v != 0.0).count() == 1)
+    .filter_map(|(row, _)| row[..variables].iter().position(|&v| v == 1.0).map(|col| OnOff { col, on: row[row.len() - 1] }))
+    .collect();
+  let solution = solve_on_off(tableau, variables, artificials, &on_off)?;
+  Ok(make_planning(data, config, &solution))
+}
+
+// what the linear program can not express, an overload the battery is not allowed to or can not cover
+fn check_overloads(data: &[Data], config: &Config) -> Result<(), SolveError> {
+  // only the battery can cover an overload
+  if data.iter().any(|d| !d.allow_discharge && d.is_overload(config)) {
+    debug!("Discharging is not allowed during an overload");
+    return Err(SolveError::Infeasible);
+  }
+  // nor faster than it can discharge, several batteries have their own rates in the linear program
+  let max_discharge = config.battery_max_discharge.filter(|_| config.batteries.is_empty()).unwrap_or(f64::INFINITY);
+  if let Some(i) = data.iter().position(|d| d.net_power() - d.max_consumption(config) > max_discharge) {
+    debug!("The overload in interval {i} is more than the battery can discharge");
+    return Err(SolveError::Infeasible);
+  }
+  check_forced(data, config)
+}
+
+// a forced charge or discharge has to fit the grid, the rates and the battery on its own, whatever the other intervals do.
+// Several batteries have their own rates and capacities, the linear program checks them
+fn check_forced(data: &[Data], config: &Config) -> Result<(), SolveError> {
+  let single = config.batteries.is_empty();
+  for (i, d) in data.iter().enumerate() {
+    if let Some(forced) = d.forced_charge {
+      let grid_left = d.connection_limit(config) - d.net_power();
+      let stored = config.charge_after(0.0, forced / config.intervals_per_hour);
+      if !d.allow_charge
+        || forced > grid_left
+        || single && (forced > config.battery_max_charge || stored > d.battery_capacity(config))
+      {
+        debug!("The forced charge of {forced} in interval {i} does not fit the grid or the battery");
+        return Err(SolveError::Infeasible);
+      }
+    }
+    if let Some(forced) = d.forced_discharge {
+      let taken = forced / config.intervals_per_hour / config.discharge_efficiency();
+      if d.max_consumption(config) > d.connection_limit(config) || single && taken > d.battery_capacity(config) {
+        debug!("The forced discharge of {forced} in interval {i} does not fit the grid or the battery");
+        return Err(SolveError::Infeasible);
+      }
+    }
+  }
+  Ok(())
+}
 
-  matrix.solve()?;
-  matrix.phase_two();
-  matrix.solve()?;
-  let solution = matrix.get_solution();
-  let count_vars = data.iter().filter(|d| d.power <= config.max_consumption).count();
+// the plan of every interval from the values of the variables
+fn make_planning(data: &[Data], config: &Config, solution: &[f64]) -> Planning {
+  if !config.batteries.is_empty() {
+    return make_batteries_planning(data, config, &config.batteries, solution);
+  }
+  if config.objective == Objective::MaxSelfConsumption {
+    return make_self_consumption_planning(data, config, solution);
+  }
+  // the segments are parts of the one battery, the plan only has their totals
+  if !config.efficiency_segments.is_empty() {
+    let mut planning = make_batteries_planning(data, config, &config.segment_batteries(), solution);
+    planning.plans.iter_mut().for_each(|plan| plan.batteries.clear());
+    return planning;
+  }
+  let count_vars = data.iter().filter(|d| !d.is_overload(config)).count();
   debug!("The solution is: {:?}", &solution[0..count_vars]);
   // make the plan
   let mut planning: Vec<Plan> = Vec::with_capacity(data.len());
   // if we use more than the limit we get it from battery, otherwise we charge the battery
+  // the exports, when energy can be sold, follow the charges, one for every interval,
+  // then the discharges to the consumers outside the overloads
+  let count_export = count_exports(data, config);
+  let exports = &solution[count_vars..count_vars + count_export];
+  let own_use = &solution[count_vars + count_export..count_vars + 2 * count_export];
+  // what the charging costs, the exports earn and the discharges to the consumers save, the objective might not be the cost
+  let mut charge_cost = 0.0;
   let mut solution_offset = 0;
-  for d in data {
-    if d.power <= config.max_consumption {
-      planning.push(Plan {
-        start: d.start,
-        end: d.end,
-        energy_to_battery_wh: solution[solution_offset] / 4.0,
-        energy_from_battery_wh: 0.0,
-      });
+  // the charge of the battery at the end of each interval
+  let mut battery_charge_wh = config.battery_initial_charge;
+  let decay = config.decay_per_interval();
+  for (i, d) in data.iter().enumerate() {
+    let energy_exported_wh = exports.get(i).map_or(0.0, |e| e / config.intervals_per_hour);
+    charge_cost -= exports.get(i).unwrap_or(&0.0) * d.sell_price(config).unwrap_or(0.0);
+    let own_use = own_use.get(i).unwrap_or(&0.0);
+    charge_cost -= config.grid_price(d.price) * own_use;
+    let (energy_to_battery_wh, energy_from_battery_wh) = if !d.is_overload(config) {
       solution_offset += 1;
+      charge_cost += config.grid_price(d.price) * solution[solution_offset - 1];
+      (solution[solution_offset - 1] / config.intervals_per_hour, own_use / config.intervals_per_hour)
     } else {
-      planning.push(Plan {
+      (0.0, (d.power - d.max_consumption(config) + own_use) / config.intervals_per_hour)
+    };
+    battery_charge_wh = battery_charge_wh * decay + energy_to_battery_wh * config.charge_efficiency()
+      - (energy_from_battery_wh + energy_exported_wh) / config.discharge_efficiency();
+    planning.push(Plan {
+      start: d.start,
+      end: d.end,
+      energy_to_battery_wh,
+      energy_from_battery_wh,
+      energy_exported_wh,
+      energy_imported_wh: imported_wh(d, config, energy_to_battery_wh, energy_from_battery_wh),
+      battery_charge_wh,
+      consumption_power: Some(d.power),
+      price: Some(d.price),
+      batteries: Vec::new(),
+      binding_constraints: Vec::new(),
+    });
+  }
+  // the rest of the bill is the consumption capped at the limit, the overload being covered by the battery
+  let consumption_cost: f64 = data.iter().map(|d| config.grid_price(d.price) * d.power.min(d.max_consumption(config))).sum();
+  let total_cost = (consumption_cost + charge_cost) / config.intervals_per_hour / WH_PER_KWH;
+  debug!("Charging cost: {charge_cost}, total cost: {total_cost}");
+  // with equal initial and final charges the discharged energy is the charged energy times the efficiency
+  let total_charged_wh: f64 = planning.iter().map(|p| p.energy_to_battery_wh).sum();
+  let total_discharged_wh: f64 = planning.iter().map(|p| p.energy_from_battery_wh + p.energy_exported_wh).sum();
+  // an empty battery never cycles
+  let equivalent_full_cycles = if config.battery_capacity > 0.0 { total_discharged_wh / config.battery_capacity } else { 0.0 };
+  // the shortfall is the last variable, it is in the same units as the final charge row
+  let final_charge_shortfall_wh = match config.final_charge_penalty {
+    Some(_) => solution.last().map_or(0.0, |shortfall| shortfall / config.intervals_per_hour),
+    None => 0.0,
+  };
+  let grid_power = grid_power(data, config, &planning);
+  Planning {
+    plans: planning,
+    total_cost,
+    equivalent_full_cycles,
+    final_charge_shortfall_wh,
+    total_charged_wh,
+    total_discharged_wh,
+    grid_power,
+  }
+}
+
+// the plan of several batteries, every interval has the share of each battery and their totals
+fn make_batteries_planning(data: &[Data], config: &Config, batteries: &[Battery], solution: &[f64]) -> Planning {
+  let iph = config.intervals_per_hour;
+  let mut charges: Vec<f64> = batteries.iter().map(|b| b.initial_charge).collect();
+  let mut charge_cost = 0.0;
+  let mut planning: Vec<Plan> = Vec::with_capacity(data.len());
+  for (i, d) in data.iter().enumerate() {
+    let overload = d.is_overload(config);
+    let batteries: Vec<BatteryPlan> = batteries
+      .iter()
+      .enumerate()
+      .map(|(b, battery)| {
+        let energy = solution[battery_columns(data, b).start + i] / iph;
+        let (energy_to_battery_wh, energy_from_battery_wh) = if overload { (0.0, energy) } else { (energy, 0.0) };
+        charges[b] += energy_to_battery_wh * battery.charge_efficiency - energy_from_battery_wh / battery.discharge_efficiency;
+        BatteryPlan { energy_from_battery_wh, energy_to_battery_wh, battery_charge_wh: charges[b] }
+      })
+      .collect();
+    let energy_to_battery_wh: f64 = batteries.iter().map(|b| b.energy_to_battery_wh).sum();
+    let energy_from_battery_wh: f64 = batteries.iter().map(|b| b.energy_from_battery_wh).sum();
+    charge_cost += config.grid_price(d.price) * energy_to_battery_wh * iph;
+    planning.push(Plan {
+      start: d.start,
+      end: d.end,
+      energy_from_battery_wh,
+      energy_to_battery_wh,
+      energy_exported_wh: 0.0,
+      energy_imported_wh: imported_wh(d, config, energy_to_battery_wh, energy_from_battery_wh),
+      battery_charge_wh: charges.iter().sum(),
+      consumption_power: Some(d.power),
+      price: Some(d.price),
+      batteries,
+      binding_constraints: Vec::new(),
+    });
+  }
+  let consumption_cost: f64 = data.iter().map(|d| config.grid_price(d.price) * d.power.min(d.max_consumption(config))).sum();
+  let total_cost = (consumption_cost + charge_cost) / iph / WH_PER_KWH;
+  let total_charged_wh: f64 = planning.iter().map(|p| p.energy_to_battery_wh).sum();
+  let total_discharged_wh: f64 = planning.iter().map(|p| p.energy_from_battery_wh).sum();
+  let capacity: f64 = batteries.iter().map(|b| b.capacity).sum();
+  let equivalent_full_cycles = if capacity > 0.0 { total_discharged_wh / capacity } else { 0.0 };
+  let grid_power = grid_power(data, config, &planning);
+  Planning {
+    plans: planning,
+    total_cost,
+    equivalent_full_cycles,
+    final_charge_shortfall_wh: 0.0,
+    total_charged_wh,
+    total_discharged_wh,
+    grid_power,
+  }
+}
+
+// the plan of the self consumption, the flows of the battery are variables in every interval
+fn make_self_consumption_planning(data: &[Data], config: &Config, solution: &[f64]) -> Planning {
+  let iph = config.intervals_per_hour;
+  let flow = |flow: Flow, i: usize| solution[flow_columns(data, flow).start + i] / iph;
+  let decay = config.decay_per_interval();
+  let mut battery_charge_wh = config.battery_initial_charge;
+  let plans: Vec<Plan> = data
+    .iter()
+    .enumerate()
+    .map(|(i, d)| {
+      let (energy_to_battery_wh, energy_from_battery_wh) = (flow(Flow::Charge, i), flow(Flow::Discharge, i));
+      battery_charge_wh = battery_charge_wh * decay + energy_to_battery_wh * config.charge_efficiency()
+        - energy_from_battery_wh / config.discharge_efficiency();
+      Plan {
         start: d.start,
         end: d.end,
-        energy_to_battery_wh: 0.0,
-        energy_from_battery_wh: (d.power - config.max_consumption) / 4.0,
+        energy_to_battery_wh,
+        energy_from_battery_wh,
+        energy_exported_wh: 0.0,
+        energy_imported_wh: imported_wh(d, config, energy_to_battery_wh, energy_from_battery_wh),
+        battery_charge_wh,
+        consumption_power: Some(d.power),
+        price: Some(d.price),
+        batteries: Vec::new(),
+        binding_constraints: Vec::new(),
+      }
+    })
+    .collect();
+  let grid_power = grid_power(data, config, &plans);
+  // the import costs the buy price, the surplus sent to the grid earns the sell price when it can be sold
+  let cost: f64 = data
+    .iter()
+    .zip(&grid_power)
+    .map(|(d, &grid)| config.grid_price(d.price) * grid.max(0.0) + d.sell_price(config).unwrap_or(0.0) * grid.min(0.0))
+    .sum();
+  let total_charged_wh: f64 = plans.iter().map(|p| p.energy_to_battery_wh).sum();
+  let total_discharged_wh: f64 = plans.iter().map(|p| p.energy_from_battery_wh).sum();
+  let equivalent_full_cycles = if config.battery_capacity > 0.0 { total_discharged_wh / config.battery_capacity } else { 0.0 };
+  Planning {
+    plans,
+    total_cost: cost / iph / WH_PER_KWH,
+    equivalent_full_cycles,
+    final_charge_shortfall_wh: 0.0,
+    total_charged_wh,
+    total_discharged_wh,
+    grid_power,
+  }
+}
+
+// the charging is taken from the grid before its losses, the discharging delivers after its losses
+fn grid_power(data: &[Data], config: &Config, plans: &[Plan]) -> Vec<f64> {
+  data
+    .iter()
+    .zip(plans)
+    .map(|(d, p)| {
+      d.net_power() + (p.energy_to_battery_wh - p.energy_from_battery_wh - p.energy_exported_wh) * config.intervals_per_hour
+    })
+    .collect()
+}
+
+// what the consumers and the battery take from the grid, the battery gives the consumers at most what they take
+fn imported_wh(d: &Data, config: &Config, energy_to_battery_wh: f64, energy_from_battery_wh: f64) -> f64 {
+  (d.net_power() / config.intervals_per_hour + energy_to_battery_wh - energy_from_battery_wh).max(0.0)
+}
+
+/// A naive plan to compare the optimum with: the battery charges as much as it can whenever the consumption is
+/// under the limit and discharges to cover every overload, whatever the prices. Only for a single battery, nothing
+/// is exported and the final charge is what is left. An overload the battery can not cover stays on the grid,
+/// `verify_plan` tells where.
+pub fn greedy_peak_shave(data: &[Data], config: &Config) -> Vec<Plan> {
+  let iph = config.intervals_per_hour;
+  let decay = config.decay_per_interval();
+  let mut battery_charge_wh = config.battery_initial_charge;
+  data
+    .iter()
+    .map(|d| {
+      let stored = battery_charge_wh * decay;
+      let limit = d.max_consumption(config);
+      let (energy_to_battery_wh, energy_from_battery_wh) = if d.power <= limit {
+        let max_charge = match d.forced_charge {
+          Some(forced) => forced,
+          None if d.allow_charge => config.battery_max_charge.min(limit - d.power),
+          None => 0.0,
+        };
+        let room = config.energy_to_fill(stored, d.battery_capacity(config));
+        ((max_charge / iph).min(room), 0.0)
+      } else {
+        let max_discharge = if d.allow_discharge { config.battery_max_discharge.unwrap_or(f64::INFINITY) } else { 0.0 };
+        let available = (stored - d.min_charge(config)).max(0.0) * config.discharge_efficiency();
+        (0.0, ((d.power - limit).min(max_discharge) / iph).min(available))
+      };
+      battery_charge_wh =
+        config.charge_after(stored, energy_to_battery_wh) - energy_from_battery_wh / config.discharge_efficiency();
+      Plan {
+        start: d.start,
+        end: d.end,
+        energy_to_battery_wh,
+        energy_from_battery_wh,
+        energy_exported_wh: 0.0,
+        energy_imported_wh: imported_wh(d, config, energy_to_battery_wh, energy_from_battery_wh),
+        battery_charge_wh,
+        consumption_power: Some(d.power),
+        price: Some(d.price),
+        batteries: Vec::new(),
+        binding_constraints: Vec::new(),
+      }
+    })
+    .collect()
+}
+
+/// The cost and the totals of plans made without the solver, by `greedy_peak_shave` for example,
+/// so that they can be summarised and compared with the optimum
+pub fn planning_from_plans(data: &[Data], config: &Config, plans: Vec<Plan>) -> Planning {
+  let iph = config.intervals_per_hour;
+  let grid_power = grid_power(data, config, &plans);
+  // the energy taken from the grid costs the buy price, the energy sold earns the sell price
+  let cost: f64 = data
+    .iter()
+    .zip(&plans)
+    .map(|(d, p)| {
+      let imported = imported_wh(d, config, p.energy_to_battery_wh, p.energy_from_battery_wh) * iph;
+      config.grid_price(d.price) * imported - d.sell_price(config).unwrap_or(0.0) * p.energy_exported_wh * iph
+    })
+    .sum();
+  let total_charged_wh: f64 = plans.iter().map(|p| p.energy_to_battery_wh).sum();
+  let total_discharged_wh: f64 = plans.iter().map(|p| p.energy_from_battery_wh + p.energy_exported_wh).sum();
+  let (capacity, final_charge) = if config.batteries.is_empty() {
+    (config.battery_capacity, config.battery_final_charge)
+  } else {
+    (config.batteries.iter().map(|b| b.capacity).sum(), config.batteries.iter().map(|b| b.final_charge).sum())
+  };
+  let equivalent_full_cycles = if capacity > 0.0 { total_discharged_wh / capacity } else { 0.0 };
+  let final_charge_shortfall_wh = plans.last().map_or(0.0, |p| (final_charge - p.battery_charge_wh).max(0.0));
+  Planning {
+    plans,
+    total_cost: cost / iph / WH_PER_KWH,
+    equivalent_full_cycles,
+    final_charge_shortfall_wh,
+    total_charged_wh,
+    total_discharged_wh,
+    grid_power,
+  }
+}
+
+/// The start of a plan, carried out before planning again
+#[derive(Debug)]
+pub struct Commitment {
+  pub plans: Vec<Plan>,
+  /// the charge of the battery after the committed intervals, the initial charge of the next plan
+  pub battery_charge_wh: f64,
+}
+
+/// Plans the whole horizon but only commits to its first `commit_intervals` intervals. The caller carries
+/// them out, moves the horizon forward with updated forecasts and plans again from `battery_charge_wh`.
+pub fn calculation_receding(data: &[Data], config: &Config, commit_intervals: usize) -> Result<Commitment, SolveError> {
+  let mut planning = calculation(data, config)?;
+  planning.plans.truncate(commit_intervals);
+  let battery_charge_wh = planning.plans.last().map_or(config.battery_initial_charge, |p| p.battery_charge_wh);
+  Ok(Commitment { plans: planning.plans, battery_charge_wh })
+}
+
+/// Plans consecutive windows of `window_len` intervals, a day for example, in parallel and joins the plans.
+/// Every window has to end with `battery_final_charge` and every window but the first starts with it.
+/// The result is only the optimum when nothing is gained by carrying energy from one window to the next:
+/// a window starts with exactly the final charge even when the previous one ended with more.
+pub fn calculation_windowed(data: &[Data], config: &Config, window_len: usize) -> Result<Planning, SolveError> {
+  let windows: Vec<Planning> = data
+    .par_chunks(window_len.max(1))
+    .enumerate()
+    .map(|(i, window)| {
+      let mut window_config = config.clone();
+      if i > 0 {
+        window_config.battery_initial_charge = config.battery_final_charge;
+      }
+      calculation(window, &window_config)
+    })
+    .collect::<Result<_, _>>()?;
+  let mut planning = Planning {
+    plans: Vec::with_capacity(data.len()),
+    total_cost: 0.0,
+    equivalent_full_cycles: 0.0,
+    final_charge_shortfall_wh: 0.0,
+    total_charged_wh: 0.0,
+    total_discharged_wh: 0.0,
+    grid_power: Vec::with_capacity(data.len()),
+  };
+  for window in windows {
+    planning.plans.extend(window.plans);
+    planning.total_cost += window.total_cost;
+    planning.equivalent_full_cycles += window.equivalent_full_cycles;
+    planning.final_charge_shortfall_wh += window.final_charge_shortfall_wh;
+    planning.total_charged_wh += window.total_charged_wh;
+    planning.total_discharged_wh += window.total_discharged_wh;
+    planning.grid_power.extend(window.grid_power);
+  }
+  Ok(planning)
+}
+
+// plans made by the simplex are off by rounding errors
+const VERIFY_TOLERANCE: f64 = 1e-6;
+
+/// What a plan checked by `verify_plan` can break
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlanConstraint {
+  /// the plan does not have one entry per interval, the value is its length and the limit the intervals
+  Intervals,
+  /// an energy of the plan is negative
+  Negative,
+  /// charging faster than battery_max_charge, or at all where charging is not allowed
+  MaxCharge,
+  /// discharging faster than battery_max_discharge, or at all where discharging is not allowed
+  MaxDischarge,
+  /// more power taken from the grid than its limit
+  GridLimit,
+  /// less power taken from the grid than min_grid_power
+  MinGrid,
+  /// more stored than the battery holds
+  Capacity,
+  /// less stored than min_charge
+  MinCharge,
+  /// less stored at the end than battery_final_charge
+  FinalCharge,
+  /// more energy taken from the grid over the plan than daily_import_quota_wh
+  ImportQuota,
+  /// an interval does not have a plan for every battery, the value is their number
+  Batteries,
+  /// the battery does not charge or discharge the forced power of the interval, the limit
+  Forced,
+  /// the charge changes more than max_ramp_per_interval from the interval before
+  Ramp,
+}
+
+// what the battery does in an interval, charging wins when the plan also discharges to the consumers
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+  Charge,
+  Discharge,
+  Idle,
+}
+
+impl Mode {
+  fn of(plan: &Plan) -> Mode {
+    if plan.energy_to_battery_wh > VERIFY_TOLERANCE {
+      Mode::Charge
+    } else if plan.energy_from_battery_wh + plan.energy_exported_wh > VERIFY_TOLERANCE {
+      Mode::Discharge
+    } else {
+      Mode::Idle
+    }
+  }
+}
+
+/// How often the battery goes from charging to discharging or back, the idle intervals in between do not count.
+/// Every switch wears the inverter.
+pub fn count_mode_switches(plans: &[Plan]) -> usize {
+  let modes: Vec<Mode> = plans.iter().map(Mode::of).filter(|&mode| mode != Mode::Idle).collect();
+  modes.windows(2).filter(|pair| pair[0] != pair[1]).count()
+}
+
+/// The first constraint a plan breaks, the values are in W for the powers and in Wh for the battery charge
+#[derive(Debug, PartialEq)]
+pub struct PlanViolation {
+  pub interval: usize,
+  pub constraint: PlanConstraint,
+  pub value: f64,
+  pub limit: f64,
+}
+
+/// Replays a plan, made by hand or elsewhere, and checks it against the constraints of the battery and of
+/// the grid without solving anything. The charge of the battery is computed again, the one in the plan is
+/// not trusted.
+pub fn verify_plan(data: &[Data], config: &Config, plans: &[Plan]) -> Result<(), PlanViolation> {
+  if plans.len() != data.len() {
+    let interval = plans.len().min(data.len());
+    return Err(PlanViolation {
+      interval,
+      constraint: PlanConstraint::Intervals,
+      value: plans.len() as f64,
+      limit: data.len() as f64,
+    });
+  }
+  if !config.batteries.is_empty() {
+    return verify_batteries(data, config, plans);
+  }
+  let iph = config.intervals_per_hour;
+  let decay = config.decay_per_interval();
+  let mut battery_charge_wh = config.battery_initial_charge;
+  let mut imported_wh = 0.0;
+  for (interval, (d, p)) in data.iter().zip(plans).enumerate() {
+    let violation = |constraint, value, limit| PlanViolation { interval, constraint, value, limit };
+    let energies = [p.energy_to_battery_wh, p.energy_from_battery_wh, p.energy_exported_wh];
+    if let Some(&value) = energies.iter().find(|&&e| e < -VERIFY_TOLERANCE) {
+      return Err(violation(PlanConstraint::Negative, value, 0.0));
+    }
+    // the plan has energies, the limits are powers
+    let charge = p.energy_to_battery_wh * iph;
+    let discharge = (p.energy_from_battery_wh + p.energy_exported_wh) * iph;
+    let max_charge = if d.allow_charge { config.battery_max_charge } else { 0.0 };
+    if charge > max_charge + VERIFY_TOLERANCE {
+      return Err(violation(PlanConstraint::MaxCharge, charge, max_charge));
+    }
+    let max_discharge = if d.allow_discharge { config.battery_max_discharge.unwrap_or(f64::INFINITY) } else { 0.0 };
+    if discharge > max_discharge + VERIFY_TOLERANCE {
+      return Err(violation(PlanConstraint::MaxDischarge, discharge, max_discharge));
+    }
+    // a forced discharge leaves nothing to export
+    for (forced, value) in [(d.forced_charge, charge), (d.forced_discharge, discharge)] {
+      if let Some(forced) = forced.filter(|forced| (value - forced).abs() > VERIFY_TOLERANCE) {
+        return Err(violation(PlanConstraint::Forced, value, forced));
+      }
+    }
+    // only between two intervals that can charge, as planned
+    let ramp =
+      config.max_ramp_per_interval.filter(|_| interval > 0 && !d.is_overload(config) && !data[interval - 1].is_overload(config));
+    if let Some(ramp) = ramp {
+      let change = (charge - plans[interval - 1].energy_to_battery_wh * iph).abs();
+      if change > ramp + VERIFY_TOLERANCE {
+        return Err(violation(PlanConstraint::Ramp, change, ramp));
+      }
+    }
+    let grid = d.net_power() + charge - discharge;
+    if grid > d.max_consumption(config) + VERIFY_TOLERANCE {
+      return Err(violation(PlanConstraint::GridLimit, grid, d.max_consumption(config)));
+    }
+    // without a minimum the exports can feed the grid
+    if config.min_grid_power > 0.0 && grid < config.min_grid_power - VERIFY_TOLERANCE {
+      return Err(violation(PlanConstraint::MinGrid, grid, config.min_grid_power));
+    }
+    imported_wh += grid / iph;
+    battery_charge_wh = config.charge_after(battery_charge_wh * decay, p.energy_to_battery_wh)
+      - (p.energy_from_battery_wh + p.energy_exported_wh) / config.discharge_efficiency();
+    if battery_charge_wh > d.battery_capacity(config) + VERIFY_TOLERANCE {
+      return Err(violation(PlanConstraint::Capacity, battery_charge_wh, d.battery_capacity(config)));
+    }
+    if battery_charge_wh < d.min_charge(config) - VERIFY_TOLERANCE {
+      return Err(violation(PlanConstraint::MinCharge, battery_charge_wh, d.min_charge(config)));
+    }
+  }
+  // with a penalty the final charge is only a target
+  if config.final_charge_penalty.is_none() && battery_charge_wh < config.battery_final_charge - VERIFY_TOLERANCE {
+    return Err(PlanViolation {
+      interval: data.len().saturating_sub(1),
+      constraint: PlanConstraint::FinalCharge,
+      value: battery_charge_wh,
+      limit: config.battery_final_charge,
+    });
+  }
+  if let Some(quota) = config.daily_import_quota_wh.filter(|&quota| imported_wh > quota + VERIFY_TOLERANCE) {
+    return Err(PlanViolation {
+      interval: data.len().saturating_sub(1),
+      constraint: PlanConstraint::ImportQuota,
+      value: imported_wh,
+      limit: quota,
+    });
+  }
+  Ok(())
+}
+
+// the checks of verify_plan for every one of several batteries, the grid limit for all of them
+fn verify_batteries(data: &[Data], config: &Config, plans: &[Plan]) -> Result<(), PlanViolation> {
+  let iph = config.intervals_per_hour;
+  let mut charges: Vec<f64> = config.batteries.iter().map(|b| b.initial_charge).collect();
+  for (interval, (d, p)) in data.iter().zip(plans).enumerate() {
+    let violation = |constraint, value, limit| PlanViolation { interval, constraint, value, limit };
+    if p.batteries.len() != config.batteries.len() {
+      return Err(violation(PlanConstraint::Batteries, p.batteries.len() as f64, config.batteries.len() as f64));
+    }
+    for ((battery, flow), charge) in config.batteries.iter().zip(&p.batteries).zip(charges.iter_mut()) {
+      if let Some(&value) = [flow.energy_to_battery_wh, flow.energy_from_battery_wh].iter().find(|&&e| e < -VERIFY_TOLERANCE) {
+        return Err(violation(PlanConstraint::Negative, value, 0.0));
+      }
+      let max_charge = if d.allow_charge { battery.max_charge } else { 0.0 };
+      if flow.energy_to_battery_wh * iph > max_charge + VERIFY_TOLERANCE {
+        return Err(violation(PlanConstraint::MaxCharge, flow.energy_to_battery_wh * iph, max_charge));
+      }
+      let max_discharge = if d.allow_discharge { battery.max_discharge.unwrap_or(f64::INFINITY) } else { 0.0 };
+      if flow.energy_from_battery_wh * iph > max_discharge + VERIFY_TOLERANCE {
+        return Err(violation(PlanConstraint::MaxDischarge, flow.energy_from_battery_wh * iph, max_discharge));
+      }
+      *charge +=
+        flow.energy_to_battery_wh * battery.charge_efficiency - flow.energy_from_battery_wh / battery.discharge_efficiency;
+      if *charge > battery.capacity + VERIFY_TOLERANCE {
+        return Err(violation(PlanConstraint::Capacity, *charge, battery.capacity));
+      }
+      if *charge < -VERIFY_TOLERANCE {
+        return Err(violation(PlanConstraint::MinCharge, *charge, 0.0));
+      }
+    }
+    let flows: f64 = p.batteries.iter().map(|b| b.energy_to_battery_wh - b.energy_from_battery_wh).sum();
+    let grid = d.power + flows * iph;
+    if grid > d.max_consumption(config) + VERIFY_TOLERANCE {
+      return Err(violation(PlanConstraint::GridLimit, grid, d.max_consumption(config)));
+    }
+  }
+  let interval = data.len().saturating_sub(1);
+  for (battery, &charge) in config.batteries.iter().zip(&charges) {
+    if charge < battery.final_charge - VERIFY_TOLERANCE {
+      return Err(PlanViolation {
+        interval,
+        constraint: PlanConstraint::FinalCharge,
+        value: charge,
+        limit: battery.final_charge,
       });
     }
   }
-  Ok(planning)
+  Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-  use crate::tests::init;
+  use crate::tests::{config, init};
 
   use super::*;
-  use chrono::Utc;
+  use crate::data::{Battery, ConfigError, EnergyBalance, Objective, Summary};
+  use chrono::{DateTime, Utc};
   use log::info;
 
   #[test]
@@ -56,19 +801,12 @@ mod tests {
     let start = Utc::now();
     let end = Utc::now();
     let data = vec![
-      Data { start, end, power: 0.0, price: 1.0 },
-      Data { start, end, power: 3.0, price: 2.0 },
-      Data { start, end, power: 1.0, price: 2.0 },
-      Data { start, end, power: 3.0, price: 0.9 },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 0.9, ..Default::default() },
     ];
-    let config = Config {
-      max_consumption: 2.0,
-      battery_capacity: 2.0 / 4.0,
-      battery_max_charge: 1.5,
-      battery_initial_charge: 1.5 / 4.0,
-      battery_efficiency: 0.9,
-      battery_final_charge: 0.0,
-    };
+    let config = Config { battery_initial_charge: 1.5 / 4.0, battery_efficiency: 0.9, ..config() };
     let (tableau, v, a) = build_tableau(&data, &config);
     let mut matrix = Matrix::new(tableau, v, a);
     info!("This is the initial matrix");
@@ -78,11 +816,139 @@ mod tests {
     matrix.phase_two();
     assert!(matrix.solve().is_ok());
     let solution = matrix.get_solution();
-    let expected = vec![0.5555555, 0.0];
+    let expected = [0.5555555, 0.0];
     let tolerance = 0.0001;
     for (i, s) in solution[0..2].iter().enumerate() {
       assert!((s - expected[i]).abs() < tolerance);
     }
+    // the grid gives at most its limit, exactly the limit when the battery covers an overload
+    let planning = calculation(&data, &config).unwrap();
+    assert_eq!(planning.grid_power.len(), data.len());
+    for (d, grid_power) in data.iter().zip(&planning.grid_power) {
+      if d.power < config.max_consumption {
+        assert!(*grid_power <= config.max_consumption + tolerance && *grid_power >= d.power - tolerance);
+      } else {
+        assert!((grid_power - config.max_consumption).abs() < tolerance);
+      }
+    }
+    assert!((planning.grid_power[0] - solution[0]).abs() < tolerance);
+  }
+
+  #[test]
+  fn logs_every_interval() {
+    init();
+
+    let start: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+    let quarter = chrono::Duration::minutes(15);
+    let interval = |i: i32, power: f64, price: f64| Data {
+      start: start + quarter * i,
+      end: start + quarter * (i + 1),
+      power,
+      price,
+      ..Default::default()
+    };
+    let data = vec![interval(0, 0.0, 1.0), interval(1, 3000.0, 2.0), interval(2, 1000.0, 2.0), interval(3, 3000.0, 0.9)];
+    let config = Config {
+      max_consumption: 2000.0,
+      battery_capacity: 2000.0 / 4.0,
+      battery_max_charge: 1500.0,
+      battery_initial_charge: 1500.0 / 4.0,
+      battery_efficiency: 0.9,
+      ..config()
+    };
+    let planning = calculation(&data, &config).unwrap();
+    assert_eq!(
+      plan_log_lines(&data, &config, &planning.plans),
+      [
+        "2024-01-01T00:00:00+00:00 charge 138.9 Wh at 1 EUR/kWh",
+        "2024-01-01T00:15:00+00:00 discharge 250.0 Wh at 2 EUR/kWh",
+        "2024-01-01T00:30:00+00:00 idle 0.0 Wh at 2 EUR/kWh",
+        "2024-01-01T00:45:00+00:00 discharge 250.0 Wh at 0.9 EUR/kWh",
+      ]
+    );
+  }
+
+  #[test]
+  fn counts_mode_switches() {
+    let plans = |energies: &[(f64, f64, f64)]| -> Vec<Plan> {
+      energies
+        .iter()
+        .map(|&(to, from, exported)| Plan {
+          start: Utc::now(),
+          end: Utc::now(),
+          energy_from_battery_wh: from,
+          energy_to_battery_wh: to,
+          energy_exported_wh: exported,
+          energy_imported_wh: 0.0,
+          battery_charge_wh: 0.0,
+          consumption_power: None,
+          price: None,
+          batteries: Vec::new(),
+          binding_constraints: Vec::new(),
+        })
+        .collect()
+    };
+    assert_eq!(count_mode_switches(&[]), 0);
+    assert_eq!(count_mode_switches(&plans(&[(0.0, 0.0, 0.0), (0.0, 0.0, 0.0)])), 0);
+    // charge, discharge, charge, discharge
+    let alternating = plans(&[(1.0, 0.0, 0.0), (0.0, 1.0, 0.0), (1.0, 0.0, 0.0), (0.0, 0.0, 1.0)]);
+    assert_eq!(count_mode_switches(&alternating), 3);
+    // the idle intervals in between and a repeated mode are not switches
+    let idle = plans(&[(1.0, 0.0, 0.0), (0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (0.0, 0.0, 0.0), (0.0, 1.0, 0.0), (0.0, 2.0, 0.0)]);
+    assert_eq!(count_mode_switches(&idle), 1);
+  }
+
+  #[test]
+  fn greedy_costs_more() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 0.9, ..Default::default() },
+    ];
+    let config = Config { battery_initial_charge: 1.5 / 4.0, battery_efficiency: 0.9, ..config() };
+    // the greedy plan also charges 1 W in the third interval, at the higher price, and ends with it unused
+    let greedy = greedy_peak_shave(&data, &config);
+    assert_eq!(verify_plan(&data, &config, &greedy), Ok(()));
+    assert!((greedy[2].energy_to_battery_wh - 1.0 / 4.0).abs() < 1e-9);
+    let greedy = planning_from_plans(&data, &config, greedy);
+    let optimum = calculation(&data, &config).unwrap();
+    let greedy_summary = Summary::new(&data, &config, &greedy);
+    let optimum_summary = Summary::new(&data, &config, &optimum);
+    assert!(greedy.total_cost > optimum.total_cost + 1e-6);
+    assert!(greedy_summary.savings < optimum_summary.savings);
+    assert_eq!(greedy.grid_power.len(), data.len());
+
+    // the solver's own plan costs the same when priced again from its energies
+    let repriced = planning_from_plans(&data, &config, optimum.plans.clone());
+    assert!((repriced.total_cost - optimum.total_cost).abs() < 1e-9);
+  }
+
+  #[test]
+  fn consumption_at_the_limit() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    // the second interval is exactly at the limit, neither charging nor discharging
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 2.0, price: 0.5, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+    ];
+    let config = config();
+    let (_, variables, _) = build_tableau(&data, &config);
+    assert_eq!(variables, 2);
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.plans[0].energy_to_battery_wh - 1.0 / 4.0).abs() < 1e-9);
+    assert_eq!(planning.plans[1].energy_to_battery_wh, 0.0);
+    assert_eq!(planning.plans[1].energy_from_battery_wh, 0.0);
+    assert!((planning.plans[2].energy_from_battery_wh - 1.0 / 4.0).abs() < 1e-9);
+    assert_eq!(verify_plan(&data, &config, &planning.plans), Ok(()));
   }
 
   #[test]
@@ -92,20 +958,14 @@ mod tests {
     let start = Utc::now();
     let end = Utc::now();
     let data = vec![
-      Data { start, end, power: 0.0, price: 1.0 },
-      Data { start, end, power: 3.0, price: 2.0 },
-      Data { start, end, power: 1.0, price: 2.0 },
-      Data { start, end, power: 3.0, price: 2.0 },
-      Data { start, end, power: 0.0, price: 1.0 },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
     ];
-    let config = Config {
-      max_consumption: 2.0,
-      battery_capacity: 2.0 / 4.0,
-      battery_max_charge: 1.5,
-      battery_initial_charge: 1.5 / 4.0,
-      battery_efficiency: 0.9,
-      battery_final_charge: 0.5 / 4.0,
-    };
+    let config =
+      Config { battery_initial_charge: 1.5 / 4.0, battery_efficiency: 0.9, battery_final_charge: 0.5 / 4.0, ..config() };
     let (tableau, v, a) = build_tableau(&data, &config);
     let mut matrix = Matrix::new(tableau, v, a);
     info!("This is the initial matrix");
@@ -116,11 +976,97 @@ mod tests {
     assert!(matrix.solve().is_ok());
     let solution = matrix.get_solution();
     info!("The solution is: {:?}", &solution);
-    let expected = vec![0.5555555, 0.0, 0.5555555];
+    let expected = [0.5555555, 0.0, 0.5555555];
     let tolerance = 0.0001;
     for (i, s) in solution[0..expected.len()].iter().enumerate() {
       assert!((s - expected[i]).abs() < tolerance);
     }
+
+    // the objective is what the charging costs: 0.5 / 0.9 W in the two intervals at a price of 1 per kWh,
+    // a quarter of an hour each. The consumption up to the limit adds up to 5 W, a quarter of an hour at 2 per kWh.
+    let (matrix, _) = solve(&data, &config);
+    let charging_cost = objective_in_currency(matrix.get_objective_value(), &config);
+    assert!((charging_cost - 2.0 * 0.5 / 0.9 / 4.0 / 1000.0).abs() < 1e-9);
+    let whole = calculation(&data, &config).unwrap();
+    assert!((whole.total_cost - 5.0 / 4.0 * 2.0 / 1000.0 - charging_cost).abs() < 1e-9);
+
+    // a single window is the whole plan
+    let windowed = calculation_windowed(&data, &config, data.len()).unwrap();
+    assert_eq!(windowed.plans.len(), whole.plans.len());
+    for (w, p) in windowed.plans.iter().zip(&whole.plans) {
+      assert!((w.energy_to_battery_wh - p.energy_to_battery_wh).abs() < tolerance);
+      assert!((w.battery_charge_wh - p.battery_charge_wh).abs() < tolerance);
+    }
+    assert!((windowed.total_cost - whole.total_cost).abs() < tolerance);
+    // the second window starts with the final charge
+    let windowed = calculation_windowed(&data, &config, 4).unwrap();
+    assert_eq!(windowed.plans.len(), data.len());
+    assert!(windowed.plans[3].battery_charge_wh >= config.battery_final_charge - tolerance);
+
+    // planning again after every interval with the same forecast gives the same plan
+    let mut receding = config.clone();
+    let mut committed = Vec::new();
+    while committed.len() < data.len() {
+      let commitment = calculation_receding(&data[committed.len()..], &receding, 1).unwrap();
+      receding.battery_initial_charge = commitment.battery_charge_wh;
+      committed.extend(commitment.plans);
+    }
+    for (c, p) in committed.iter().zip(&whole.plans) {
+      assert!((c.energy_to_battery_wh - p.energy_to_battery_wh).abs() < tolerance);
+      assert!((c.battery_charge_wh - p.battery_charge_wh).abs() < tolerance);
+    }
+
+    // the plan keeps to every constraint, until it is changed
+    assert_eq!(verify_plan(&data, &config, &whole.plans), Ok(()));
+    let check = |change: fn(&mut Vec<Plan>), interval: usize, constraint: PlanConstraint| {
+      let mut plans = calculation(&data, &config).unwrap().plans;
+      change(&mut plans);
+      let violation = verify_plan(&data, &config, &plans).unwrap_err();
+      assert_eq!((violation.interval, violation.constraint), (interval, constraint), "{violation:?}");
+    };
+    check(|p| p[0].energy_to_battery_wh = 2.0 / 4.0, 0, PlanConstraint::MaxCharge);
+    check(|p| p[0].energy_to_battery_wh = -0.1, 0, PlanConstraint::Negative);
+    check(|p| p[1].energy_from_battery_wh = 0.0, 1, PlanConstraint::GridLimit);
+    check(|p| p[0].energy_to_battery_wh = 1.5 / 4.0, 0, PlanConstraint::Capacity);
+    check(|p| p[2].energy_exported_wh = 0.5, 2, PlanConstraint::MinCharge);
+    check(|p| p[4].energy_to_battery_wh = 0.0, 4, PlanConstraint::FinalCharge);
+    check(|p| p.truncate(3), 3, PlanConstraint::Intervals);
+
+    // two overloads of 1 W for a quarter of an hour each empty the 0.5 Wh battery once
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.equivalent_full_cycles - 1.0).abs() < tolerance);
+
+    // the energy balance closes, every interval starts with what the one before ended with
+    let rows = EnergyBalance { config: &config, planning: &planning }.rows();
+    assert!((rows[0].start_wh - config.battery_initial_charge).abs() < tolerance);
+    for pair in rows.windows(2) {
+      assert!((pair[0].end_wh - pair[1].start_wh).abs() < tolerance);
+    }
+    assert!(rows.iter().all(|row| row.residual_wh().abs() < tolerance), "{rows:?}");
+    // a tenth of what is charged is lost
+    let losses: f64 = rows.iter().map(|row| row.losses_wh).sum();
+    assert!((losses - planning.total_charged_wh * 0.1).abs() < tolerance);
+
+    let config = Config { battery_capacity: 0.0, battery_initial_charge: 0.0, battery_final_charge: 0.0, ..config };
+    let data = vec![Data { start, end, power: 1.0, price: 1.0, ..Default::default() }];
+    assert_eq!(calculation(&data, &config).unwrap().equivalent_full_cycles, 0.0);
+  }
+
+  #[test]
+  fn not_finite_price() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: f64::NAN, ..Default::default() },
+      Data { start, end, power: 3.0, price: 3.0, ..Default::default() },
+    ];
+    let config = Config { battery_capacity: 1.0, battery_max_charge: 2.0, ..config() };
+    // the price of the first charge is in the objective row, after the constraints
+    let (tableau, _, _) = build_tableau(&data, &config);
+    let objective_row = tableau.len() - 2;
+    assert_eq!(calculation(&data, &config).unwrap_err(), SolveError::NotFinite { row: objective_row, col: 0 });
   }
 
   #[test]
@@ -130,25 +1076,923 @@ mod tests {
     let start = Utc::now();
     let end = Utc::now();
     let data = vec![
-      Data { start, end, power: 0.0, price: 1.0 },
-      Data { start, end, power: 3.0, price: 2.0 },
-      Data { start, end, power: 1.0, price: 2.0 },
-      Data { start, end, power: 3.0, price: 2.0 },
-      Data { start, end, power: 0.0, price: 1.0 },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
     ];
     let config = Config {
-      max_consumption: 2.0,
-      battery_capacity: 2.0 / 4.0,
-      battery_max_charge: 1.5,
       battery_initial_charge: 1.5 / 4.0,
       battery_efficiency: 0.9,
       // too high to be possible
       battery_final_charge: 100.0,
+      ..config()
     };
     let (tableau, v, a) = build_tableau(&data, &config);
     let mut matrix = Matrix::new(tableau, v, a);
     info!("This is the initial matrix");
     info!("{matrix}");
-    assert!(matrix.solve().is_err());
+    assert_eq!(matrix.solve(), Err(SolveError::Infeasible));
+
+    let infeasibility = explain(&data, &config).unwrap();
+    info!("{:?}", infeasibility);
+    assert_eq!(infeasibility.constraint, ConstraintKind::FinalCharge);
+    // 100 Wh at the end, on top of the 0.375 Wh initial charge minus the 0.5 Wh taken by the overload
+    assert_eq!(infeasibility.required, 100.125);
+    // the battery holds at most 0.5 Wh, 0.125 Wh more than at the start, plus the 0.5 Wh for the overload
+    assert!((infeasibility.achievable - 0.625).abs() < 0.0001);
+
+    // with a penalty the plan falls short of the final charge instead, the battery ends full
+    let config = Config { final_charge_penalty: Some(10.0), ..config };
+    let planning = calculation(&data, &config).unwrap();
+    let last = planning.plans.last().unwrap();
+    assert!((last.battery_charge_wh - config.battery_capacity).abs() < 0.0001);
+    assert!((planning.final_charge_shortfall_wh - (100.0 - config.battery_capacity)).abs() < 0.0001);
+  }
+
+  #[test]
+  fn every_interval_overloaded() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 3.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 2.5, price: 2.0, ..Default::default() },
+    ];
+    let mut config = Config { battery_initial_charge: 1.5 / 4.0, battery_efficiency: 0.9, ..config() };
+    // nothing can be charged, the initial charge covers the overload
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.plans.iter().all(|p| p.energy_to_battery_wh == 0.0));
+    assert_eq!(planning.plans[0].energy_from_battery_wh, 1.0 / 4.0);
+    assert_eq!(planning.plans[1].energy_from_battery_wh, 0.5 / 4.0);
+
+    // and when it does not there is no way to charge more
+    config.battery_initial_charge = 1.0 / 4.0;
+    assert_eq!(calculation(&data, &config).unwrap_err(), SolveError::Infeasible);
+  }
+
+  #[test]
+  fn no_interval_overloaded() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 1.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.5, price: 2.0, ..Default::default() },
+    ];
+    let mut config = Config { battery_initial_charge: 1.5 / 4.0, battery_efficiency: 0.9, ..config() };
+    // no discharge needed, nothing to charge for
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.plans.iter().all(|p| p.energy_to_battery_wh == 0.0 && p.energy_from_battery_wh == 0.0));
+
+    // the battery is only charged to reach the final charge, in the cheaper interval
+    config.battery_final_charge = 2.0 / 4.0;
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.plans[0].energy_to_battery_wh > 0.0);
+    assert_eq!(planning.plans[1].energy_to_battery_wh, 0.0);
+    assert!((planning.plans[1].battery_charge_wh - 2.0 / 4.0).abs() < 0.0001);
+  }
+
+  #[test]
+  fn minimise_peak_instead_of_cost() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 1.5, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 2.0, ..Default::default() },
+    ];
+    let mut config = Config { battery_final_charge: 1.0 / 4.0, ..config() };
+    let grid = |planning: &Planning| -> Vec<f64> {
+      data.iter().zip(&planning.plans).map(|(d, p)| d.power + p.energy_to_battery_wh * 4.0).collect()
+    };
+    // charge as much as possible in the cheap interval, up to the limit
+    let cheapest = calculation(&data, &config).unwrap();
+    assert_eq!(grid(&cheapest), vec![2.0, 0.5]);
+
+    // charge when the consumption is low, the peak stays at the consumption of the first interval
+    config.objective = Objective::MinimisePeak;
+    let flattest = calculation(&data, &config).unwrap();
+    assert_eq!(flattest.plans[0].energy_to_battery_wh, 0.0);
+    assert_eq!(grid(&flattest).into_iter().fold(0.0, f64::max), 1.5);
+    assert!(flattest.total_cost > cheapest.total_cost);
+  }
+
+  #[test]
+  fn demand_charge_shaves_the_peak() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 1.5, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.1, ..Default::default() },
+    ];
+    let mut config = Config { battery_final_charge: 1.0 / 4.0, ..config() };
+    let grid = |planning: &Planning| -> Vec<f64> {
+      data.iter().zip(&planning.plans).map(|(d, p)| d.power + p.energy_to_battery_wh * 4.0).collect()
+    };
+    let cheapest = calculation(&data, &config).unwrap();
+    assert_eq!(grid(&cheapest), vec![2.0, 0.5]);
+
+    // lowering the peak by 0.5 saves 0.1 * 0.5, charging that later costs 0.1 * 0.5 for a quarter of an hour
+    config.demand_charge_per_kw = 0.1;
+    let shaved = calculation(&data, &config).unwrap();
+    assert_eq!(grid(&shaved), vec![1.5, 1.0]);
+    assert!(shaved.total_cost > cheapest.total_cost);
+  }
+
+  #[test]
+  fn idle_interval() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let mut data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+    ];
+    let config = config();
+    let planning = calculation(&data, &config).unwrap();
+    assert_eq!(planning.plans[0].energy_to_battery_wh, 1.0 / 4.0);
+    assert_eq!(planning.plans[1].energy_to_battery_wh, 0.0);
+
+    // the battery is not available in the cheap interval
+    data[0].allow_charge = false;
+    data[0].allow_discharge = false;
+    let planning = calculation(&data, &config).unwrap();
+    assert_eq!(planning.plans[0].energy_to_battery_wh, 0.0);
+    assert_eq!(planning.plans[1].energy_to_battery_wh, 1.0 / 4.0);
+
+    // nothing else can cover the overload
+    data[2].allow_discharge = false;
+    assert_eq!(calculation(&data, &config).unwrap_err(), SolveError::Infeasible);
+  }
+
+  #[test]
+  fn baseload_reduces_discharging() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, allow_discharge: false, ..Default::default() },
+      Data { start, end, power: 1.0, price: 3.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 3.0, ..Default::default() },
+    ];
+    let config = Config { battery_max_charge: 2.0, feed_in_price: Some(2.5), ..config() };
+    // the bill is what the grid gives, in W for every interval at its price
+    let cost = |imports: f64| imports / 4.0 / WH_PER_KWH;
+    // what is left in the battery after the overload goes to the consumers at 3, that saves more than selling it.
+    // The grid gives 2 W at 1 to fill the battery and 1 W at 3 in the other two intervals
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.plans.iter().all(|p| p.energy_exported_wh.abs() < 1e-9));
+    assert!((planning.total_cost - cost(2.0 * 1.0 + 1.0 * 3.0 + 1.0 * 3.0)).abs() < 1e-12);
+
+    // the grid has to give 1.5 W in every interval, only half of the overload limit can be discharged
+    // on top of the overload and the battery has to take what the consumers do not
+    let config = Config { min_grid_power: 1.5, ..config };
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.grid_power.iter().all(|grid| *grid >= config.min_grid_power - 1e-9));
+    let stored = planning.plans[1].energy_to_battery_wh - planning.plans[1].energy_from_battery_wh;
+    assert!((stored - 0.5 / 4.0).abs() < 1e-9);
+    assert!((planning.total_cost - cost(1.5 * 1.0 + 1.5 * 3.0 + 1.5 * 3.0)).abs() < 1e-12);
+
+    // a grid limit below the baseload can not work
+    let config = Config { min_grid_power: 2.5, ..config };
+    assert_eq!(calculation(&data, &config).unwrap_err(), SolveError::Infeasible);
+    assert_eq!(explain(&data, &config).map(|i| i.constraint), Some(ConstraintKind::MinGrid));
+  }
+
+  #[test]
+  fn grid_price_markup() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+    ];
+    let config = Config { battery_max_charge: 2.0, feed_in_price: Some(1.5), ..config() };
+    let charged = |planning: &Planning| planning.plans.iter().map(|p| p.energy_to_battery_wh).sum::<f64>();
+    // at the market price the grid energy is cheaper than the feed-in, the battery buys to sell
+    let planning = calculation(&data, &config).unwrap();
+    assert!(charged(&planning) > 0.0);
+    assert!(planning.total_cost < 0.0);
+
+    // the customer pays 1 * 1.5 + 0.2 = 1.7 per kWh, more than the feed-in earns
+    let config = Config { grid_price_markup: 0.5, grid_price_fixed_adder: 0.2, ..config };
+    assert!((config.grid_price(1.0) - 1.7).abs() < 1e-12);
+    let planning = calculation(&data, &config).unwrap();
+    assert_eq!(charged(&planning), 0.0);
+    assert!(planning.plans.iter().all(|p| p.energy_exported_wh == 0.0));
+  }
+
+  #[cfg(feature = "milp")]
+  #[test]
+  fn on_off_charging() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.2, price: 3.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 3.0, ..Default::default() },
+    ];
+    let config = config();
+    // the overload needs 1.2 W, the grid leaves 1 W in the cheapest interval and the rest comes before
+    let relaxed = calculation(&data, &config).unwrap();
+    assert!((relaxed.plans[0].energy_to_battery_wh - 0.2 / 4.0).abs() < 1e-9);
+    assert!((relaxed.plans[1].energy_to_battery_wh - 1.0 / 4.0).abs() < 1e-9);
+
+    // on or off the cheap 1 W is not enough, and with the full 1.5 W before it would not fit in the battery
+    let binary = calculation_binary(&data, &config).unwrap();
+    assert!((binary.plans[0].energy_to_battery_wh - 1.5 / 4.0).abs() < 1e-9);
+    assert_eq!(binary.plans[1].energy_to_battery_wh, 0.0);
+    assert_eq!(binary.plans[3].energy_to_battery_wh, 0.0);
+    assert!(binary.total_cost > relaxed.total_cost);
+  }
+
+  #[test]
+  fn discharge_rate_binds() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, allow_discharge: false, ..Default::default() },
+      Data { start, end, power: 1.0, price: 3.0, allow_discharge: false, ..Default::default() },
+      Data { start, end, power: 3.0, price: 3.0, ..Default::default() },
+    ];
+    let config = Config { battery_max_charge: 2.0, battery_max_discharge: Some(1.5), feed_in_price: Some(2.5), ..config() };
+    // the battery could hold more but only what is left of the discharge rate after the overload can go to the consumers
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.plans[2].energy_from_battery_wh - 1.5 / 4.0).abs() < 1e-9);
+    assert!((planning.plans[0].energy_to_battery_wh - 1.5 / 4.0).abs() < 1e-9);
+
+    // an overload above the discharge rate can not be covered
+    let config = Config { battery_max_discharge: Some(0.5), ..config };
+    assert_eq!(calculation(&data, &config).unwrap_err(), SolveError::Infeasible);
+  }
+
+  #[test]
+  fn import_quota() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, allow_discharge: false, ..Default::default() },
+      Data { start, end, power: 0.0, price: 3.0, ..Default::default() },
+    ];
+    let config =
+      Config { battery_capacity: 1.0, battery_max_charge: 2.0, battery_efficiency: 0.8, feed_in_price: Some(2.5), ..config() };
+    // buying at 1 and selling at 2.5 pays for the losses, the battery charges as fast as it can
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.total_charged_wh - 2.0 / 4.0).abs() < 1e-9);
+
+    // a fifth of what is charged is lost and never exported, the quota limits the charging
+    let config = Config { daily_import_quota_wh: Some(0.05), ..config };
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.total_charged_wh - 0.25).abs() < 1e-9);
+    assert!((planning.total_discharged_wh - 0.2).abs() < 1e-9);
+    assert_eq!(verify_plan(&data, &config, &planning.plans), Ok(()));
+    let tighter = Config { daily_import_quota_wh: Some(0.04), ..config.clone() };
+    let violation = verify_plan(&data, &tighter, &planning.plans).unwrap_err();
+    assert_eq!((violation.interval, violation.constraint), (1, PlanConstraint::ImportQuota));
+
+    // the consumption alone is above the quota and the battery has nothing to export
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, allow_discharge: false, ..Default::default() },
+      Data { start, end, power: 1.0, price: 3.0, ..Default::default() },
+    ];
+    let config = Config { daily_import_quota_wh: Some(0.0), ..config };
+    assert_eq!(calculation(&data, &config).unwrap_err(), SolveError::Infeasible);
+    assert_eq!(explain(&data, &config).map(|i| i.constraint), Some(ConstraintKind::ImportQuota));
+  }
+
+  #[test]
+  fn losses_on_both_sides() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 3.0, ..Default::default() },
+    ];
+    let config = Config {
+      battery_capacity: 1.0,
+      battery_max_charge: 2.0,
+      battery_final_charge: 0.1,
+      charge_efficiency: Some(0.9),
+      discharge_efficiency: Some(0.8),
+      ..config()
+    };
+    // the overload of 0.25 Wh takes 0.25 / 0.8 from the battery, the final charge has to be there on top
+    let stored = 0.25 / 0.8 + 0.1;
+    let (tableau, _, _, kinds) = build_tableau_with_kinds(&data, &config);
+    let row = &tableau[kinds.iter().position(|k| *k == ConstraintKind::FinalCharge).unwrap()];
+    assert_eq!(row[0], 0.9);
+    assert!((row[row.len() - 1] - stored * 4.0).abs() < 1e-9);
+
+    // charging only what is needed leaves exactly the final charge
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.plans[0].energy_to_battery_wh - stored / 0.9).abs() < 1e-9);
+    assert!((planning.plans[0].battery_charge_wh - stored).abs() < 1e-9);
+    assert!((planning.plans[1].battery_charge_wh - 0.1).abs() < 1e-9);
+    assert_eq!(verify_plan(&data, &config, &planning.plans), Ok(()));
+  }
+
+  #[test]
+  fn several_batteries() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 3.0, ..Default::default() },
+    ];
+    let battery = |capacity: f64, cycle_cost_per_kwh: f64| Battery {
+      capacity,
+      max_charge: 2.0,
+      max_discharge: None,
+      initial_charge: 0.0,
+      final_charge: 0.0,
+      charge_efficiency: 1.0,
+      discharge_efficiency: 1.0,
+      cycle_cost_per_kwh,
+    };
+    let config = Config { battery_capacity: 1.0, battery_max_charge: 2.0, ..config() };
+    // a single battery in the list plans like the battery of the configuration
+    let single = calculation(&data, &config).unwrap();
+    let listed = Config { batteries: vec![battery(1.0, 0.0)], ..config.clone() };
+    let planning = calculation(&data, &listed).unwrap();
+    assert!((planning.total_cost - single.total_cost).abs() < 1e-9);
+    assert!((planning.plans[0].energy_to_battery_wh - single.plans[0].energy_to_battery_wh).abs() < 1e-9);
+    assert_eq!(planning.plans[0].batteries.len(), 1);
+
+    // the battery that wears less covers the whole overload of 0.25 Wh
+    let config = Config { batteries: vec![battery(1.0, 0.5), battery(1.0, 0.1)], ..config };
+    let planning = calculation(&data, &config).unwrap();
+    let flows = |i: usize| -> Vec<(f64, f64)> {
+      planning.plans[i].batteries.iter().map(|b| (b.energy_to_battery_wh, b.energy_from_battery_wh)).collect()
+    };
+    assert_eq!(flows(0), vec![(0.0, 0.0), (0.25, 0.0)]);
+    assert_eq!(flows(1), vec![(0.0, 0.0), (0.0, 0.25)]);
+    assert!((planning.plans[1].energy_from_battery_wh - 0.25).abs() < 1e-9);
+    assert_eq!(verify_plan(&data, &config, &planning.plans), Ok(()));
+    // the rate of the single battery does not limit them, validate rejects it
+    let limited = Config { battery_max_discharge: Some(0.5), ..config.clone() };
+    assert!(calculation(&data, &limited).is_ok());
+    assert_eq!(limited.validate(), Err(ConfigError::NotWithBatteries("battery_max_discharge")));
+
+    // when it is too small the other battery covers the rest
+    let config = Config { batteries: vec![battery(1.0, 0.5), battery(0.1, 0.1)], ..config };
+    let planning = calculation(&data, &config).unwrap();
+    let from: Vec<f64> = planning.plans[1].batteries.iter().map(|b| b.energy_from_battery_wh).collect();
+    assert!((from[0] - 0.15).abs() < 1e-9 && (from[1] - 0.1).abs() < 1e-9, "{from:?}");
+    assert_eq!(verify_plan(&data, &config, &planning.plans), Ok(()));
+    let mut plans = planning.plans;
+    plans[0].batteries[1].energy_to_battery_wh = 0.2;
+    let violation = verify_plan(&data, &config, &plans).unwrap_err();
+    assert_eq!((violation.interval, violation.constraint), (0, PlanConstraint::Capacity));
+
+    // together they can not cover more than they hold
+    let config = Config { batteries: vec![battery(0.1, 0.5), battery(0.1, 0.1)], ..config };
+    assert_eq!(calculation(&data, &config).unwrap_err(), SolveError::Infeasible);
+  }
+
+  #[test]
+  fn energy_throughput() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 3.0, ..Default::default() },
+    ];
+    let config = Config { battery_capacity: 1.0, battery_max_charge: 2.0, battery_efficiency: 0.8, ..config() };
+    // the overload of 1 W for a quarter of an hour needs 0.25 Wh, charging it loses a fifth
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.total_discharged_wh - 0.25).abs() < 1e-9);
+    assert!((planning.total_charged_wh - 0.25 / 0.8).abs() < 1e-9);
+    assert!((planning.total_discharged_wh / planning.total_charged_wh - config.battery_efficiency).abs() < 1e-9);
+  }
+
+  #[test]
+  fn midday_derating() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let mut data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 3.0, ..Default::default() },
+      Data { start, end, power: 3.5, price: 3.0, ..Default::default() },
+    ];
+    let config = config();
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.plans[0].energy_to_battery_wh - 1.5 / 4.0).abs() < 1e-9);
+    assert!(planning.plans[2].energy_to_battery_wh.abs() < 1e-9);
+
+    // the battery only holds a quarter of its capacity around noon, the rest is charged later at a higher price
+    data[1].capacity = Some(0.5 / 4.0);
+    let planning = calculation(&data, &config).unwrap();
+    let cheap = planning.plans[0].energy_to_battery_wh + planning.plans[1].energy_to_battery_wh;
+    assert!((cheap - 0.5 / 4.0).abs() < 1e-9);
+    assert!((planning.plans[2].energy_to_battery_wh - 1.0 / 4.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn total_cost_matches_plan() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 0.9, ..Default::default() },
+    ];
+    let config = Config { battery_initial_charge: 1.5 / 4.0, battery_efficiency: 0.9, ..config() };
+    let planning = calculation(&data, &config).unwrap();
+    let expected: f64 = data
+      .iter()
+      .zip(planning.plans.iter())
+      .map(|(d, p)| d.price * (d.power + 4.0 * (p.energy_to_battery_wh - p.energy_from_battery_wh)) / 4.0 / WH_PER_KWH)
+      .sum();
+    info!("total cost: {}, expected {expected}", planning.total_cost);
+    assert!((planning.total_cost - expected).abs() < 1e-9);
+  }
+
+  #[test]
+  fn efficiency_segments() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 10.4, price: 3.0, ..Default::default() },
+    ];
+    // the lower half of the battery charges without loss, the upper half keeps 70 %
+    let config = Config {
+      max_consumption: 8.0,
+      battery_capacity: 1.0,
+      battery_max_charge: 8.0,
+      efficiency_segments: vec![(0.5, 1.0), (1.0, 0.7)],
+      ..config()
+    };
+    assert_eq!(config.validate(), Ok(()));
+    let charged = |config: &Config| {
+      let planning = calculation(&data, config).unwrap();
+      assert_eq!(verify_plan(&data, config, &planning.plans), Ok(()));
+      assert!(planning.plans[0].batteries.is_empty());
+      planning.plans[0].energy_to_battery_wh
+    };
+    // the overload of 0.6 Wh fills the empty half first, only the last 0.1 Wh is stored with the loss
+    assert!((charged(&config) - (0.5 + 0.1 / 0.7)).abs() < 1e-9);
+    // the balance closes along the curve, only the energy charged in the upper half loses
+    let mut planning = calculation(&data, &config).unwrap();
+    let rows = EnergyBalance { config: &config, planning: &planning }.rows();
+    assert!(rows.iter().all(|row| row.residual_wh().abs() < 1e-9), "{rows:?}");
+    assert!((rows[0].losses_wh - (0.1 / 0.7 - 0.1)).abs() < 1e-9);
+    // a charge the curve can not give is caught
+    planning.plans[0].battery_charge_wh += 0.05;
+    let rows = EnergyBalance { config: &config, planning: &planning }.rows();
+    assert!((rows[0].residual_wh() + 0.05).abs() < 1e-9, "{rows:?}");
+    // from half full all of it goes to the upper half, the same energy takes more from the grid
+    let half_full = Config { battery_initial_charge: 0.5, ..config.clone() };
+    assert!((charged(&half_full) - 0.1 / 0.7).abs() < 1e-9);
+
+    // a single segment is the charge efficiency
+    let flat = Config { efficiency_segments: vec![(1.0, 0.7)], ..config.clone() };
+    let scalar = Config { efficiency_segments: vec![], charge_efficiency: Some(0.7), ..config.clone() };
+    assert!((charged(&flat) - charged(&scalar)).abs() < 1e-9);
+
+    let rising = Config { efficiency_segments: vec![(0.5, 0.7), (1.0, 1.0)], ..config.clone() };
+    assert!(matches!(rising.validate(), Err(ConfigError::Segments(_))));
+    let short = Config { efficiency_segments: vec![(0.5, 1.0)], ..config.clone() };
+    assert!(matches!(short.validate(), Err(ConfigError::Segments(_))));
+    let exports = Config { feed_in_price: Some(0.1), ..config };
+    assert_eq!(exports.validate(), Err(ConfigError::NotWithSegments("feed_in_price")));
+  }
+
+  #[test]
+  fn self_consumption() {
+    init();
+
+    let start: DateTime<Utc> = "2024-06-01T06:00:00Z".parse().unwrap();
+    let hour = chrono::Duration::hours(1);
+    let interval = |i: i32, power: f64, generation_power: f64| Data {
+      start: start + hour * i,
+      end: start + hour * (i + 1),
+      power,
+      generation_power,
+      price: 0.3,
+      ..Default::default()
+    };
+    // a morning on the grid, a midday surplus of 4000 Wh and an evening of 4000 Wh
+    let data = vec![
+      interval(0, 1000.0, 0.0),
+      interval(1, 1000.0, 3000.0),
+      interval(2, 1000.0, 3000.0),
+      interval(3, 2000.0, 0.0),
+      interval(4, 2000.0, 0.0),
+    ];
+    let config = Config {
+      max_consumption: 5000.0,
+      battery_capacity: 3000.0,
+      battery_max_charge: 2000.0,
+      intervals_per_hour: 1.0,
+      objective: Objective::MaxSelfConsumption,
+      ..config()
+    };
+    assert_eq!(config.validate(), Ok(()));
+    let planning = calculation(&data, &config).unwrap();
+    assert_eq!(verify_plan(&data, &config, &planning.plans), Ok(()));
+    // the battery takes the surplus up to its capacity, nothing from the grid, and gives it back in the evening
+    let charged: Vec<f64> = planning.plans.iter().map(|p| p.energy_to_battery_wh).collect();
+    assert!(charged[0].abs() < 1e-6 && (charged[1] + charged[2] - 3000.0).abs() < 1e-6, "{charged:?}");
+    let discharged: f64 = planning.plans[3..].iter().map(|p| p.energy_from_battery_wh).sum();
+    assert!((discharged - 3000.0).abs() < 1e-6);
+    // 1000 Wh of the surplus is left for the grid, the evening takes 1000 Wh more than the battery gives
+    let exchanged: f64 = planning.grid_power.iter().map(|g| g.abs()).sum();
+    assert!((exchanged - 3000.0).abs() < 1e-6, "{:?}", planning.grid_power);
+    assert!((planning.total_cost - (1000.0 + 1000.0) * 0.3 / 1000.0).abs() < 1e-9);
+
+    let with_peak_charge = Config { demand_charge_per_kw: 1.0, ..config };
+    assert_eq!(with_peak_charge.validate(), Err(ConfigError::NotWithSelfConsumption("demand_charge_per_kw")));
+  }
+
+  #[test]
+  fn total_cost_without_battery_use() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    // no overload and the battery ends where it started, there is no reason to charge it
+    let data = vec![
+      Data { start, end, power: 1.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 1.5, price: 2.0, ..Default::default() },
+      Data { start, end, power: 0.5, price: 3.0, ..Default::default() },
+    ];
+    let config =
+      Config { battery_initial_charge: 1.0 / 4.0, battery_efficiency: 0.9, battery_final_charge: 1.0 / 4.0, ..config() };
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.plans.iter().all(|p| p.energy_to_battery_wh == 0.0 && p.energy_from_battery_wh == 0.0));
+    let naive_bill: f64 = data.iter().map(|d| d.price * d.power / 4.0 / WH_PER_KWH).sum();
+    assert!((planning.total_cost - naive_bill).abs() < 1e-9);
+  }
+
+  #[test]
+  fn export_when_feed_in_pays_more_than_charging() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 5.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+    ];
+    let mut config = Config { battery_initial_charge: 1.0 / 4.0, battery_efficiency: 0.9, ..config() };
+    let stored = calculation(&data, &config).unwrap();
+    assert!(stored.plans.iter().all(|p| p.energy_exported_wh == 0.0 && p.energy_to_battery_wh == 0.0));
+
+    // charging at 1 and selling at 3 is worth it even with the losses, charging at 5 is not
+    config.feed_in_price = Some(3.0);
+    let exported = calculation(&data, &config).unwrap();
+    info!("plan with exports: {:?}", exported.plans);
+    let total_discharged: f64 = exported.plans.iter().map(|p| p.energy_from_battery_wh + p.energy_exported_wh).sum();
+    let total_charged: f64 = exported.plans.iter().map(|p| p.energy_to_battery_wh).sum();
+    let tolerance = 0.0001;
+    assert!(exported.plans[1].energy_to_battery_wh.abs() < tolerance);
+    assert!((exported.plans[0].energy_to_battery_wh - 1.5 / 4.0).abs() < tolerance);
+    assert!((exported.plans[2].energy_to_battery_wh - 1.5 / 4.0).abs() < tolerance);
+    // the consumption at 5 is covered first, it saves more than selling earns
+    assert!((exported.plans[1].energy_from_battery_wh - 1.0 / 4.0).abs() < tolerance);
+    // everything in the battery gets used or exported
+    assert!((total_discharged - (1.0 / 4.0 + 0.9 * total_charged)).abs() < tolerance);
+    assert!(exported.total_cost < stored.total_cost);
+  }
+
+  #[test]
+  fn wide_buy_sell_spread() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let mut data = vec![
+      Data { start, end, power: 0.0, price: 1.0, sell_price: Some(0.1), ..Default::default() },
+      Data { start, end, power: 1.0, price: 5.0, sell_price: Some(0.5), ..Default::default() },
+      Data { start, end, power: 0.0, price: 5.0, sell_price: Some(0.5), ..Default::default() },
+    ];
+    let config = config();
+    // selling at 0.5 never pays for buying at 1, the battery only covers the consumption bought at 5
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.plans.iter().all(|p| p.energy_exported_wh.abs() < 1e-9));
+    assert!((planning.plans[0].energy_to_battery_wh - 1.0 / 4.0).abs() < 1e-9);
+    assert!((planning.plans[1].energy_from_battery_wh - 1.0 / 4.0).abs() < 1e-9);
+    assert!(planning.plans[1].energy_imported_wh.abs() < 1e-9);
+    assert!((planning.plans[0].energy_imported_wh - 1.0 / 4.0).abs() < 1e-9);
+    assert!((planning.total_cost - 1.0 / 4.0 / WH_PER_KWH).abs() < 1e-12);
+
+    // with a narrow spread what the battery can still hold is bought at 1 and sold at 4
+    data[2].sell_price = Some(4.0);
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.plans[0].energy_to_battery_wh - 1.5 / 4.0).abs() < 1e-9);
+    assert!((planning.plans[2].energy_exported_wh - 0.5 / 4.0).abs() < 1e-9);
+    assert!(planning.grid_power[2] < 0.0);
+  }
+
+  #[test]
+  fn cycle_cost_stops_marginal_arbitrage() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+    ];
+    let mut config = Config { feed_in_price: Some(1.1), ..config() };
+    // buying at 1 and selling at 1.1 pays when the battery does not wear
+    let traded = calculation(&data, &config).unwrap();
+    let total_charged: f64 = traded.plans.iter().map(|p| p.energy_to_battery_wh).sum();
+    assert!(total_charged > 0.0);
+
+    // the wear costs more than the spread
+    config.cycle_cost_per_kwh = 0.2;
+    let idle = calculation(&data, &config).unwrap();
+    assert!(idle.plans.iter().all(|p| p.energy_to_battery_wh == 0.0 && p.energy_exported_wh == 0.0));
+    // the wear is not part of the bill
+    assert_eq!(idle.total_cost, 0.0);
+    assert!(traded.total_cost < idle.total_cost);
+  }
+
+  #[test]
+  fn tighter_limit_in_some_intervals() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let config = Config { battery_efficiency: 0.9, ..config() };
+    let mut data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 1.5, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.5, price: 2.0, ..Default::default() },
+    ];
+    // under the limit everywhere, the empty battery stays empty
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.plans.iter().all(|p| p.energy_to_battery_wh == 0.0 && p.energy_from_battery_wh == 0.0));
+
+    // the limit drops to 1 for the last two intervals
+    data[1].max_power = Some(1.0);
+    data[2].max_power = Some(1.0);
+    let planning = calculation(&data, &config).unwrap();
+    info!("plan with tighter limits: {:?}", planning.plans);
+    let tolerance = 0.0001;
+    // 2 * 0.5 has to be charged in the first interval, with the losses
+    assert!((planning.plans[0].energy_to_battery_wh - 1.0 / 0.9 / 4.0).abs() < tolerance);
+    assert!((planning.plans[1].energy_from_battery_wh - 0.5 / 4.0).abs() < tolerance);
+    assert!((planning.plans[2].energy_from_battery_wh - 0.5 / 4.0).abs() < tolerance);
+  }
+
+  #[test]
+  fn asymmetric_efficiencies() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 0.9, ..Default::default() },
+    ];
+    let mut config = Config {
+      battery_initial_charge: 1.5 / 4.0,
+      battery_efficiency: 0.9,
+      charge_efficiency: Some(0.9),
+      discharge_efficiency: Some(0.9),
+      ..config()
+    };
+    let charged = |config: &Config| -> Vec<f64> {
+      let planning = calculation(&data, config).unwrap();
+      planning.plans.iter().map(|p| p.energy_to_battery_wh * 4.0).collect()
+    };
+    let tolerance = 0.0001;
+    // the two overloads take 1 / 0.9 each from the battery, the first charge fills the battery
+    let symmetric = charged(&config);
+    let needed = 2.0 / 0.9 - 1.5;
+    assert!((symmetric[0] - 0.5 / 0.9).abs() < tolerance);
+    assert!((symmetric[2] - (needed / 0.9 - 0.5 / 0.9)).abs() < tolerance);
+
+    // charging loses less, less energy has to be bought in the expensive interval
+    config.charge_efficiency = Some(0.95);
+    let asymmetric = charged(&config);
+    assert!((asymmetric[0] - 0.5 / 0.95).abs() < tolerance);
+    assert!((asymmetric[2] - (needed / 0.95 - 0.5 / 0.95)).abs() < tolerance);
+    assert!(asymmetric[2] < symmetric[2]);
+  }
+
+  #[test]
+  fn self_discharge_moves_charging_closer_to_the_overload() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.05, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+    ];
+    let mut config = Config { battery_efficiency: 0.9, ..config() };
+    let tolerance = 0.0001;
+    // without losses the cheapest interval is used
+    let planning = calculation(&data, &config).unwrap();
+    assert!((planning.plans[0].energy_to_battery_wh - 1.0 / 0.9 / 4.0).abs() < tolerance);
+    assert!(planning.plans[1].energy_to_battery_wh.abs() < tolerance);
+
+    // half of the charge is lost in an hour, keeping the energy an extra interval costs more than
+    // the 5% price difference so the battery is charged just before the overload
+    config.self_discharge_per_hour = 0.5;
+    let decay = 0.5_f64.powf(0.25);
+    let planning = calculation(&data, &config).unwrap();
+    info!("plan with self discharge: {:?}", planning.plans);
+    assert!(planning.plans[0].energy_to_battery_wh.abs() < tolerance);
+    assert!((planning.plans[1].energy_to_battery_wh - 1.0 / 0.9 / decay / 4.0).abs() < tolerance);
+  }
+
+  #[test]
+  fn reserve_forces_earlier_charging() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+    ];
+    let mut config = Config { battery_efficiency: 0.9, ..config() };
+    let tolerance = 0.0001;
+    // everything is charged in the cheap interval right before the overload
+    let planning = calculation(&data, &config).unwrap();
+    assert!(planning.plans[0].energy_to_battery_wh.abs() < tolerance);
+    assert!((planning.plans[1].energy_to_battery_wh - 1.0 / 0.9 / 4.0).abs() < tolerance);
+
+    // the reserve has to be there from the first interval on and stay after the overload
+    config.min_charge = 0.2 / 4.0;
+    let planning = calculation(&data, &config).unwrap();
+    info!("plan with reserve: {:?}", planning.plans);
+    assert!((planning.plans[0].energy_to_battery_wh - 0.2 / 0.9 / 4.0).abs() < tolerance);
+    assert!((planning.plans[1].energy_to_battery_wh - 1.0 / 0.9 / 4.0).abs() < tolerance);
+  }
+
+  #[test]
+  fn battery_charge_trajectory() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.5, price: 1.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.5, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.5, price: 2.0, ..Default::default() },
+    ];
+    let config = Config { battery_capacity: 4.0 / 4.0, battery_efficiency: 0.9, battery_final_charge: 0.5 / 4.0, ..config() };
+    let planning = calculation(&data, &config).unwrap();
+    let trajectory: Vec<f64> = planning.plans.iter().map(|p| p.battery_charge_wh).collect();
+    info!("battery charge: {:?}", trajectory);
+    let tolerance = 0.0001;
+    // the battery only charges in the first three intervals
+    assert!(trajectory[..3].windows(2).all(|w| w[1] >= w[0] - tolerance));
+    // the overloads take 1.5 each
+    assert!((trajectory[3] - (trajectory[2] - 1.5 / 4.0)).abs() < tolerance);
+    assert!((trajectory[4] - config.battery_final_charge).abs() < tolerance);
+  }
+
+  #[test]
+  fn binding_constraints() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    // two overloads of 1.5 Wh and a battery of 1.5 Wh, it has to be full before each of them
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 14.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 14.0, price: 1.0, ..Default::default() },
+    ];
+    let config = Config { max_consumption: 8.0, battery_capacity: 1.5, battery_max_charge: 8.0, ..config() };
+    let (tableau, variables, artificials, rows) = build_tableau_with_rows(&data, &config);
+    let mut matrix = Matrix::new(tableau, variables, artificials);
+    matrix.solve().unwrap();
+    // every row of the single battery has a slack
+    let slacks = matrix.get_slacks();
+    assert_eq!(slacks.len(), rows.len());
+    let slack = |row| slacks[rows.iter().position(|&r| r == row).unwrap()];
+    assert!(slack((ConstraintKind::Capacity, 0)).abs() < 1e-9);
+    // the charge of 6 W leaves 2 W of the grid limit
+    assert!((slack((ConstraintKind::MaxCharge, 0)) - 2.0).abs() < 1e-9);
+
+    let planning = calculation(&data, &config).unwrap();
+    let binding = |i: usize| planning.plans[i].binding_constraints.clone();
+    assert_eq!(binding(0), ["Capacity"]);
+    assert_eq!(binding(2), ["Capacity"]);
+    // the overloads empty the battery
+    assert_eq!(binding(1), ["Discharge"]);
+  }
+
+  #[test]
+  fn forced_actions() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    let mut data = vec![
+      Data { start, end, power: 2.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 2.0, price: 3.0, ..Default::default() },
+      Data { start, end, power: 6.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 2.0, price: 5.0, ..Default::default() },
+    ];
+    let config = Config { max_consumption: 8.0, battery_capacity: 4.0, battery_max_charge: 8.0, ..config() };
+    // without an overload the battery stays idle
+    let idle = calculation(&data, &config).unwrap();
+    assert!(idle.plans.iter().all(|p| p.energy_to_battery_wh == 0.0 && p.energy_from_battery_wh == 0.0));
+
+    // the midday discharge of 1 Wh is charged in the cheapest interval before it
+    data[2].forced_discharge = Some(4.0);
+    let planning = calculation(&data, &config).unwrap();
+    assert_eq!(verify_plan(&data, &config, &planning.plans), Ok(()));
+    let to_battery: Vec<f64> = planning.plans.iter().map(|p| p.energy_to_battery_wh).collect();
+    assert_eq!(to_battery, [1.0, 0.0, 0.0, 0.0]);
+    assert_eq!(planning.plans[2].energy_from_battery_wh, 1.0);
+    assert_eq!(planning.grid_power[2], 2.0);
+    // the plan of the idle battery does not do what is forced
+    let violation = verify_plan(&data, &config, &idle.plans).unwrap_err();
+    assert_eq!((violation.interval, violation.constraint), (2, PlanConstraint::Forced));
+
+    // a forced charge at the end is kept, even at the highest price
+    data[3].forced_charge = Some(4.0);
+    let planning = calculation(&data, &config).unwrap();
+    assert_eq!(verify_plan(&data, &config, &planning.plans), Ok(()));
+    assert_eq!(planning.plans[3].energy_to_battery_wh, 1.0);
+
+    // above the rates of the battery
+    let slow = Config { battery_max_discharge: Some(2.0), ..config.clone() };
+    assert_eq!(calculation(&data, &slow).unwrap_err(), SolveError::Infeasible);
+    data[3].forced_charge = Some(20.0);
+    assert_eq!(calculation(&data, &config).unwrap_err(), SolveError::Infeasible);
+  }
+
+  #[test]
+  fn ramp_limit() {
+    init();
+
+    let start = Utc::now();
+    let end = Utc::now();
+    // the overload of 8 W at the end is charged at the lowest price, in the middle
+    let data = vec![
+      Data { start, end, power: 0.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 0.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 16.0, price: 2.0, ..Default::default() },
+    ];
+    let config = Config { max_consumption: 8.0, battery_capacity: 4.0, battery_max_charge: 8.0, ..config() };
+    let jump = calculation(&data, &config).unwrap();
+    let to_battery: Vec<f64> = jump.plans.iter().map(|p| p.energy_to_battery_wh).collect();
+    assert_eq!(to_battery, [0.0, 0.0, 2.0, 0.0, 0.0]);
+
+    // 2 W more or less every interval, the charge of 8 W is spread over its neighbours
+    let ramped = Config { max_ramp_per_interval: Some(2.0), ..config };
+    let planning = calculation(&data, &ramped).unwrap();
+    assert_eq!(verify_plan(&data, &ramped, &planning.plans), Ok(()));
+    let expected = [0.0, 0.5, 1.0, 0.5, 0.0];
+    for (plan, expected) in planning.plans.iter().zip(expected) {
+      assert!((plan.energy_to_battery_wh - expected).abs() < 1e-9, "{:?}", planning.plans);
+    }
+    assert!(planning.total_cost > jump.total_cost);
+    let violation = verify_plan(&data, &ramped, &jump.plans).unwrap_err();
+    assert_eq!((violation.interval, violation.constraint, violation.value), (2, PlanConstraint::Ramp, 8.0));
   }
 }
@@ -1,8 +1,16 @@
-use chrono::{DateTime, Utc};
+use std::{
+  ffi::OsString,
+  fmt,
+  io::{Read, Write},
+};
+
+use chrono::{DateTime, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use flate2::read::GzDecoder;
 use log::debug;
 use serde::{Deserialize, Serialize};
 
-use crate::Args;
+use crate::{calculation::count_mode_switches, Args};
 
 #[derive(Debug, Deserialize)]
 struct Consumption {
@@ -10,6 +18,24 @@ struct Consumption {
   end: DateTime<Utc>,
   #[serde(rename = "consumption_average_power_interval")]
   power: f64,
+  #[serde(default)]
+  generation_power: f64,
+  #[serde(default)]
+  max_power: Option<f64>,
+  #[serde(default)]
+  capacity: Option<f64>,
+  #[serde(default)]
+  forced_charge: Option<f64>,
+  #[serde(default)]
+  forced_discharge: Option<f64>,
+  #[serde(default = "allowed")]
+  allow_charge: bool,
+  #[serde(default = "allowed")]
+  allow_discharge: bool,
+}
+
+fn allowed() -> bool {
+  true
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,6 +44,9 @@ struct Price {
   end: DateTime<Utc>,
   #[serde(rename = "market_price_per_kwh")]
   value: f64,
+  /// what a kWh sold to the grid earns, at most the price of buying it
+  #[serde(default, rename = "sell_price_per_kwh")]
+  sell: Option<f64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,113 +59,2709 @@ struct Prices {
   prices: Vec<Price>,
 }
 
+/// Everything in one JSON document, the configuration can still come from its own file
+#[derive(Debug, Deserialize)]
+struct Combined {
+  #[serde(alias = "consumption")]
+  forecasts: Vec<Consumption>,
+  prices: Vec<Price>,
+  #[serde(default)]
+  config: Option<Config>,
+}
+
+#[derive(Debug, Serialize)]
 pub struct Data {
   pub start: DateTime<Utc>,
   pub end: DateTime<Utc>,
+  /// average power used in the interval, in W like every power in the configuration
   pub power: f64,
+  /// average power of the on-site generation in the interval, solar panels for example, in W.
+  /// Only planned with the max_self_consumption objective
+  pub generation_power: f64,
+  /// price of a kWh taken from the grid, the energy of an interval is power / intervals_per_hour in Wh
   pub price: f64,
+  /// price of a kWh sold to the grid in this interval, when absent the feed-in price from the configuration applies
+  pub sell_price: Option<f64>,
+  /// grid limit for this interval only, when absent the limit from the configuration applies
+  pub max_power: Option<f64>,
+  /// usable battery capacity in this interval only, when absent the capacity from the configuration applies
+  pub capacity: Option<f64>,
+  /// the least the battery holds at the end of this interval only, from the schedule of the configuration,
+  /// when absent its min_charge applies
+  pub min_charge: Option<f64>,
+  /// the battery charges exactly this power in this interval, in W, a grid services obligation for example
+  pub forced_charge: Option<f64>,
+  /// the battery gives the consumers exactly this power in this interval, in W, the grid gives the rest
+  pub forced_discharge: Option<f64>,
+  /// the battery can be charged in this interval
+  pub allow_charge: bool,
+  /// the battery can be discharged in this interval, to cover an overload or to export
+  pub allow_discharge: bool,
 }
 
-#[derive(Debug, Deserialize)]
+impl Default for Data {
+  fn default() -> Self {
+    Data {
+      start: DateTime::default(),
+      end: DateTime::default(),
+      power: 0.0,
+      generation_power: 0.0,
+      price: 0.0,
+      sell_price: None,
+      max_power: None,
+      capacity: None,
+      min_charge: None,
+      forced_charge: None,
+      forced_discharge: None,
+      allow_charge: true,
+      allow_discharge: true,
+    }
+  }
+}
+
+impl Data {
+  /// The grid limit in this interval. A forced discharge takes its place, the grid gives exactly what the battery
+  /// leaves of the consumption and the battery covers the rest like an overload
+  pub fn max_consumption(&self, config: &Config) -> f64 {
+    match self.forced_discharge {
+      Some(forced) => self.power - forced,
+      None => self.connection_limit(config),
+    }
+  }
+
+  /// The limit of the grid connection in this interval, whatever the battery does
+  pub fn connection_limit(&self, config: &Config) -> f64 {
+    self.max_power.unwrap_or(config.max_consumption)
+  }
+
+  /// What a kWh sold to the grid earns in this interval, None when nothing can be sold
+  pub fn sell_price(&self, config: &Config) -> Option<f64> {
+    self.sell_price.or(config.feed_in_price)
+  }
+
+  /// The consumption less the generation, what the grid gives without the battery, negative with a surplus
+  pub fn net_power(&self) -> f64 {
+    self.power - self.generation_power
+  }
+
+  /// The consumption is above the grid limit, only the battery can cover the difference.
+  /// At the limit exactly the interval is an underload where the battery can not charge
+  pub fn is_overload(&self, config: &Config) -> bool {
+    self.net_power() > self.max_consumption(config)
+  }
+
+  /// The usable battery capacity in this interval
+  pub fn battery_capacity(&self, config: &Config) -> f64 {
+    self.capacity.unwrap_or(config.battery_capacity)
+  }
+
+  /// The reserve the battery keeps in this interval
+  pub fn min_charge(&self, config: &Config) -> f64 {
+    self.min_charge.unwrap_or(config.min_charge)
+  }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Config {
   pub max_consumption: f64,
+  #[serde(default)]
   pub battery_capacity: f64,
+  #[serde(default)]
   pub battery_max_charge: f64,
+  /// highest power the battery can deliver, to the consumers and the grid together, no limit without it
+  #[serde(default)]
+  pub battery_max_discharge: Option<f64>,
+  #[serde(default)]
   pub battery_initial_charge: f64,
+  /// deprecated, round trip efficiency with all the loss taken when charging.
+  /// Used as the charge efficiency when charge_efficiency is missing.
+  #[serde(default = "default_efficiency")]
   pub battery_efficiency: f64,
+  #[serde(default)]
   pub battery_final_charge: f64,
+  /// price per kWh missing from battery_final_charge at the end. Without it the final charge has to be reached,
+  /// with it the plan can fall short and the shortfall is paid for in the objective
+  #[serde(default)]
+  pub final_charge_penalty: Option<f64>,
+  /// number of consumption intervals in an hour, derived from the consumption series when reading the data
+  #[serde(skip, default = "default_intervals_per_hour")]
+  pub intervals_per_hour: f64,
+  /// price per kWh paid for the energy exported from the battery, no exports without it
+  pub feed_in_price: Option<f64>,
+  /// part of the energy taken from the grid that ends up in the battery
+  pub charge_efficiency: Option<f64>,
+  /// part of the energy taken from the battery that reaches the consumers or the grid
+  pub discharge_efficiency: Option<f64>,
+  /// part of the stored energy lost in an hour
+  #[serde(default)]
+  pub self_discharge_per_hour: f64,
+  /// reserve that has to stay in the battery at all times
+  #[serde(default)]
+  pub min_charge: f64,
+  /// wear of the battery, paid for every kWh charged
+  #[serde(default)]
+  pub cycle_cost_per_kwh: f64,
+  /// what the plan optimises, the cost by default
+  #[serde(default)]
+  pub objective: Objective,
+  /// price per kW of the highest power taken from the grid during the planned period, added to the cost.
+  /// A tariff with a monthly demand charge has to be prorated to the length of the period.
+  #[serde(default)]
+  pub demand_charge_per_kw: f64,
+  /// power that has to be taken from the grid in every interval, whatever the battery does
+  #[serde(default)]
+  pub min_grid_power: f64,
+  /// most energy, in Wh, taken from the grid over the planned period, meant for plans of a day.
+  /// Exported energy counts against it, no limit without it
+  #[serde(default)]
+  pub daily_import_quota_wh: Option<f64>,
+  /// most the charging power, in W, changes from one interval to the next, the inverter can not jump from
+  /// idle to full charge. Only between intervals that can both charge, no limit without it
+  #[serde(default)]
+  pub max_ramp_per_interval: Option<f64>,
+  /// part of the market price added for the grid energy, the taxes and the margin of the supplier
+  #[serde(default)]
+  pub grid_price_markup: f64,
+  /// added per kWh to the grid energy after the markup, the network fees
+  #[serde(default)]
+  pub grid_price_fixed_adder: f64,
+  /// several batteries, each with its own parameters, instead of the single battery of the fields above.
+  /// They share the grid and cover the overloads together
+  #[serde(default)]
+  pub batteries: Vec<Battery>,
+  /// the charge efficiency as a curve of the state of charge, pairs of the part of the capacity where a segment
+  /// ends and the efficiency of charging in it, `[[0.8, 0.95], [1.0, 0.85]]`. Replaces charge_efficiency
+  #[serde(default)]
+  pub efficiency_segments: Vec<(f64, f64)>,
+  /// overrides of the fields for the intervals within a time of day, the peak hours of a tariff for example.
+  /// Merged into the data by `apply_schedule`
+  #[serde(default)]
+  pub schedule: Vec<ScheduleEntry>,
+  /// label of the costs in the output, the prices are taken as they are
+  #[serde(default = "default_currency")]
+  pub currency: String,
+  /// unit of the energies in the output, the plan is converted from Wh to it
+  #[serde(default)]
+  pub energy_unit: EnergyUnit,
+}
+
+/// One of several batteries of a site
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Battery {
+  pub capacity: f64,
+  pub max_charge: f64,
+  /// no limit without it
+  #[serde(default)]
+  pub max_discharge: Option<f64>,
+  #[serde(default)]
+  pub initial_charge: f64,
+  #[serde(default)]
+  pub final_charge: f64,
+  /// part of the energy taken from the grid that ends up in the battery
+  #[serde(default = "default_efficiency")]
+  pub charge_efficiency: f64,
+  /// part of the energy taken from the battery that reaches the consumers
+  #[serde(default = "default_efficiency")]
+  pub discharge_efficiency: f64,
+  /// wear of the battery, paid for every kWh charged
+  #[serde(default)]
+  pub cycle_cost_per_kwh: f64,
+}
+
+impl Battery {
+  /// Same checks as for the single battery of the configuration
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    let numbers = [
+      ("capacity", Some(self.capacity)),
+      ("max_charge", Some(self.max_charge)),
+      ("max_discharge", self.max_discharge),
+      ("initial_charge", Some(self.initial_charge)),
+      ("final_charge", Some(self.final_charge)),
+      ("charge_efficiency", Some(self.charge_efficiency)),
+      ("discharge_efficiency", Some(self.discharge_efficiency)),
+      ("cycle_cost_per_kwh", Some(self.cycle_cost_per_kwh)),
+    ];
+    for (field, value) in numbers {
+      match value {
+        Some(value) if !value.is_finite() => return Err(ConfigError::NotFinite { field, value }),
+        Some(value) if value < 0.0 => return Err(ConfigError::Negative { field, value }),
+        _ => (),
+      }
+    }
+    for (field, value) in [("charge_efficiency", self.charge_efficiency), ("discharge_efficiency", self.discharge_efficiency)] {
+      if value <= 0.0 || value > 1.0 {
+        return Err(ConfigError::Efficiency { field, value });
+      }
+    }
+    for (field, value) in [("initial_charge", self.initial_charge), ("final_charge", self.final_charge)] {
+      if value > self.capacity {
+        return Err(ConfigError::AboveCapacity { field, value, capacity: self.capacity });
+      }
+    }
+    Ok(())
+  }
+}
+
+/// The values of the configuration for the intervals starting from `start` until before `end`, every day.
+/// The times of day are in UTC like the intervals, an `end` before the `start` goes on past midnight
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScheduleEntry {
+  pub start: NaiveTime,
+  pub end: NaiveTime,
+  #[serde(default)]
+  pub max_consumption: Option<f64>,
+  #[serde(default)]
+  pub battery_capacity: Option<f64>,
+  #[serde(default)]
+  pub min_charge: Option<f64>,
+}
+
+impl ScheduleEntry {
+  /// The interval starting at `start` is within the window
+  pub fn covers(&self, start: DateTime<Utc>) -> bool {
+    let time = start.time();
+    if self.start <= self.end {
+      self.start <= time && time < self.end
+    } else {
+      self.start <= time || time < self.end
+    }
+  }
+
+  // same checks as for the fields of the configuration, the reserve within the capacity of the window
+  fn validate(&self, config: &Config) -> Result<(), ConfigError> {
+    let numbers =
+      [("max_consumption", self.max_consumption), ("battery_capacity", self.battery_capacity), ("min_charge", self.min_charge)];
+    for (field, value) in numbers {
+      match value {
+        Some(value) if !value.is_finite() => return Err(ConfigError::NotFinite { field, value }),
+        Some(value) if value < 0.0 => return Err(ConfigError::Negative { field, value }),
+        _ => (),
+      }
+    }
+    let capacity = self.battery_capacity.unwrap_or(config.battery_capacity);
+    match self.min_charge {
+      Some(value) if value > capacity => Err(ConfigError::AboveCapacity { field: "min_charge", value, capacity }),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// The goal of the optimisation
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Objective {
+  /// the lowest bill for the energy, and for the peak when there is a demand charge
+  #[default]
+  MinimiseCost,
+  /// the lowest power taken from the grid in any interval, for tariffs with a demand charge
+  MinimisePeak,
+  /// the least energy exchanged with the grid, imported and exported, for a site with its own generation.
+  /// The surplus is stored for later instead of being exported
+  MaxSelfConsumption,
+}
+
+/// A configuration value that does not describe a real battery
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+  /// NaN or an infinity, it would spread through the tableau
+  NotFinite {
+    field: &'static str,
+    value: f64,
+  },
+  Negative {
+    field: &'static str,
+    value: f64,
+  },
+  Efficiency {
+    field: &'static str,
+    value: f64,
+  },
+  AboveCapacity {
+    field: &'static str,
+    value: f64,
+    capacity: f64,
+  },
+  SelfDischarge(f64),
+  /// a `--set` for a field the configuration does not have
+  UnknownKey {
+    key: String,
+    valid: Vec<String>,
+  },
+  /// a `--set` that is not `key=value` or whose value does not fit the field
+  InvalidOverride {
+    key: String,
+    message: String,
+  },
+  /// a value that does not make sense for the battery at `index` of `batteries`
+  Battery {
+    index: usize,
+    error: Box<ConfigError>,
+  },
+  /// an option that is only planned for the single battery
+  NotWithBatteries(&'static str),
+  /// the efficiency curve does not go from the empty to the full battery with decreasing efficiencies
+  Segments(String),
+  /// an option that is not planned with an efficiency curve
+  NotWithSegments(&'static str),
+  /// the environment variables do not make a configuration
+  Environment(String),
+  /// an option that is not planned with the max_self_consumption objective
+  NotWithSelfConsumption(&'static str),
+  /// a value that does not make sense for the entry at `index` of `schedule`
+  Schedule {
+    index: usize,
+    error: Box<ConfigError>,
+  },
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigError::NotFinite { field, value } => write!(f, "{} has to be a finite number, it is {}", field, value),
+      ConfigError::Negative { field, value } => write!(f, "{} can not be negative, it is {}", field, value),
+      ConfigError::Efficiency { field, value } => {
+        write!(f, "{} has to be above 0 and at most 1, it is {}", field, value)
+      }
+      ConfigError::AboveCapacity { field, value, capacity } => {
+        write!(f, "{} of {} is more than the battery capacity of {}", field, value, capacity)
+      }
+      ConfigError::SelfDischarge(value) => {
+        write!(f, "self_discharge_per_hour has to be at least 0 and below 1, it is {}", value)
+      }
+      ConfigError::UnknownKey { key, valid } => write!(f, "unknown key {}, valid keys are {}", key, valid.join(", ")),
+      ConfigError::InvalidOverride { key, message } => write!(f, "can not set {}: {}", key, message),
+      ConfigError::Battery { index, error } => write!(f, "battery {}: {}", index, error),
+      ConfigError::NotWithBatteries(field) => write!(f, "{} is not supported with several batteries", field),
+      ConfigError::Segments(message) => write!(f, "efficiency_segments: {}", message),
+      ConfigError::NotWithSegments(field) => write!(f, "{} is not supported with efficiency_segments", field),
+      ConfigError::Environment(message) => write!(f, "from the environment: {}", message),
+      ConfigError::NotWithSelfConsumption(field) => {
+        write!(f, "{} is not supported with the max_self_consumption objective", field)
+      }
+      ConfigError::Schedule { index, error } => write!(f, "schedule {}: {}", index, error),
+    }
+  }
 }
 
+impl std::error::Error for ConfigError {}
+
+impl Config {
+  pub fn charge_efficiency(&self) -> f64 {
+    self.charge_efficiency.unwrap_or(self.battery_efficiency)
+  }
+
+  // battery_efficiency already covers the whole round trip, discharging is lossless without a specific value
+  pub fn discharge_efficiency(&self) -> f64 {
+    self.discharge_efficiency.unwrap_or(1.0)
+  }
+
+  /// What a kWh taken from the grid costs at the given market price
+  pub fn grid_price(&self, market_price: f64) -> f64 {
+    market_price * (1.0 + self.grid_price_markup) + self.grid_price_fixed_adder
+  }
+
+  /// The part of the charge left in the battery after one interval
+  pub fn decay_per_interval(&self) -> f64 {
+    (1.0 - self.self_discharge_per_hour).powf(1.0 / self.intervals_per_hour)
+  }
+
+  /// Check the values make sense for a battery before building a tableau out of them
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    // NaN fails every comparison below, it has to be caught first
+    let numbers = [
+      ("max_consumption", Some(self.max_consumption)),
+      ("battery_capacity", Some(self.battery_capacity)),
+      ("battery_max_charge", Some(self.battery_max_charge)),
+      ("battery_max_discharge", self.battery_max_discharge),
+      ("battery_initial_charge", Some(self.battery_initial_charge)),
+      ("battery_efficiency", Some(self.battery_efficiency)),
+      ("battery_final_charge", Some(self.battery_final_charge)),
+      ("final_charge_penalty", self.final_charge_penalty),
+      ("feed_in_price", self.feed_in_price),
+      ("charge_efficiency", self.charge_efficiency),
+      ("discharge_efficiency", self.discharge_efficiency),
+      ("self_discharge_per_hour", Some(self.self_discharge_per_hour)),
+      ("min_charge", Some(self.min_charge)),
+      ("cycle_cost_per_kwh", Some(self.cycle_cost_per_kwh)),
+      ("demand_charge_per_kw", Some(self.demand_charge_per_kw)),
+      ("min_grid_power", Some(self.min_grid_power)),
+      ("daily_import_quota_wh", self.daily_import_quota_wh),
+      ("max_ramp_per_interval", self.max_ramp_per_interval),
+      ("grid_price_markup", Some(self.grid_price_markup)),
+      ("grid_price_fixed_adder", Some(self.grid_price_fixed_adder)),
+    ];
+    for (field, value) in numbers {
+      if let Some(value) = value.filter(|v| !v.is_finite()) {
+        return Err(ConfigError::NotFinite { field, value });
+      }
+    }
+
+    let non_negative = [
+      ("max_consumption", self.max_consumption),
+      ("battery_capacity", self.battery_capacity),
+      ("battery_max_charge", self.battery_max_charge),
+      ("battery_max_discharge", self.battery_max_discharge.unwrap_or(0.0)),
+      ("battery_initial_charge", self.battery_initial_charge),
+      ("battery_final_charge", self.battery_final_charge),
+      ("min_charge", self.min_charge),
+      ("cycle_cost_per_kwh", self.cycle_cost_per_kwh),
+      ("demand_charge_per_kw", self.demand_charge_per_kw),
+      ("min_grid_power", self.min_grid_power),
+      ("final_charge_penalty", self.final_charge_penalty.unwrap_or(0.0)),
+      ("daily_import_quota_wh", self.daily_import_quota_wh.unwrap_or(0.0)),
+      ("max_ramp_per_interval", self.max_ramp_per_interval.unwrap_or(0.0)),
+      ("grid_price_markup", self.grid_price_markup),
+    ];
+    if let Some(&(field, value)) = non_negative.iter().find(|(_, value)| *value < 0.0) {
+      return Err(ConfigError::Negative { field, value });
+    }
+
+    let efficiencies = [
+      ("battery_efficiency", Some(self.battery_efficiency)),
+      ("charge_efficiency", self.charge_efficiency),
+      ("discharge_efficiency", self.discharge_efficiency),
+    ];
+    for (field, value) in efficiencies {
+      if let Some(value) = value.filter(|v| *v <= 0.0 || *v > 1.0) {
+        return Err(ConfigError::Efficiency { field, value });
+      }
+    }
+
+    let stored = [
+      ("battery_initial_charge", self.battery_initial_charge),
+      ("battery_final_charge", self.battery_final_charge),
+      ("min_charge", self.min_charge),
+    ];
+    if let Some(&(field, value)) = stored.iter().find(|(_, value)| *value > self.battery_capacity) {
+      return Err(ConfigError::AboveCapacity { field, value, capacity: self.battery_capacity });
+    }
+
+    if !(0.0..1.0).contains(&self.self_discharge_per_hour) {
+      return Err(ConfigError::SelfDischarge(self.self_discharge_per_hour));
+    }
+
+    for (index, battery) in self.batteries.iter().enumerate() {
+      battery.validate().map_err(|error| ConfigError::Battery { index, error: Box::new(error) })?;
+    }
+    for (index, entry) in self.schedule.iter().enumerate() {
+      entry.validate(self).map_err(|error| ConfigError::Schedule { index, error: Box::new(error) })?;
+    }
+    // the tableau of several batteries only has the charges and the discharges
+    let single_battery_only = [
+      ("feed_in_price", self.feed_in_price.is_some()),
+      ("objective", self.objective != Objective::MinimiseCost),
+      ("demand_charge_per_kw", self.demand_charge_per_kw > 0.0),
+      ("min_grid_power", self.min_grid_power > 0.0),
+      ("daily_import_quota_wh", self.daily_import_quota_wh.is_some()),
+      ("max_ramp_per_interval", self.max_ramp_per_interval.is_some()),
+      ("self_discharge_per_hour", self.self_discharge_per_hour > 0.0),
+      ("min_charge", self.min_charge > 0.0),
+      ("schedule min_charge", self.schedule.iter().any(|e| e.min_charge.is_some_and(|m| m > 0.0))),
+      // every battery has its own capacity
+      ("schedule battery_capacity", self.schedule.iter().any(|e| e.battery_capacity.is_some())),
+      ("final_charge_penalty", self.final_charge_penalty.is_some()),
+    ];
+    // every battery has its own rates, efficiencies and wear, these would be ignored
+    let battery_fields = [
+      ("battery_max_discharge", self.battery_max_discharge.is_some()),
+      ("battery_efficiency", self.battery_efficiency != 1.0),
+      ("charge_efficiency", self.charge_efficiency.is_some()),
+      ("discharge_efficiency", self.discharge_efficiency.is_some()),
+      ("cycle_cost_per_kwh", self.cycle_cost_per_kwh > 0.0),
+    ];
+    if !self.batteries.is_empty() {
+      if let Some((field, _)) = single_battery_only.iter().chain(&battery_fields).find(|(_, set)| *set) {
+        return Err(ConfigError::NotWithBatteries(field));
+      }
+    }
+    if !self.efficiency_segments.is_empty() {
+      self.validate_segments(&single_battery_only)?;
+    }
+    // the tableau of the self consumption has the battery and the grid, not the tariffs around them
+    let not_with_self_consumption = [
+      ("demand_charge_per_kw", self.demand_charge_per_kw > 0.0),
+      ("min_grid_power", self.min_grid_power > 0.0),
+      ("daily_import_quota_wh", self.daily_import_quota_wh.is_some()),
+      ("max_ramp_per_interval", self.max_ramp_per_interval.is_some()),
+      ("final_charge_penalty", self.final_charge_penalty.is_some()),
+    ];
+    if self.objective == Objective::MaxSelfConsumption {
+      if let Some((field, _)) = not_with_self_consumption.iter().find(|(_, set)| *set) {
+        return Err(ConfigError::NotWithSelfConsumption(field));
+      }
+    }
+    Ok(())
+  }
+
+  // the segments are planned like several batteries, the same options are missing
+  fn validate_segments(&self, single_battery_only: &[(&'static str, bool)]) -> Result<(), ConfigError> {
+    if !self.batteries.is_empty() {
+      return Err(ConfigError::NotWithBatteries("efficiency_segments"));
+    }
+    if let Some((field, _)) =
+      single_battery_only.iter().chain(&[("charge_efficiency", self.charge_efficiency.is_some())]).find(|(_, set)| *set)
+    {
+      return Err(ConfigError::NotWithSegments(field));
+    }
+    let mut previous = (0.0, 1.0);
+    for &(end, efficiency) in &self.efficiency_segments {
+      if !end.is_finite() || !efficiency.is_finite() {
+        return Err(ConfigError::Segments(format!("[{end}, {efficiency}] has to be finite")));
+      }
+      if end <= previous.0 || end > 1.0 {
+        return Err(ConfigError::Segments(format!(
+          "the segment ending at {end} has to end after {} and at most at 1",
+          previous.0
+        )));
+      }
+      if efficiency <= 0.0 || efficiency > previous.1 {
+        // a linear program would fill a more efficient segment above first
+        return Err(ConfigError::Segments(format!(
+          "the efficiency of {efficiency} has to be above 0 and at most {}",
+          previous.1
+        )));
+      }
+      previous = (end, efficiency);
+    }
+    if previous.0 != 1.0 {
+      return Err(ConfigError::Segments(format!("the last segment ends at {}, it has to end at 1", previous.0)));
+    }
+    Ok(())
+  }
+
+  /// Sets the fields of the schedule on the intervals it covers, the last entry covering an interval wins.
+  /// The grid limit and the capacity of a record stay, they are more specific than the schedule
+  pub fn apply_schedule(&self, data: &mut [Data]) {
+    for d in data.iter_mut() {
+      let covering: Vec<&ScheduleEntry> = self.schedule.iter().filter(|e| e.covers(d.start)).collect();
+      let last = |field: fn(&ScheduleEntry) -> Option<f64>| covering.iter().rev().find_map(|e| field(e));
+      d.max_power = d.max_power.or(last(|e| e.max_consumption));
+      d.capacity = d.capacity.or(last(|e| e.battery_capacity));
+      d.min_charge = d.min_charge.or(last(|e| e.min_charge));
+    }
+  }
+
+  /// What the battery holds after charging `energy_wh` from `charge_wh`, along the efficiency curve from segment
+  /// to segment. Above the capacity the last efficiency goes on, without a curve it is charge_efficiency throughout
+  pub fn charge_after(&self, charge_wh: f64, energy_wh: f64) -> f64 {
+    let (mut charge, mut energy) = (charge_wh, energy_wh);
+    for &(end, efficiency) in &self.efficiency_segments {
+      let stored = (energy * efficiency).min((end * self.battery_capacity - charge).max(0.0));
+      charge += stored;
+      energy -= stored / efficiency;
+    }
+    charge + energy * self.top_efficiency()
+  }
+
+  /// The energy charging takes to bring the battery from `charge_wh` to `capacity`, along the efficiency curve
+  pub fn energy_to_fill(&self, charge_wh: f64, capacity: f64) -> f64 {
+    let (mut charge, mut energy) = (charge_wh, 0.0);
+    for &(end, efficiency) in &self.efficiency_segments {
+      let end = (end * self.battery_capacity).min(capacity);
+      energy += (end - charge).max(0.0) / efficiency;
+      charge = charge.max(end);
+    }
+    energy + (capacity - charge).max(0.0) / self.top_efficiency()
+  }
+
+  // the efficiency of charging a full battery
+  fn top_efficiency(&self) -> f64 {
+    self.efficiency_segments.last().map_or(self.charge_efficiency(), |&(_, efficiency)| efficiency)
+  }
+
+  /// The segments of the efficiency curve as batteries, each holds its part of the capacity and charges with its
+  /// efficiency. The initial charge fills them from the lowest one. They share the rates and the final charge of
+  /// the battery, those are not set on them.
+  pub fn segment_batteries(&self) -> Vec<Battery> {
+    let mut start = 0.0;
+    self
+      .efficiency_segments
+      .iter()
+      .map(|&(end, efficiency)| {
+        let capacity = (end - start) * self.battery_capacity;
+        let initial_charge = (self.battery_initial_charge - start * self.battery_capacity).clamp(0.0, capacity);
+        start = end;
+        Battery {
+          capacity,
+          max_charge: self.battery_max_charge,
+          max_discharge: self.battery_max_discharge,
+          initial_charge,
+          final_charge: 0.0,
+          charge_efficiency: efficiency,
+          discharge_efficiency: self.discharge_efficiency(),
+          cycle_cost_per_kwh: self.cycle_cost_per_kwh,
+        }
+      })
+      .collect()
+  }
+
+  /// Reads a configuration from json, as echoed by serializing one. intervals_per_hour is not part of it,
+  /// it comes from the time series
+  pub fn from_json(text: &str) -> Result<Config, serde_json::Error> {
+    serde_json::from_str(text)
+  }
+
+  /// Replaces fields given as `key=value`, the value is read as JSON or else taken as a string
+  pub fn with_overrides(self, overrides: &[String]) -> Result<Config, ConfigError> {
+    if overrides.is_empty() {
+      return Ok(self);
+    }
+    let serde_json::Value::Object(mut fields) = serde_json::to_value(&self).expect("Config serializes to an object") else {
+      unreachable!("Config is a struct");
+    };
+    for text in overrides {
+      let Some((key, value)) = text.split_once('=') else {
+        return Err(ConfigError::InvalidOverride { key: text.clone(), message: "expected key=value".to_string() });
+      };
+      let (key, value) = (key.trim(), value.trim());
+      let Some(field) = fields.get_mut(key) else {
+        return Err(ConfigError::UnknownKey { key: key.to_string(), valid: fields.keys().cloned().collect() });
+      };
+      *field = parse_value(value);
+    }
+    let mut config: Config = serde_json::from_value(serde_json::Value::Object(fields))
+      .map_err(|e| ConfigError::InvalidOverride { key: overrides.join(" "), message: e.to_string() })?;
+    // not serialized, it comes from the time series
+    config.intervals_per_hour = self.intervals_per_hour;
+    Ok(config)
+  }
+
+  /// The configuration from variables named after its fields in capitals, MAX_CONSUMPTION or BATTERY_CAPACITY,
+  /// for a container without a toml file. The values are read like the `--set` ones and the fields without
+  /// a variable keep their defaults. None when none of the variables is set. The variables whose name or value
+  /// is not Unicode are skipped, none of the fields has such a name.
+  pub fn from_vars(vars: impl IntoIterator<Item = (OsString, OsString)>) -> Option<Result<Config, ConfigError>> {
+    // every field but max_consumption has a default, the names are those of a configuration with just it
+    let minimal: Config = serde_json::from_str(r#"{ "max_consumption": 0.0 }"#).expect("only max_consumption is required");
+    let serde_json::Value::Object(known) = serde_json::to_value(&minimal).expect("Config serializes to an object") else {
+      unreachable!("Config is a struct");
+    };
+    let fields: serde_json::Map<String, serde_json::Value> = vars
+      .into_iter()
+      .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+      .filter(|(name, _)| *name == name.to_uppercase())
+      .map(|(name, value)| (name.to_lowercase(), value))
+      .filter(|(key, _)| known.contains_key(key))
+      .map(|(key, value)| (key, parse_value(value.trim())))
+      .collect();
+    if fields.is_empty() {
+      return None;
+    }
+    Some(serde_json::from_value(serde_json::Value::Object(fields)).map_err(|e| ConfigError::Environment(e.to_string())))
+  }
+}
+
+// a value of --set or of an environment variable, JSON when it parses, a string otherwise
+fn parse_value(value: &str) -> serde_json::Value {
+  serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()))
+}
+
+fn default_intervals_per_hour() -> f64 {
+  4.0
+}
+
+fn default_efficiency() -> f64 {
+  1.0
+}
+
+fn default_currency() -> String {
+  "EUR".to_string()
+}
+
+/// prices are per kWh while the plan is expressed in Wh
+pub const WH_PER_KWH: f64 = 1000.0;
+
+const CONSUMPTION_COLUMNS: [&str; 3] = ["start", "end", "consumption_average_power_interval"];
+const PRICE_COLUMNS: [&str; 3] = ["start", "end", "market_price_per_kwh"];
+/// file name standing for the standard input
+const STDIN: &str = "-";
+
+/// Everything that can go wrong while reading the input files
+#[derive(Debug)]
+pub enum DataError {
+  Read {
+    filename: String,
+    error: std::io::Error,
+  },
+  Parse {
+    filename: String,
+    format: &'static str,
+    message: String,
+  },
+  NoConsumption,
+  NoPrices,
+  /// the prices start after the consumption
+  StartMismatch,
+  /// the prices end before the consumption
+  EndMismatch,
+  /// the consumption interval at `index` does not start where the previous one ended,
+  /// a positive gap is missing time, a negative one is an overlap
+  NonContiguous {
+    index: usize,
+    gap: TimeDelta,
+  },
+  /// the consumption interval at `index` is not as long as the first one
+  IntervalDuration {
+    index: usize,
+    duration: TimeDelta,
+    expected: TimeDelta,
+  },
+  /// the consumption interval at `index` is not within a single price interval,
+  /// it crosses the boundary between two prices or falls in a gap of the prices
+  Unpriced {
+    index: usize,
+  },
+  /// the record at `index` of the `series` ends where it starts
+  ZeroLength {
+    series: &'static str,
+    index: usize,
+  },
+  /// the record at `index` of the `series` starts with the one before it, a record given twice
+  Duplicate {
+    series: &'static str,
+    index: usize,
+  },
+  /// a record that parsed but can not be right
+  InvalidRecord {
+    index: usize,
+    reason: String,
+  },
+  /// the window given with --start and --end is empty or not within the consumption
+  Window {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+  },
+  /// a wall clock time skipped or repeated by the time zone when the clocks change
+  LocalTime {
+    time: NaiveDateTime,
+    timezone: Tz,
+  },
+  /// there is only one standard input, at most one of the files can be read from it
+  MultipleStdin,
+  /// the batch input has to carry its own configuration
+  NoConfig,
+  Config(ConfigError),
+}
+
+impl fmt::Display for DataError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DataError::Read { filename, error } => write!(f, "Unable to read file: {}, {}", filename, error),
+      DataError::Parse { filename, format, message } => {
+        write!(f, "Unable to parse {} from file {}: {}", format, filename, message)
+      }
+      DataError::NoConsumption => write!(f, "No consumption data"),
+      DataError::NoPrices => write!(f, "No price data"),
+      DataError::StartMismatch => write!(f, "The prices start after the forecasts"),
+      DataError::EndMismatch => write!(f, "The prices end before the forecasts"),
+      DataError::NonContiguous { index, gap } => {
+        write!(f, "Consumption interval {} does not follow the previous one, gap of {}", index, gap)
+      }
+      DataError::IntervalDuration { index, duration, expected } => {
+        write!(f, "Consumption interval {} lasts {}, expected {}", index, duration, expected)
+      }
+      DataError::Unpriced { index } => write!(f, "Consumption interval {} is not within a single price interval", index),
+      DataError::ZeroLength { series, index } => write!(f, "The {} record {} ends where it starts", series, index),
+      DataError::Duplicate { series, index } => {
+        write!(f, "The {} record {} has the same start as the one before it", series, index)
+      }
+      DataError::InvalidRecord { index, reason } => write!(f, "Invalid record {}: {}", index, reason),
+      DataError::Window { start, end } => write!(f, "The window from {} to {} is not within the forecasts", start, end),
+      DataError::LocalTime { time, timezone } => {
+        write!(f, "The time {} does not exist or is ambiguous in {}, give it with its offset", time, timezone)
+      }
+      DataError::MultipleStdin => write!(f, "Only one of the input files can be read from stdin"),
+      DataError::NoConfig => write!(f, "No configuration in the batch input"),
+      DataError::Config(error) => write!(f, "Invalid configuration: {}", error),
+    }
+  }
+}
+
+impl std::error::Error for DataError {}
+
+#[derive(Clone, Copy)]
 enum FileType {
   Json,
   Toml,
+  Csv,
 }
-fn read_file_and_parse<T>(filename: &str, file_type: FileType) -> T
+
+impl FileType {
+  // time series can come as Csv, everything else is Json
+  fn of_series(filename: &str) -> FileType {
+    let filename = filename.to_lowercase();
+    if filename.strip_suffix(GZIP_EXTENSION).unwrap_or(&filename).ends_with(".csv") {
+      FileType::Csv
+    } else {
+      FileType::Json
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      FileType::Json => "Json",
+      FileType::Toml => "Toml",
+      FileType::Csv => "Csv",
+    }
+  }
+}
+
+const GZIP_EXTENSION: &str = ".gz";
+
+// files ending in .gz are decompressed while reading, the format is the one of the name without .gz
+fn read_file(filename: &str) -> Result<String, DataError> {
+  if filename == STDIN {
+    return read_from(filename, std::io::stdin().lock());
+  }
+  if filename.to_lowercase().ends_with(GZIP_EXTENSION) {
+    let file = std::fs::File::open(filename).map_err(|error| DataError::Read { filename: filename.to_string(), error })?;
+    return read_from(filename, GzDecoder::new(file));
+  }
+  std::fs::read_to_string(filename).map_err(|error| DataError::Read { filename: filename.to_string(), error })
+}
+
+fn read_from<R: Read>(filename: &str, mut reader: R) -> Result<String, DataError> {
+  let mut text = String::new();
+  reader.read_to_string(&mut text).map_err(|error| DataError::Read { filename: filename.to_string(), error })?;
+  Ok(text)
+}
+
+fn parse<T>(filename: &str, text: &str, file_type: FileType) -> Result<T, DataError>
 where
   T: serde::de::DeserializeOwned,
 {
-  let text = match std::fs::read_to_string(filename) {
-    Ok(json) => json,
-    Err(e) => {
-      eprintln!("Unable to read file: {}, {}", filename, e);
-      std::process::exit(1);
-    }
+  let parsed = match file_type {
+    FileType::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+    FileType::Toml => toml::from_str(text).map_err(|e| e.to_string()),
+    FileType::Csv => unreachable!("Csv files hold time series and are read by read_series"),
   };
-  match file_type {
-    FileType::Json => match serde_json::from_str(&text) {
-      Ok(f) => f,
-      Err(e) => {
-        eprintln!("Unable to parse Json from file {}: {}", filename, e);
-        std::process::exit(1);
+  parsed.map_err(|message| DataError::Parse { filename: filename.to_string(), format: file_type.name(), message })
+}
+
+fn read_file_and_parse<T>(filename: &str, file_type: FileType) -> Result<T, DataError>
+where
+  T: serde::de::DeserializeOwned,
+{
+  parse(filename, &read_file(filename)?, file_type)
+}
+
+// a time series is either a Json document with the records in an array or a Csv file with one record per line
+fn read_series<W, T>(filename: &str, columns: &[&str], records: impl FnOnce(W) -> Vec<T>) -> Result<Vec<T>, DataError>
+where
+  W: serde::de::DeserializeOwned,
+  T: serde::de::DeserializeOwned,
+{
+  match FileType::of_series(filename) {
+    FileType::Csv => parse_csv(&read_file(filename)?, columns).map_err(|message| DataError::Parse {
+      filename: filename.to_string(),
+      format: FileType::Csv.name(),
+      message,
+    }),
+    file_type => Ok(records(read_file_and_parse(filename, file_type)?)),
+  }
+}
+
+// the header row is optional, without it the columns are expected in the given order
+fn parse_csv<T>(text: &str, columns: &[&str]) -> Result<Vec<T>, String>
+where
+  T: serde::de::DeserializeOwned,
+{
+  let mut reader = csv::ReaderBuilder::new().has_headers(false).trim(csv::Trim::All).from_reader(text.as_bytes());
+  let mut headers = None;
+  let mut series = Vec::new();
+  for (i, record) in reader.records().enumerate() {
+    let record = record.map_err(|e| e.to_string())?;
+    if i == 0 && record.iter().any(|h| columns.contains(&h)) {
+      if let Some(missing) = columns.iter().find(|c| !record.iter().any(|h| h == **c)) {
+        return Err(format!("missing column {missing}"));
       }
-    },
-    FileType::Toml => match toml::from_str(&text) {
-      Ok(f) => f,
-      Err(e) => {
-        eprintln!("Unable to parse Toml from file {}: {}", filename, e);
-        std::process::exit(1);
+      headers = Some(record);
+      continue;
+    }
+    if headers.is_none() && record.len() < columns.len() {
+      return Err(format!("line {} has {} columns, expected {}", i + 1, record.len(), columns.join(",")));
+    }
+    series.push(record.deserialize(headers.as_ref()).map_err(|e| format!("line {}: {}", i + 1, e))?);
+  }
+  Ok(series)
+}
+
+// every record of a series must end after it starts and start after the previous one
+fn check_order(series: &'static str, intervals: impl Iterator<Item = (DateTime<Utc>, DateTime<Utc>)>) -> Result<(), DataError> {
+  let mut previous_start = None;
+  for (index, (start, end)) in intervals.enumerate() {
+    // an interval of no time has no energy, the intervals per hour would be infinite
+    if end == start {
+      return Err(DataError::ZeroLength { series, index });
+    }
+    if end < start {
+      return Err(DataError::InvalidRecord { index, reason: format!("{series} ends at {end}, not after its start {start}") });
+    }
+    if previous_start == Some(start) {
+      return Err(DataError::Duplicate { series, index });
+    }
+    if previous_start.is_some_and(|previous| start < previous) {
+      return Err(DataError::InvalidRecord { index, reason: format!("{series} starts at {start}, not after the previous one") });
+    }
+    previous_start = Some(start);
+  }
+  Ok(())
+}
+
+fn check_records(forecasts: &[Consumption], prices: &[Price]) -> Result<(), DataError> {
+  // NaN or an infinity would spread through the tableau
+  for (index, f) in forecasts.iter().enumerate() {
+    let numbers = [
+      ("consumption", Some(f.power)),
+      ("generation", Some(f.generation_power)),
+      ("max_power", f.max_power),
+      ("capacity", f.capacity),
+      ("forced charge", f.forced_charge),
+      ("forced discharge", f.forced_discharge),
+    ];
+    if let Some((name, value)) = numbers.iter().find_map(|(name, v)| v.filter(|v| !v.is_finite()).map(|v| (name, v))) {
+      return Err(DataError::InvalidRecord { index, reason: format!("{name} is not a finite number, it is {value}") });
+    }
+  }
+  for (index, p) in prices.iter().enumerate() {
+    let numbers = [("price", Some(p.value)), ("sell price", p.sell)];
+    if let Some((name, value)) = numbers.iter().find_map(|(name, v)| v.filter(|v| !v.is_finite()).map(|v| (name, v))) {
+      return Err(DataError::InvalidRecord { index, reason: format!("{name} is not a finite number, it is {value}") });
+    }
+  }
+  // selling above the price of buying would pay for taking from the grid just to sell it back
+  if let Some(index) = prices.iter().position(|p| p.sell.is_some_and(|s| s > p.value)) {
+    let reason = format!("sell price {} above the price {}", prices[index].sell.unwrap_or_default(), prices[index].value);
+    return Err(DataError::InvalidRecord { index, reason });
+  }
+  if let Some(index) = forecasts.iter().position(|f| f.power < 0.0) {
+    let reason = format!("negative consumption {}", forecasts[index].power);
+    return Err(DataError::InvalidRecord { index, reason });
+  }
+  if let Some(index) = forecasts.iter().position(|f| f.generation_power < 0.0) {
+    let reason = format!("negative generation {}", forecasts[index].generation_power);
+    return Err(DataError::InvalidRecord { index, reason });
+  }
+  if let Some(index) = forecasts.iter().position(|f| f.capacity.is_some_and(|c| c < 0.0)) {
+    let reason = format!("negative capacity {}", forecasts[index].capacity.unwrap_or_default());
+    return Err(DataError::InvalidRecord { index, reason });
+  }
+  // the battery can not charge and discharge at once, nor give the consumers more than they take
+  for (index, f) in forecasts.iter().enumerate() {
+    let reason = match (f.forced_charge, f.forced_discharge) {
+      (Some(charge), _) if charge < 0.0 => format!("negative forced charge {charge}"),
+      (_, Some(discharge)) if discharge < 0.0 => format!("negative forced discharge {discharge}"),
+      (Some(_), Some(_)) => "both a forced charge and a forced discharge".to_string(),
+      (_, Some(discharge)) if discharge > f.power => {
+        format!("forced discharge {discharge} above the consumption {}", f.power)
+      }
+      _ => continue,
+    };
+    return Err(DataError::InvalidRecord { index, reason });
+  }
+  check_order("consumption", forecasts.iter().map(|f| (f.start, f.end)))?;
+  check_order("price", prices.iter().map(|p| (p.start, p.end)))
+}
+
+// every interval must start where the previous one ended and have the same duration
+fn check_contiguous(forecasts: &[Consumption]) -> Result<(), DataError> {
+  let Some(first) = forecasts.first() else {
+    return Ok(());
+  };
+  let expected = first.end - first.start;
+  for (index, pair) in forecasts.windows(2).enumerate() {
+    let index = index + 1;
+    let gap = pair[1].start - pair[0].end;
+    if gap != TimeDelta::zero() {
+      return Err(DataError::NonContiguous { index, gap });
+    }
+    let duration = pair[1].end - pair[1].start;
+    if duration != expected {
+      return Err(DataError::IntervalDuration { index, duration, expected });
+    }
+  }
+  Ok(())
+}
+
+// read the required data from the files and perform some basic checks.
+// The powers are in W, the battery energies in Wh and the prices per kWh, they are used as they are
+pub fn read_data(args: Args) -> Result<(Vec<Data>, Config), DataError> {
+  let files = [Some(&args.consumption), Some(&args.prices), Some(&args.config), args.input.as_ref()];
+  if files.iter().filter(|f| f.is_some_and(|f| f == STDIN)).count() > 1 {
+    return Err(DataError::MultipleStdin);
+  }
+  let (forecasts, prices, combined_config) = match &args.input {
+    Some(input) => {
+      let combined: Combined = read_file_and_parse(input, FileType::Json)?;
+      (combined.forecasts, combined.prices, combined.config)
+    }
+    None => (
+      read_series(&args.consumption, &CONSUMPTION_COLUMNS, |f: Forecasts| f.forecasts)?,
+      read_series(&args.prices, &PRICE_COLUMNS, |p: Prices| p.prices)?,
+      None,
+    ),
+  };
+  let to_utc = |time: Option<TimeArg>| time.map(|time| time.to_utc(args.timezone)).transpose();
+  let forecasts = clip(forecasts, to_utc(args.start)?, to_utc(args.end)?)?;
+  join(forecasts, prices, || {
+    let config = match combined_config {
+      Some(config) => config,
+      None => read_config(&args.config, std::env::vars_os)?,
+    };
+    config.with_overrides(&args.set).map_err(DataError::Config)
+  })
+}
+
+// the toml file, without it the configuration can come from the environment, only read then
+fn read_config<I>(path: &str, vars: impl FnOnce() -> I) -> Result<Config, DataError>
+where
+  I: IntoIterator<Item = (OsString, OsString)>,
+{
+  let config = read_file_and_parse(path, FileType::Toml);
+  if let Err(DataError::Read { error, .. }) = &config {
+    if error.kind() == std::io::ErrorKind::NotFound {
+      if let Some(config) = Config::from_vars(vars()) {
+        return config.map_err(DataError::Config);
       }
-    },
+    }
   }
+  config
 }
 
-// read the required data from the files and perform some basic checks
-pub fn read_data(args: Args) -> (Vec<Data>, Config) {
-  let forecast: Forecasts = read_file_and_parse(&args.consumption, FileType::Json);
-  let price: Prices = read_file_and_parse(&args.prices, FileType::Json);
-  debug!("Read {}, {} records", forecast.forecasts.len(), price.prices.len());
+/// A time given on the command line, RFC3339 with its offset or a wall clock time of the --timezone
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeArg {
+  Utc(DateTime<Utc>),
+  Local(NaiveDateTime),
+}
 
-  let forecasts = forecast.forecasts;
-  let prices = price.prices;
+impl std::str::FromStr for TimeArg {
+  type Err = String;
 
-  if forecasts.is_empty() {
-    panic!("No consumption data");
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match DateTime::parse_from_rfc3339(s) {
+      Ok(time) => Ok(TimeArg::Utc(time.with_timezone(&Utc))),
+      Err(_) => s
+        .parse()
+        .map(TimeArg::Local)
+        .map_err(|_| format!("{s} is neither RFC3339 nor a wall clock time like 2023-03-26T02:00:00")),
+    }
   }
-  if prices.is_empty() {
-    panic!("No price data");
+}
+
+impl TimeArg {
+  /// The instant of the time, a wall clock time is in the time zone, in UTC without one
+  pub fn to_utc(self, timezone: Option<Tz>) -> Result<DateTime<Utc>, DataError> {
+    match (self, timezone) {
+      (TimeArg::Utc(time), _) => Ok(time),
+      (TimeArg::Local(time), None) => Ok(time.and_utc()),
+      (TimeArg::Local(time), Some(timezone)) => match timezone.from_local_datetime(&time).single() {
+        Some(local) => Ok(local.with_timezone(&Utc)),
+        None => Err(DataError::LocalTime { time, timezone }),
+      },
+    }
   }
+}
 
-  // check that the start and the end of the time series is the same for both
-  if forecasts.first().unwrap().start != prices.first().unwrap().start {
-    panic!("Start of time series is not the same for both forecasts and prices");
+// only the intervals within the window are planned, the prices are joined to them afterwards
+fn clip(
+  forecasts: Vec<Consumption>,
+  start: Option<DateTime<Utc>>,
+  end: Option<DateTime<Utc>>,
+) -> Result<Vec<Consumption>, DataError> {
+  let (Some(first), Some(last)) = (forecasts.first(), forecasts.last()) else {
+    return Ok(forecasts);
+  };
+  if start.is_none() && end.is_none() {
+    return Ok(forecasts);
+  }
+  let (start, end) = (start.unwrap_or(first.start), end.unwrap_or(last.end));
+  if start < first.start || end > last.end {
+    return Err(DataError::Window { start, end });
   }
-  if forecasts.last().unwrap().end != prices.last().unwrap().end {
-    panic!("End of time series is not the same for both forecasts and prices");
+  let clipped: Vec<Consumption> = forecasts.into_iter().filter(|f| start <= f.start && f.end <= end).collect();
+  if clipped.is_empty() {
+    return Err(DataError::Window { start, end });
   }
-  debug!(
-    "Time series starts at {} and ends at {}, consumption and price time series overlap",
-    forecasts[0].start,
-    forecasts.last().unwrap().end
-  );
+  debug!("{} intervals from {} to {}", clipped.len(), start, end);
+  Ok(clipped)
+}
+
+/// Reads the consumption, the prices and the config from a single json document, as used by the batch mode
+pub fn read_batch<R: Read>(reader: R) -> Result<(Vec<Data>, Config), DataError> {
+  let combined: Combined = parse(STDIN, &read_from(STDIN, reader)?, FileType::Json)?;
+  let config = combined.config.ok_or(DataError::NoConfig)?;
+  join(combined.forecasts, combined.prices, || Ok(config))
+}
+
+// check the time series and join them, the config is only read once the series are known to be fine
+fn join<F>(forecasts: Vec<Consumption>, prices: Vec<Price>, read_config: F) -> Result<(Vec<Data>, Config), DataError>
+where
+  F: FnOnce() -> Result<Config, DataError>,
+{
+  debug!("Read {}, {} records", forecasts.len(), prices.len());
+  check_records(&forecasts, &prices)?;
+
+  let (Some(first_forecast), Some(last_forecast)) = (forecasts.first(), forecasts.last()) else {
+    return Err(DataError::NoConsumption);
+  };
+  let (Some(first_price), Some(last_price)) = (prices.first(), prices.last()) else {
+    return Err(DataError::NoPrices);
+  };
+
+  // the prices have to cover the consumption, they can start earlier and end later, a plan starting
+  // at 14:15 has the price of 14:00 to 15:00 for its first three intervals
+  if first_forecast.start < first_price.start {
+    return Err(DataError::StartMismatch);
+  }
+  if last_forecast.end > last_price.end {
+    return Err(DataError::EndMismatch);
+  }
+  check_contiguous(&forecasts)?;
+
+  let duration = first_forecast.end - first_forecast.start;
+  let intervals_per_hour = 3600.0 / duration.num_seconds() as f64;
+  debug!("{intervals_per_hour} consumption intervals per hour");
+  debug!("Time series starts at {} and ends at {}", first_forecast.start, last_forecast.end);
 
   let mut joined_data: Vec<Data> = Vec::with_capacity(forecasts.len());
-  // join the power intervals with the prices. There is one price for four power intervals
-  for (i, val) in forecasts.iter().enumerate() {
-    joined_data.push(Data { start: val.start, end: val.end, power: val.power, price: prices[i / 4].value });
+  // join the power intervals with the price they fall in. A price usually covers four 15 minutes power intervals
+  let mut prices = prices.iter().peekable();
+  for (index, val) in forecasts.iter().enumerate() {
+    // both series are in order, the prices that ended are not needed anymore
+    while prices.next_if(|price| price.end <= val.start).is_some() {}
+    let price = prices.peek().filter(|price| price.start <= val.start && val.end <= price.end);
+    let Some(price) = price else {
+      return Err(DataError::Unpriced { index });
+    };
+    joined_data.push(Data {
+      start: val.start,
+      end: val.end,
+      power: val.power,
+      generation_power: val.generation_power,
+      price: price.value,
+      sell_price: price.sell,
+      max_power: val.max_power,
+      capacity: val.capacity,
+      min_charge: None,
+      forced_charge: val.forced_charge,
+      forced_discharge: val.forced_discharge,
+      allow_charge: val.allow_charge,
+      allow_discharge: val.allow_discharge,
+    });
   }
 
   // read the conditions data
-  let config: Config = read_file_and_parse(&args.config, FileType::Toml);
+  let mut config = read_config()?;
+  config.validate().map_err(DataError::Config)?;
+  // like the feed-in price, the tableau of several batteries has no exports
+  if !config.batteries.is_empty() && joined_data.iter().any(|d| d.sell_price.is_some()) {
+    return Err(DataError::Config(ConfigError::NotWithBatteries("sell_price_per_kwh")));
+  }
+  // every battery has its own capacity and the segments are parts of the capacity of the config
+  if joined_data.iter().any(|d| d.capacity.is_some()) {
+    if !config.batteries.is_empty() {
+      return Err(DataError::Config(ConfigError::NotWithBatteries("capacity")));
+    }
+    if !config.efficiency_segments.is_empty() {
+      return Err(DataError::Config(ConfigError::NotWithSegments("capacity")));
+    }
+  }
+  // the other objectives plan the consumption alone, a surplus would be ignored
+  if config.objective != Objective::MaxSelfConsumption {
+    if let Some(index) = joined_data.iter().position(|d| d.generation_power > 0.0) {
+      let reason = "generation_power is only planned with the max_self_consumption objective".to_string();
+      return Err(DataError::InvalidRecord { index, reason });
+    }
+  } else if let Some(index) = joined_data.iter().position(|d| d.forced_charge.is_some() || d.forced_discharge.is_some()) {
+    let reason = "a forced charge or discharge is not planned with the max_self_consumption objective".to_string();
+    return Err(DataError::InvalidRecord { index, reason });
+  }
+  config.intervals_per_hour = intervals_per_hour;
+  config.apply_schedule(&mut joined_data);
 
-  (joined_data, config)
+  Ok((joined_data, config))
 }
 
-/// Output data is a JSON file with energy in and from the battery
-#[derive(Debug, Serialize)]
-pub struct Plan {
-  pub start: DateTime<Utc>,
-  pub end: DateTime<Utc>,
+/// Output data is a JSON file with energy in and from the battery.
+/// The times are in UTC, `with_timezone` moves them to another zone for the output
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(serialize = "DateTime<Z>: Serialize", deserialize = "DateTime<Z>: Deserialize<'de>"))]
+pub struct Plan<Z: TimeZone = Utc> {
+  pub start: DateTime<Z>,
+  pub end: DateTime<Z>,
   pub energy_from_battery_wh: f64,
   pub energy_to_battery_wh: f64,
+  /// energy taken from the battery and sold to the grid, on top of energy_from_battery_wh
+  pub energy_exported_wh: f64,
+  /// energy taken from the grid, for the consumers and the battery
+  #[serde(default)]
+  pub energy_imported_wh: f64,
+  /// energy stored in the battery at the end of the interval
+  pub battery_charge_wh: f64,
+  /// the consumption forecast of the interval, in W, only written with --verbose-output
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub consumption_power: Option<f64>,
+  /// the price of a kWh in the interval, only written with --verbose-output
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub price: Option<f64>,
+  /// the share of every battery, in the order of the configuration, only with several batteries.
+  /// The energies above are their totals
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub batteries: Vec<BatteryPlan>,
+  /// the constraints of the interval at their limit in the optimum, `Capacity` when the battery is full or
+  /// `MaxCharge` when it charges all it can, only the inequalities. Only set by `calculation`
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub binding_constraints: Vec<String>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct Out {
-  pub planning: Vec<Plan>,
+impl Plan {
+  /// The same plan with the times in the time zone, the energies are not changed
+  pub fn with_timezone<Z: TimeZone>(self, timezone: &Z) -> Plan<Z> {
+    Plan {
+      start: self.start.with_timezone(timezone),
+      end: self.end.with_timezone(timezone),
+      energy_from_battery_wh: self.energy_from_battery_wh,
+      energy_to_battery_wh: self.energy_to_battery_wh,
+      energy_exported_wh: self.energy_exported_wh,
+      energy_imported_wh: self.energy_imported_wh,
+      battery_charge_wh: self.battery_charge_wh,
+      consumption_power: self.consumption_power,
+      price: self.price,
+      batteries: self.batteries,
+      binding_constraints: self.binding_constraints,
+    }
+  }
+}
+
+/// What one of several batteries does in an interval
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BatteryPlan {
+  pub energy_from_battery_wh: f64,
+  pub energy_to_battery_wh: f64,
+  /// energy stored in the battery at the end of the interval
+  pub battery_charge_wh: f64,
+}
+
+/// The plan together with the total cost of the electricity once the plan is applied
+#[derive(Debug)]
+pub struct Planning {
+  pub plans: Vec<Plan>,
+  pub total_cost: f64,
+  /// the energy taken from the battery over the whole plan, in battery capacities
+  pub equivalent_full_cycles: f64,
+  /// what the battery is missing from the final charge at the end, only with a final charge penalty
+  pub final_charge_shortfall_wh: f64,
+  /// the energy put into the battery, before the charging losses
+  pub total_charged_wh: f64,
+  /// the energy taken from the battery, for the overloads and the exports
+  pub total_discharged_wh: f64,
+  /// the power taken from the grid in every interval once the plan is applied, in W, negative when exporting.
+  /// The consumption plus what charges the battery, less what the battery delivers
+  pub grid_power: Vec<f64>,
 }
 
-pub fn print_output(planning: Vec<Plan>) {
-  let out = Out { planning };
-  let json = serde_json::to_string_pretty(&out).expect("Unable to serialize output");
-  println!("{}", json);
+impl Planning {
+  /// Leaves the consumption and the price of the intervals out of the plans, for a shorter output
+  pub fn without_inputs(mut self) -> Self {
+    for plan in &mut self.plans {
+      plan.consumption_power = None;
+      plan.price = None;
+    }
+    self
+  }
+
+  /// The energies of the plans and the totals in another unit, they are made in Wh. The powers stay in W
+  pub fn in_unit(mut self, unit: EnergyUnit) -> Self {
+    let scale = 1.0 / unit.wh_per_unit();
+    for plan in &mut self.plans {
+      plan.energy_from_battery_wh *= scale;
+      plan.energy_to_battery_wh *= scale;
+      plan.energy_exported_wh *= scale;
+      plan.energy_imported_wh *= scale;
+      plan.battery_charge_wh *= scale;
+      for battery in &mut plan.batteries {
+        battery.energy_from_battery_wh *= scale;
+        battery.energy_to_battery_wh *= scale;
+        battery.battery_charge_wh *= scale;
+      }
+    }
+    self.final_charge_shortfall_wh *= scale;
+    self.total_charged_wh *= scale;
+    self.total_discharged_wh *= scale;
+    self
+  }
+}
+
+/// The unit of the energies in the output, the fields keep their names and the summary has the unit
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, clap::ValueEnum)]
+pub enum EnergyUnit {
+  #[default]
+  Wh,
+  #[serde(rename = "kWh", alias = "kwh")]
+  Kwh,
+  #[serde(rename = "MWh", alias = "mwh")]
+  Mwh,
+}
+
+impl EnergyUnit {
+  pub fn wh_per_unit(self) -> f64 {
+    match self {
+      EnergyUnit::Wh => 1.0,
+      EnergyUnit::Kwh => WH_PER_KWH,
+      EnergyUnit::Mwh => WH_PER_KWH * WH_PER_KWH,
+    }
+  }
+
+  pub fn label(self) -> &'static str {
+    match self {
+      EnergyUnit::Wh => "Wh",
+      EnergyUnit::Kwh => "kWh",
+      EnergyUnit::Mwh => "MWh",
+    }
+  }
+}
+
+/// What the optimisation is worth
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Summary {
+  /// the bill without a battery, the overloads are simply taken from the grid
+  pub baseline_cost: f64,
+  /// the bill with the planned use of the battery
+  pub optimised_cost: f64,
+  pub savings: f64,
+  /// the energy taken from the battery in battery capacities, warranties are given in these cycles
+  pub equivalent_full_cycles: f64,
+  /// what the battery is missing from the final charge at the end
+  pub final_charge_shortfall_wh: f64,
+  /// the energy charged, more than the energy discharged by the losses of the battery efficiency
+  pub total_charged_wh: f64,
+  /// the energy discharged, the overloads and the exports
+  pub total_discharged_wh: f64,
+  /// how often the battery goes from charging to discharging or back
+  pub mode_switches: usize,
+  /// the currency of the costs above
+  pub currency: String,
+  /// the unit of the energy in the plan
+  pub energy_unit: EnergyUnit,
+}
+
+impl Summary {
+  pub fn new(data: &[Data], config: &Config, planning: &Planning) -> Self {
+    // without the battery a surplus of the generation is sold, when it can be
+    let baseline_cost = data
+      .iter()
+      .map(|d| config.grid_price(d.price) * d.net_power().max(0.0) + d.sell_price(config).unwrap_or(0.0) * d.net_power().min(0.0))
+      .sum::<f64>()
+      / config.intervals_per_hour
+      / WH_PER_KWH;
+    let optimised_cost = planning.total_cost;
+    Summary {
+      baseline_cost,
+      optimised_cost,
+      savings: baseline_cost - optimised_cost,
+      equivalent_full_cycles: planning.equivalent_full_cycles,
+      final_charge_shortfall_wh: planning.final_charge_shortfall_wh,
+      total_charged_wh: planning.total_charged_wh,
+      total_discharged_wh: planning.total_discharged_wh,
+      mode_switches: count_mode_switches(&planning.plans),
+      currency: config.currency.clone(),
+      energy_unit: config.energy_unit,
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(bound(serialize = "DateTime<Z>: Serialize", deserialize = "DateTime<Z>: Deserialize<'de>"))]
+pub struct Out<Z: TimeZone = Utc> {
+  pub planning: Vec<Plan<Z>>,
+  pub summary: Summary,
+}
+
+/// Aligned text table of the plan next to the input, for a quick look at the result
+pub struct PlanTable<'a> {
+  pub data: &'a [Data],
+  pub config: &'a Config,
+  pub plans: &'a [Plan],
+  /// the zone of the start times, UTC when missing
+  pub timezone: Option<Tz>,
+}
+
+impl fmt::Display for PlanTable<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    // the offset of a time zone takes more room than the Z of UTC
+    let width = if self.timezone.is_some() { 25 } else { 20 };
+    // the plans are in Wh, the columns in the unit of the config
+    let unit = self.config.energy_unit;
+    let scale = 1.0 / unit.wh_per_unit();
+    let header = |name: &str| format!("{name}_{}", unit.label().to_lowercase());
+    writeln!(
+      f,
+      "{:<width$} {:>10} {:>14} {:>13} {:>13} {:>13}",
+      "start",
+      "price",
+      header("consumed"),
+      header("charge"),
+      header("discharge"),
+      header("battery")
+    )?;
+    let (mut consumed, mut charged, mut discharged) = (0.0, 0.0, 0.0);
+    for (d, p) in self.data.iter().zip(self.plans) {
+      let consumption = d.power / self.config.intervals_per_hour * scale;
+      let (charge, discharge, battery) =
+        (p.energy_to_battery_wh * scale, p.energy_from_battery_wh * scale, p.battery_charge_wh * scale);
+      consumed += consumption;
+      charged += charge;
+      discharged += discharge;
+      let start = match self.timezone {
+        Some(timezone) => p.start.with_timezone(&timezone).to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        None => p.start.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+      };
+      writeln!(
+        f,
+        "{:<width$} {:>10.4} {:>14.2} {:>13.2} {:>13.2} {:>13.2}",
+        start, d.price, consumption, charge, discharge, battery
+      )?;
+    }
+    writeln!(f, "{:<width$} {:>10} {:>14.2} {:>13.2} {:>13.2} {:>13}", "total", "", consumed, charged, discharged, "")
+  }
+}
+
+/// The energy accounting of a plan, interval by interval: what the battery holds before, what goes in and out,
+/// what is lost on the way and what it holds after. A check that the plan keeps to the physics of the battery,
+/// the `residual_wh` of every interval is zero. The energies are in Wh, a power of 1 W lasting one interval
+/// is 1 / intervals_per_hour Wh.
+pub struct EnergyBalance<'a> {
+  pub config: &'a Config,
+  pub planning: &'a Planning,
+}
+
+/// One interval of the `EnergyBalance`, all in Wh
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BalanceRow {
+  /// the charge of the battery at the start of the interval
+  pub start_wh: f64,
+  /// the energy taken in, before the charging losses
+  pub charged_wh: f64,
+  /// the energy given out, to the consumers and the grid, after the discharging losses
+  pub discharged_wh: f64,
+  /// the losses of charging, discharging and of the self discharge
+  pub losses_wh: f64,
+  /// the charge of the battery at the end of the interval
+  pub end_wh: f64,
+  /// the energy taken from the grid, negative when it is sent to the grid
+  pub grid_wh: f64,
+}
+
+impl BalanceRow {
+  /// What the interval does not account for, zero when the charges follow from the flows
+  pub fn residual_wh(&self) -> f64 {
+    self.start_wh + self.charged_wh - self.discharged_wh - self.losses_wh - self.end_wh
+  }
+}
+
+impl EnergyBalance<'_> {
+  pub fn rows(&self) -> Vec<BalanceRow> {
+    let config = self.config;
+    let initial_charge = if config.batteries.is_empty() {
+      config.battery_initial_charge
+    } else {
+      config.batteries.iter().map(|b| b.initial_charge).sum()
+    };
+    let decay = config.decay_per_interval();
+    let mut start_wh = initial_charge;
+    let mut rows = Vec::with_capacity(self.planning.plans.len());
+    for (i, plan) in self.planning.plans.iter().enumerate() {
+      let discharged_wh = plan.energy_from_battery_wh + plan.energy_exported_wh;
+      let losses_wh = if !plan.batteries.is_empty() && plan.batteries.len() == config.batteries.len() {
+        // every battery with its own efficiencies, they do not self discharge
+        plan
+          .batteries
+          .iter()
+          .zip(&config.batteries)
+          .map(|(p, b)| {
+            p.energy_to_battery_wh * (1.0 - b.charge_efficiency) + p.energy_from_battery_wh * (1.0 / b.discharge_efficiency - 1.0)
+          })
+          .sum()
+      } else if !config.efficiency_segments.is_empty() {
+        // the plan only has the total of the segments, the curve tells what of it is stored
+        let kept = start_wh * decay;
+        let stored = config.charge_after(kept, plan.energy_to_battery_wh) - kept;
+        start_wh - kept + plan.energy_to_battery_wh - stored + discharged_wh * (1.0 / config.discharge_efficiency() - 1.0)
+      } else {
+        start_wh * (1.0 - decay)
+          + plan.energy_to_battery_wh * (1.0 - config.charge_efficiency())
+          + discharged_wh * (1.0 / config.discharge_efficiency() - 1.0)
+      };
+      let grid_wh = self.planning.grid_power.get(i).map_or(plan.energy_imported_wh, |power| power / config.intervals_per_hour);
+      rows.push(BalanceRow {
+        start_wh,
+        charged_wh: plan.energy_to_battery_wh,
+        discharged_wh,
+        losses_wh,
+        end_wh: plan.battery_charge_wh,
+        grid_wh,
+      });
+      start_wh = plan.battery_charge_wh;
+    }
+    rows
+  }
+}
+
+impl fmt::Display for EnergyBalance<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(
+      f,
+      "# {} intervals per hour, the energies in Wh, 1 W during an interval is {} Wh",
+      self.config.intervals_per_hour,
+      1.0 / self.config.intervals_per_hour
+    )?;
+    writeln!(
+      f,
+      "{:<20} {:>12} {:>12} {:>14} {:>12} {:>12} {:>12} {:>12}",
+      "start", "soc_start_wh", "charged_wh", "discharged_wh", "losses_wh", "soc_end_wh", "grid_wh", "residual_wh"
+    )?;
+    for (plan, row) in self.planning.plans.iter().zip(self.rows()) {
+      writeln!(
+        f,
+        "{:<20} {:>12.2} {:>12.2} {:>14.2} {:>12.2} {:>12.2} {:>12.2} {:>12.2}",
+        plan.start.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        row.start_wh,
+        row.charged_wh,
+        row.discharged_wh,
+        row.losses_wh,
+        row.end_wh,
+        row.grid_wh,
+        row.residual_wh()
+      )?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+  /// the plan and the summary
+  Json,
+  /// one line per interval, without the summary
+  Csv,
+  /// one JSON plan per line, without the summary, every line is flushed as soon as it is written
+  Jsonl,
+}
+
+pub fn write_output<W: Write, Z: TimeZone>(out: &Out<Z>, format: OutputFormat, mut writer: W) -> std::io::Result<()>
+where
+  DateTime<Z>: Serialize,
+{
+  match format {
+    OutputFormat::Json => {
+      serde_json::to_writer_pretty(&mut writer, out)?;
+      writeln!(writer)
+    }
+    OutputFormat::Csv => {
+      let mut csv_writer = csv::Writer::from_writer(writer);
+      // a line per interval has no room for the batteries, only their totals are written, nor for the binding constraints
+      for plan in &out.planning {
+        if plan.batteries.is_empty() && plan.binding_constraints.is_empty() {
+          csv_writer.serialize(plan)?;
+        } else {
+          csv_writer.serialize(Plan { batteries: Vec::new(), binding_constraints: Vec::new(), ..plan.clone() })?;
+        }
+      }
+      csv_writer.flush()
+    }
+    OutputFormat::Jsonl => {
+      // a reader at the other end of a pipe can start on the first intervals of a long horizon
+      for plan in &out.planning {
+        serde_json::to_writer(&mut writer, plan)?;
+        writeln!(writer)?;
+        writer.flush()?;
+      }
+      Ok(())
+    }
+  }
+}
+
+/// Writes the consumption joined with the prices as json, to check which price every interval got
+pub fn echo_input<W: Write>(data: &[Data], mut writer: W) -> std::io::Result<()> {
+  serde_json::to_writer_pretty(&mut writer, data)?;
+  writeln!(writer)
+}
+
+pub fn print_output(
+  data: &[Data],
+  config: &Config,
+  planning: Planning,
+  format: OutputFormat,
+  timezone: Option<Tz>,
+  writer: Box<dyn Write>,
+) -> std::io::Result<()> {
+  let planning = planning.in_unit(config.energy_unit);
+  let summary = Summary::new(data, config, &planning);
+  match timezone {
+    Some(timezone) => {
+      let plans = planning.plans.into_iter().map(|plan| plan.with_timezone(&timezone)).collect();
+      write_output(&Out { planning: plans, summary }, format, writer)
+    }
+    None => write_output(&Out { planning: planning.plans, summary }, format, writer),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // the input files are written in a temporary directory, the directory is removed when dropped
+  fn input_files(consumption: &str, prices: &str, config: &str) -> (tempfile::TempDir, Args) {
+    let dir = tempfile::tempdir().unwrap();
+    let write = |name: &str, content: &str| {
+      let path = dir.path().join(name);
+      std::fs::File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+      path.to_str().unwrap().to_string()
+    };
+    let args = Args {
+      command: None,
+      consumption: write("consumption.json", consumption),
+      prices: write("prices.json", prices),
+      config: write("config.toml", config),
+      output_format: OutputFormat::Json,
+      output: None,
+      summary: false,
+      input: None,
+      start: None,
+      end: None,
+      timezone: None,
+      explain_units: false,
+      debug_dump: None,
+      echo_input: false,
+      max_switches: None,
+      energy_unit: None,
+      batch: false,
+      set: vec![],
+      quiet: false,
+      verbose: 0,
+      verbose_output: false,
+    };
+    (dir, args)
+  }
+
+  const CONSUMPTION: &str = r#"{ "forecasts": [
+    { "start": "2022-12-12T23:00:00Z", "end": "2022-12-12T23:15:00Z", "consumption_average_power_interval": 3.0 },
+    { "start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:30:00Z", "consumption_average_power_interval": 1.0 },
+    { "start": "2022-12-12T23:30:00Z", "end": "2022-12-12T23:45:00Z", "consumption_average_power_interval": 3.0 },
+    { "start": "2022-12-12T23:45:00Z", "end": "2022-12-13T00:00:00Z", "consumption_average_power_interval": 0.0 }
+  ] }"#;
+  const PRICES: &str = r#"{ "prices": [
+    { "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.3 }
+  ] }"#;
+  const CONFIG: &str = "max_consumption = 2.0
+battery_capacity = 0.5
+battery_max_charge = 1.5
+battery_initial_charge = 0.375
+battery_efficiency = 0.9
+battery_final_charge = 0.0
+";
+
+  #[test]
+  fn read_valid_data() {
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(data.len(), 4);
+    assert!(data.iter().all(|d| d.price == 0.3));
+    assert_eq!(config.max_consumption, 2.0);
+    assert_eq!(config.intervals_per_hour, 4.0);
+    assert!(data.iter().all(|d| d.max_power.is_none()));
+    assert!(data.iter().all(|d| d.allow_charge && d.allow_discharge));
+  }
+
+  #[test]
+  fn read_idle_interval() {
+    let consumption = CONSUMPTION.replace(
+      r#""consumption_average_power_interval": 1.0 }"#,
+      r#""consumption_average_power_interval": 1.0, "allow_charge": false, "allow_discharge": false }"#,
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let (data, _) = read_data(args).unwrap();
+    assert!(!data[1].allow_charge && !data[1].allow_discharge);
+    assert!(data[0].allow_charge && data[0].allow_discharge);
+  }
+
+  #[test]
+  fn read_interval_limit() {
+    let consumption = CONSUMPTION.replace(
+      r#""consumption_average_power_interval": 1.0 }"#,
+      r#""consumption_average_power_interval": 1.0, "max_power": 0.5 }"#,
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(data[1].max_power, Some(0.5));
+    assert_eq!(data[1].max_consumption(&config), 0.5);
+    assert_eq!(data[0].max_consumption(&config), 2.0);
+  }
+
+  #[test]
+  fn read_interval_capacity() {
+    let consumption = CONSUMPTION.replace(
+      r#""consumption_average_power_interval": 1.0 }"#,
+      r#""consumption_average_power_interval": 1.0, "capacity": 0.25 }"#,
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(data[1].battery_capacity(&config), 0.25);
+    assert_eq!(data[0].battery_capacity(&config), config.battery_capacity);
+    // the capacity of the record would be ignored
+    let segments = format!("{CONFIG}efficiency_segments = [[0.5, 1.0], [1.0, 0.9]]\n");
+    let (_dir, args) = input_files(&consumption, PRICES, &segments);
+    assert!(matches!(read_data(args), Err(DataError::Config(ConfigError::NotWithSegments("capacity")))));
+    let batteries = "max_consumption = 2.0\n[[batteries]]\ncapacity = 1.0\nmax_charge = 0.5\n";
+    let (_dir, args) = input_files(&consumption, PRICES, batteries);
+    assert!(matches!(read_data(args), Err(DataError::Config(ConfigError::NotWithBatteries("capacity")))));
+
+    let consumption = CONSUMPTION.replace(
+      r#""consumption_average_power_interval": 1.0 }"#,
+      r#""consumption_average_power_interval": 1.0, "capacity": -1.0 }"#,
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::InvalidRecord { index: 1, .. })));
+  }
+
+  #[test]
+  fn read_interval_generation() {
+    let consumption = CONSUMPTION.replace(
+      r#""consumption_average_power_interval": 1.0 }"#,
+      r#""consumption_average_power_interval": 1.0, "generation_power": 3.0 }"#,
+    );
+    let config = format!("{CONFIG}objective = \"max_self_consumption\"\n");
+    let (_dir, args) = input_files(&consumption, PRICES, &config);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(config.objective, Objective::MaxSelfConsumption);
+    assert_eq!(data[1].net_power(), -2.0);
+    assert_eq!(data[0].generation_power, 0.0);
+
+    // the surplus would be ignored by the other objectives
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::InvalidRecord { index: 1, .. })));
+  }
+
+  #[test]
+  fn read_forced_actions() {
+    let forced = |fields: &str| {
+      let consumption = CONSUMPTION.replace(
+        r#""consumption_average_power_interval": 1.0 }"#,
+        &format!(r#""consumption_average_power_interval": 1.0, {fields} }}"#),
+      );
+      let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+      read_data(args)
+    };
+    let (data, config) = forced(r#""forced_discharge": 0.5"#).unwrap();
+    assert_eq!(data[1].forced_discharge, Some(0.5));
+    // the grid gives the rest of the consumption
+    assert_eq!(data[1].max_consumption(&config), 0.5);
+    assert_eq!(data[1].connection_limit(&config), 2.0);
+
+    assert!(matches!(forced(r#""forced_discharge": 1.5"#), Err(DataError::InvalidRecord { index: 1, .. })));
+    assert!(matches!(forced(r#""forced_charge": -1.0"#), Err(DataError::InvalidRecord { index: 1, .. })));
+    let both = forced(r#""forced_charge": 0.5, "forced_discharge": 0.5"#);
+    assert!(matches!(both, Err(DataError::InvalidRecord { index: 1, .. })));
+  }
+
+  #[test]
+  fn peak_window_schedule() {
+    let consumption = CONSUMPTION.replace("3.0", "1.0");
+    // the window goes on past midnight
+    let config = format!("{CONFIG}\n[[schedule]]\nstart = \"23:30\"\nend = \"00:15\"\nmax_consumption = 0.5\n");
+    let (_dir, args) = input_files(&consumption, PRICES, &config);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(data.iter().map(|d| d.max_power).collect::<Vec<_>>(), [None, None, Some(0.5), Some(0.5)]);
+    assert!(!data[1].is_overload(&config));
+    assert!(data[2].is_overload(&config));
+    // the battery covers what the window does not allow
+    let planning = crate::calculation::calculation(&data, &config).unwrap();
+    assert!(planning.grid_power[2] <= 0.5 + 1e-9);
+    assert!(planning.plans[2].energy_from_battery_wh > 0.0);
+
+    let reserve =
+      Config { schedule: vec![ScheduleEntry { min_charge: Some(0.6), ..config.schedule[0].clone() }], ..config.clone() };
+    let above = ConfigError::AboveCapacity { field: "min_charge", value: 0.6, capacity: 0.5 };
+    assert_eq!(reserve.validate(), Err(ConfigError::Schedule { index: 0, error: Box::new(above) }));
+  }
+
+  #[test]
+  fn efficiencies() {
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let (_, config) = read_data(args).unwrap();
+    assert_eq!(config.charge_efficiency(), 0.9);
+    assert_eq!(config.discharge_efficiency(), 1.0);
+
+    let config_text = CONFIG.replace("battery_efficiency = 0.9", "charge_efficiency = 0.95\ndischarge_efficiency = 0.92");
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, &config_text);
+    let (_, config) = read_data(args).unwrap();
+    assert_eq!(config.charge_efficiency(), 0.95);
+    assert_eq!(config.discharge_efficiency(), 0.92);
+  }
+
+  #[test]
+  fn read_hourly_data() {
+    let consumption = r#"{ "forecasts": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "consumption_average_power_interval": 3.0 },
+      { "start": "2022-12-13T00:00:00Z", "end": "2022-12-13T01:00:00Z", "consumption_average_power_interval": 1.0 }
+    ] }"#;
+    let prices = r#"{ "prices": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.3 },
+      { "start": "2022-12-13T00:00:00Z", "end": "2022-12-13T01:00:00Z", "market_price_per_kwh": 0.2 }
+    ] }"#;
+    let (_dir, args) = input_files(consumption, prices, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(config.intervals_per_hour, 1.0);
+    assert_eq!(data.iter().map(|d| d.price).collect::<Vec<_>>(), vec![0.3, 0.2]);
+  }
+
+  #[test]
+  fn one_price_per_interval() {
+    let prices = r#"{ "prices": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-12T23:15:00Z", "market_price_per_kwh": 0.3 },
+      { "start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:30:00Z", "market_price_per_kwh": 0.2 },
+      { "start": "2022-12-12T23:30:00Z", "end": "2022-12-12T23:45:00Z", "market_price_per_kwh": 0.1 },
+      { "start": "2022-12-12T23:45:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.4 }
+    ] }"#;
+    let (_dir, args) = input_files(CONSUMPTION, prices, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(data.iter().map(|d| d.price).collect::<Vec<_>>(), vec![0.3, 0.2, 0.1, 0.4]);
+    // the charging in the second and the fourth quarter costs their own price, not the one of the hour
+    let (tableau, variables, _) = crate::build_tableau(&data, &config);
+    assert_eq!(tableau[tableau.len() - 2][..variables], [-0.2, -0.4]);
+  }
+
+  #[test]
+  fn sell_prices() {
+    let prices = r#"{ "prices": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.3, "sell_price_per_kwh": 0.1 }
+    ] }"#;
+    let (_dir, args) = input_files(CONSUMPTION, prices, CONFIG);
+    let (data, _) = read_data(args).unwrap();
+    assert!(data.iter().all(|d| d.sell_price == Some(0.1)));
+
+    // only the sell price of the records, the feed-in price is the same in every interval
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, &format!("{CONFIG}feed_in_price = 0.2\n"));
+    let (data, config) = read_data(args).unwrap();
+    assert!(data.iter().all(|d| d.sell_price.is_none() && d.sell_price(&config) == Some(0.2)));
+
+    let (_dir, args) = input_files(CONSUMPTION, &prices.replace("0.1", "0.4"), CONFIG);
+    assert!(
+      matches!(read_data(args), Err(DataError::InvalidRecord { index: 0, reason }) if reason.starts_with("sell price 0.4"))
+    );
+  }
+
+  #[test]
+  fn echo_joined_input() {
+    let consumption = r#"{ "forecasts": [
+      { "start": "2022-12-12T23:30:00Z", "end": "2022-12-12T23:45:00Z", "consumption_average_power_interval": 3.0 },
+      { "start": "2022-12-12T23:45:00Z", "end": "2022-12-13T00:00:00Z", "consumption_average_power_interval": 1.0 },
+      { "start": "2022-12-13T00:00:00Z", "end": "2022-12-13T00:15:00Z", "consumption_average_power_interval": 3.0 },
+      { "start": "2022-12-13T00:15:00Z", "end": "2022-12-13T00:30:00Z", "consumption_average_power_interval": 0.0 }
+    ] }"#;
+    let prices = r#"{ "prices": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.3 },
+      { "start": "2022-12-13T00:00:00Z", "end": "2022-12-13T01:00:00Z", "market_price_per_kwh": 0.2 }
+    ] }"#;
+    let (_dir, args) = input_files(consumption, prices, CONFIG);
+    let (data, _) = read_data(args).unwrap();
+    let mut output = Vec::new();
+    echo_input(&data, &mut output).unwrap();
+    let echoed: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let echoed = echoed.as_array().unwrap();
+    let prices: Vec<f64> = echoed.iter().map(|d| d["price"].as_f64().unwrap()).collect();
+    assert_eq!(prices, vec![0.3, 0.3, 0.2, 0.2]);
+    assert_eq!(echoed[2]["start"], "2022-12-13T00:00:00Z");
+    assert_eq!(echoed[2]["power"], 3.0);
+  }
+
+  #[test]
+  fn one_price_per_four_intervals() {
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(config.intervals_per_hour, 4.0);
+    assert_eq!(data.iter().map(|d| d.price).collect::<Vec<_>>(), vec![0.3; 4]);
+  }
+
+  #[test]
+  fn fewer_prices_than_needed() {
+    let prices = r#"{ "prices": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-12T23:20:00Z", "market_price_per_kwh": 0.3 },
+      { "start": "2022-12-12T23:20:00Z", "end": "2022-12-12T23:40:00Z", "market_price_per_kwh": 0.2 },
+      { "start": "2022-12-12T23:40:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.1 }
+    ] }"#;
+    let (_dir, args) = input_files(CONSUMPTION, prices, CONFIG);
+    // the second interval, 23:15 to 23:30, has two prices
+    assert!(matches!(read_data(args), Err(DataError::Unpriced { index: 1 })));
+  }
+
+  #[test]
+  fn more_prices_than_intervals() {
+    let consumption = r#"{ "forecasts": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "consumption_average_power_interval": 3.0 }
+    ] }"#;
+    let prices = r#"{ "prices": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-12T23:30:00Z", "market_price_per_kwh": 0.3 },
+      { "start": "2022-12-12T23:30:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.2 }
+    ] }"#;
+    let (_dir, args) = input_files(consumption, prices, CONFIG);
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::Unpriced { index: 0 }));
+    assert_eq!(error.to_string(), "Consumption interval 0 is not within a single price interval");
+  }
+
+  #[test]
+  fn prices_of_different_lengths() {
+    // two prices for four intervals, the first one lasts only one interval
+    let prices = r#"{ "prices": [
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-12T23:15:00Z", "market_price_per_kwh": 0.3 },
+      { "start": "2022-12-12T23:15:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.2 }
+    ] }"#;
+    let (_dir, args) = input_files(CONSUMPTION, prices, CONFIG);
+    let (data, _) = read_data(args).unwrap();
+    assert_eq!(data.iter().map(|d| d.price).collect::<Vec<_>>(), vec![0.3, 0.2, 0.2, 0.2]);
+
+    // a gap in the prices leaves an interval without a price
+    let prices = prices.replace(r#""start": "2022-12-12T23:15:00Z""#, r#""start": "2022-12-12T23:30:00Z""#);
+    let (_dir, args) = input_files(CONSUMPTION, &prices, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::Unpriced { index: 1 })));
+  }
+
+  #[test]
+  fn start_in_the_middle_of_a_price() {
+    // planning from 23:15, the first hourly price covers only three intervals
+    let consumption = r#"{ "forecasts": [
+      { "start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:30:00Z", "consumption_average_power_interval": 1.0 },
+      { "start": "2022-12-12T23:30:00Z", "end": "2022-12-12T23:45:00Z", "consumption_average_power_interval": 3.0 },
+      { "start": "2022-12-12T23:45:00Z", "end": "2022-12-13T00:00:00Z", "consumption_average_power_interval": 0.0 },
+      { "start": "2022-12-13T00:00:00Z", "end": "2022-12-13T00:15:00Z", "consumption_average_power_interval": 1.0 }
+    ] }"#;
+    let prices = r#"{ "prices": [
+      { "start": "2022-12-12T22:00:00Z", "end": "2022-12-12T23:00:00Z", "market_price_per_kwh": 0.4 },
+      { "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.3 },
+      { "start": "2022-12-13T00:00:00Z", "end": "2022-12-13T01:00:00Z", "market_price_per_kwh": 0.2 }
+    ] }"#;
+    let (_dir, args) = input_files(consumption, prices, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(config.intervals_per_hour, 4.0);
+    assert_eq!(data.iter().map(|d| d.price).collect::<Vec<_>>(), vec![0.3, 0.3, 0.3, 0.2]);
+    assert_eq!(data[0].start, "2022-12-12T23:15:00Z".parse::<DateTime<Utc>>().unwrap());
+  }
+
+  #[test]
+  fn malformed_json() {
+    let (_dir, args) = input_files("{ \"forecasts\": [", PRICES, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::Parse { format: "Json", .. })));
+  }
+
+  #[test]
+  fn malformed_toml() {
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, "max_consumption = ");
+    assert!(matches!(read_data(args), Err(DataError::Parse { format: "Toml", .. })));
+  }
+
+  #[test]
+  fn missing_file() {
+    let (_dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    args.prices = "does-not-exist.json".to_string();
+    assert!(matches!(read_data(args), Err(DataError::Read { .. })));
+  }
+
+  #[test]
+  fn empty_series() {
+    let (_dir, args) = input_files(r#"{ "forecasts": [] }"#, PRICES, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::NoConsumption)));
+    let (_dir, args) = input_files(CONSUMPTION, r#"{ "prices": [] }"#, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::NoPrices)));
+  }
+
+  #[test]
+  fn series_do_not_overlap() {
+    let prices = PRICES.replace("2022-12-13T00:00:00Z", "2022-12-12T23:45:00Z");
+    let (_dir, args) = input_files(CONSUMPTION, &prices, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::EndMismatch)));
+    let prices = PRICES.replace("2022-12-12T23:00:00Z", "2022-12-12T23:15:00Z");
+    let (_dir, args) = input_files(CONSUMPTION, &prices, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::StartMismatch)));
+  }
+
+  #[test]
+  fn missing_interval() {
+    let consumption = CONSUMPTION.replace(
+      r#"{ "start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:30:00Z", "consumption_average_power_interval": 1.0 },"#,
+      "",
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let result = read_data(args);
+    assert!(matches!(result, Err(DataError::NonContiguous { index: 1, gap }) if gap == TimeDelta::minutes(15)));
+  }
+
+  #[test]
+  fn negative_consumption() {
+    let consumption =
+      CONSUMPTION.replace(r#""consumption_average_power_interval": 1.0"#, r#""consumption_average_power_interval": -1.0"#);
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::InvalidRecord { index: 1, .. }));
+    assert_eq!(error.to_string(), "Invalid record 1: negative consumption -1");
+  }
+
+  #[test]
+  fn consumption_out_of_order() {
+    let consumption = CONSUMPTION.replace(
+      r#""start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:30:00Z""#,
+      r#""start": "2022-12-12T22:45:00Z", "end": "2022-12-12T23:00:00Z""#,
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::InvalidRecord { index: 1, .. }));
+    assert_eq!(error.to_string(), "Invalid record 1: consumption starts at 2022-12-12 22:45:00 UTC, not after the previous one");
+  }
+
+  #[test]
+  fn zero_length_and_duplicate_intervals() {
+    let consumption = CONSUMPTION.replace(
+      r#""start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:30:00Z""#,
+      r#""start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:15:00Z""#,
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::ZeroLength { series: "consumption", index: 1 }));
+    assert_eq!(error.to_string(), "The consumption record 1 ends where it starts");
+
+    // the second record given twice
+    let consumption = CONSUMPTION.replace(
+      r#""start": "2022-12-12T23:30:00Z", "end": "2022-12-12T23:45:00Z""#,
+      r#""start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:30:00Z""#,
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::Duplicate { series: "consumption", index: 2 })));
+
+    let prices = PRICES.replace(
+      r#"{ "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.3 }"#,
+      r#"{ "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.3 },
+    { "start": "2022-12-12T23:00:00Z", "end": "2022-12-13T00:00:00Z", "market_price_per_kwh": 0.4 }"#,
+    );
+    let (_dir, args) = input_files(CONSUMPTION, &prices, CONFIG);
+    assert!(matches!(read_data(args), Err(DataError::Duplicate { series: "price", index: 1 })));
+  }
+
+  #[test]
+  fn price_ending_before_its_start() {
+    let prices = PRICES.replace(r#""end": "2022-12-13T00:00:00Z""#, r#""end": "2022-12-12T22:00:00Z""#);
+    let (_dir, args) = input_files(CONSUMPTION, &prices, CONFIG);
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::InvalidRecord { index: 0, reason } if reason.starts_with("price ends")));
+  }
+
+  #[test]
+  fn not_finite_records() {
+    let (dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let path = dir.path().join("prices.csv");
+    std::fs::write(&path, "2022-12-12T23:00:00Z,2022-12-13T00:00:00Z,NaN\n").unwrap();
+    args.prices = path.to_str().unwrap().to_string();
+    let error = read_data(args).unwrap_err();
+    assert_eq!(error.to_string(), "Invalid record 0: price is not a finite number, it is NaN");
+
+    let config = format!("{CONFIG}min_grid_power = inf\n");
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, &config);
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::Config(ConfigError::NotFinite { field: "min_grid_power", .. })));
+  }
+
+  #[test]
+  fn overlapping_interval() {
+    let consumption = CONSUMPTION.replace(
+      r#""start": "2022-12-12T23:30:00Z", "end": "2022-12-12T23:45:00Z""#,
+      r#""start": "2022-12-12T23:25:00Z", "end": "2022-12-12T23:45:00Z""#,
+    );
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let result = read_data(args);
+    assert!(matches!(result, Err(DataError::NonContiguous { index: 2, gap }) if gap == TimeDelta::minutes(-5)));
+  }
+
+  #[test]
+  fn interval_of_different_duration() {
+    let consumption = CONSUMPTION
+      .replace(
+        r#""start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:30:00Z""#,
+        r#""start": "2022-12-12T23:15:00Z", "end": "2022-12-12T23:20:00Z""#,
+      )
+      .replace(r#""start": "2022-12-12T23:30:00Z""#, r#""start": "2022-12-12T23:20:00Z""#);
+    let (_dir, args) = input_files(&consumption, PRICES, CONFIG);
+    let result = read_data(args);
+    assert!(matches!(result, Err(DataError::IntervalDuration { index: 1, .. })));
+  }
+
+  #[test]
+  fn csv_with_header() {
+    let text = "start,end,consumption_average_power_interval
+\"2022-12-12T23:00:00Z\",\"2022-12-12T23:15:00Z\",4656000
+\"2022-12-12T23:15:00Z\",\"2022-12-12T23:30:00Z\",4528000
+";
+    let series: Vec<Consumption> = parse_csv(text, &CONSUMPTION_COLUMNS).unwrap();
+    assert_eq!(series.len(), 2);
+    assert_eq!(series[1].start, "2022-12-12T23:15:00Z".parse::<DateTime<Utc>>().unwrap());
+    assert_eq!(series[1].power, 4528000.0);
+  }
+
+  #[test]
+  fn csv_with_columns_in_different_order() {
+    let text = "market_price_per_kwh,end,start
+0.3057,2022-12-13T00:00:00Z,2022-12-12T23:00:00Z
+";
+    let series: Vec<Price> = parse_csv(text, &PRICE_COLUMNS).unwrap();
+    assert_eq!(series[0].value, 0.3057);
+    assert_eq!(series[0].end, "2022-12-13T00:00:00Z".parse::<DateTime<Utc>>().unwrap());
+  }
+
+  #[test]
+  fn csv_without_header() {
+    let text = "2022-12-12T23:00:00Z,2022-12-13T00:00:00Z,0.3057
+2022-12-13T00:00:00Z,2022-12-13T01:00:00Z,0.28752
+";
+    let series: Vec<Price> = parse_csv(text, &PRICE_COLUMNS).unwrap();
+    assert_eq!(series.len(), 2);
+    assert_eq!(series[1].value, 0.28752);
+  }
+
+  #[test]
+  fn csv_missing_column() {
+    let text = "start,end
+2022-12-12T23:00:00Z,2022-12-13T00:00:00Z
+";
+    let result = parse_csv::<Price>(text, &PRICE_COLUMNS);
+    assert_eq!(result.err(), Some("missing column market_price_per_kwh".to_string()));
+    let text = "2022-12-12T23:00:00Z,2022-12-13T00:00:00Z
+";
+    assert!(parse_csv::<Price>(text, &PRICE_COLUMNS).is_err());
+  }
+
+  #[test]
+  fn summary_of_the_savings() {
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 1000.0, price: 0.2, ..Default::default() },
+      Data { start, end, power: 3000.0, price: 0.4, ..Default::default() },
+    ];
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let (_, config) = read_data(args).unwrap();
+    let planning = Planning {
+      plans: vec![],
+      total_cost: 0.3,
+      equivalent_full_cycles: 0.0,
+      final_charge_shortfall_wh: 0.0,
+      total_charged_wh: 0.0,
+      total_discharged_wh: 0.0,
+      grid_power: vec![],
+    };
+    let summary = Summary::new(&data, &config, &planning);
+    // (1000 * 0.2 + 3000 * 0.4) / 4 intervals in an hour / 1000 Wh in a kWh
+    assert!((summary.baseline_cost - 0.35).abs() < 1e-9);
+    assert_eq!(summary.optimised_cost, 0.3);
+    assert!((summary.savings - 0.05).abs() < 1e-9);
+    assert_eq!(summary.currency, "EUR");
+    assert_eq!(summary.energy_unit, EnergyUnit::Wh);
+  }
+
+  #[test]
+  fn labels_in_the_output() {
+    let config = format!("{CONFIG}currency = \"RON\"\nenergy_unit = \"kWh\"\n");
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, &config);
+    let (data, config) = read_data(args).unwrap();
+    let planning = Planning {
+      plans: sample_output().planning,
+      total_cost: 0.8,
+      equivalent_full_cycles: 0.0,
+      final_charge_shortfall_wh: 0.0,
+      total_charged_wh: 0.0,
+      total_discharged_wh: 0.0,
+      grid_power: vec![],
+    };
+    let mut buffer = Vec::new();
+    let out = Out { summary: Summary::new(&data, &config, &planning), planning: planning.plans };
+    write_output(&out, OutputFormat::Json, &mut buffer).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+    assert_eq!(json["summary"]["currency"], "RON");
+    assert_eq!(json["summary"]["energy_unit"], "kWh");
+    // the energies are converted, a unit that can not be is rejected
+    assert!(toml::from_str::<Config>(&format!("{CONFIG}energy_unit = \"GJ\"\n")).is_err());
+  }
+
+  fn sample_output() -> Out {
+    let start: DateTime<Utc> = "2022-12-12T23:00:00Z".parse().unwrap();
+    let end: DateTime<Utc> = "2022-12-12T23:15:00Z".parse().unwrap();
+    let plan = |from: f64, to: f64| Plan {
+      start,
+      end,
+      energy_from_battery_wh: from,
+      energy_to_battery_wh: to,
+      energy_exported_wh: 0.0,
+      energy_imported_wh: to,
+      battery_charge_wh: 1.0,
+      consumption_power: None,
+      price: None,
+      batteries: vec![],
+      binding_constraints: vec![],
+    };
+    Out {
+      planning: vec![plan(0.0, 0.25), plan(0.5, 0.0)],
+      summary: Summary {
+        baseline_cost: 1.0,
+        optimised_cost: 0.8,
+        savings: 0.2,
+        equivalent_full_cycles: 1.0,
+        final_charge_shortfall_wh: 0.0,
+        total_charged_wh: 0.25,
+        total_discharged_wh: 0.5,
+        mode_switches: 1,
+        currency: "EUR".to_string(),
+        energy_unit: EnergyUnit::Wh,
+      },
+    }
+  }
+
+  #[test]
+  fn jsonl_output() {
+    let mut buffer = Vec::new();
+    write_output(&sample_output(), OutputFormat::Jsonl, &mut buffer).unwrap();
+    let text = String::from_utf8(buffer).unwrap();
+    let plans: Vec<Plan> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(plans.len(), 2);
+    assert_eq!(plans[0].energy_to_battery_wh, 0.25);
+    assert_eq!(plans[1].energy_from_battery_wh, 0.5);
+    assert_eq!(plans[1].start, sample_output().planning[1].start);
+  }
+
+  #[test]
+  fn csv_output() {
+    let mut buffer = Vec::new();
+    write_output(&sample_output(), OutputFormat::Csv, &mut buffer).unwrap();
+    let text = String::from_utf8(buffer).unwrap();
+    let mut lines = text.lines();
+    assert_eq!(
+      lines.next(),
+      Some("start,end,energy_from_battery_wh,energy_to_battery_wh,energy_exported_wh,energy_imported_wh,battery_charge_wh")
+    );
+    assert_eq!(lines.next(), Some("2022-12-12T23:00:00Z,2022-12-12T23:15:00Z,0.0,0.25,0.0,0.25,1.0"));
+
+    // and back
+    let plans: Vec<Plan> = csv::Reader::from_reader(text.as_bytes()).deserialize().map(|p| p.unwrap()).collect();
+    assert_eq!(plans.len(), 2);
+    assert_eq!(plans[1].energy_from_battery_wh, 0.5);
+    assert_eq!(plans[1].start, sample_output().planning[1].start);
+  }
+
+  #[test]
+  fn inputs_in_the_output() {
+    let mut out = sample_output();
+    out.planning[0].consumption_power = Some(1500.0);
+    out.planning[0].price = Some(0.3);
+    let mut buffer = Vec::new();
+    write_output(&out, OutputFormat::Json, &mut buffer).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+    assert_eq!(json["planning"][0]["consumption_power"], 1500.0);
+    assert_eq!(json["planning"][0]["price"], 0.3);
+    // left out, not written as null
+    assert!(json["planning"][1].get("price").is_none());
+
+    let planning = Planning {
+      plans: out.planning,
+      total_cost: 0.8,
+      equivalent_full_cycles: 0.0,
+      final_charge_shortfall_wh: 0.0,
+      total_charged_wh: 0.0,
+      total_discharged_wh: 0.0,
+      grid_power: vec![],
+    };
+    let plans = planning.without_inputs().plans;
+    assert!(plans.iter().all(|p| p.consumption_power.is_none() && p.price.is_none()));
+  }
+
+  #[test]
+  fn json_output() {
+    let mut buffer = Vec::new();
+    write_output(&sample_output(), OutputFormat::Json, &mut buffer).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+    assert_eq!(json["planning"][0]["start"], "2022-12-12T23:00:00Z");
+    assert_eq!(json["summary"]["savings"], 0.2);
+  }
+
+  #[test]
+  fn output_to_file() {
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    let planning = Planning {
+      plans: sample_output().planning,
+      total_cost: 0.8,
+      equivalent_full_cycles: 0.0,
+      final_charge_shortfall_wh: 0.0,
+      total_charged_wh: 0.0,
+      total_discharged_wh: 0.0,
+      grid_power: vec![],
+    };
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let path = out_dir.path().join("plan.json");
+    let file = std::fs::File::create(&path).unwrap();
+    print_output(&data, &config, planning, OutputFormat::Json, None, Box::new(file)).unwrap();
+
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(json["planning"].as_array().unwrap().len(), 2);
+    assert_eq!(json["summary"]["optimised_cost"], 0.8);
+  }
+
+  #[test]
+  fn read_combined_input() {
+    let forecasts = CONSUMPTION.trim().trim_start_matches('{').trim_end_matches('}');
+    let prices = PRICES.trim().trim_start_matches('{').trim_end_matches('}');
+    let config = r#""config": { "max_consumption": 2.0, "battery_capacity": 0.5, "battery_max_charge": 1.5,
+      "battery_initial_charge": 0.375, "battery_final_charge": 0.0 }"#;
+
+    // the configuration in the same file
+    let (dir, mut args) = input_files(CONSUMPTION, PRICES, "not toml");
+    let combined = dir.path().join("combined.json");
+    std::fs::write(&combined, format!("{{ {forecasts}, {prices}, {config} }}")).unwrap();
+    args.input = Some(combined.to_string_lossy().to_string());
+    args.consumption = "missing.json".to_string();
+    args.prices = "missing.json".to_string();
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(data.len(), 4);
+    assert!(data.iter().all(|d| d.price == 0.3));
+    assert_eq!(config.battery_initial_charge, 0.375);
+
+    // the configuration in its own file
+    let (dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let combined = dir.path().join("combined.json");
+    std::fs::write(&combined, format!("{{ {forecasts}, {prices} }}")).unwrap();
+    args.input = Some(combined.to_string_lossy().to_string());
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(data.len(), 4);
+    assert_eq!(config.battery_efficiency, 0.9);
+  }
+
+  #[test]
+  fn read_from_a_reader() {
+    let text = read_from(STDIN, CONSUMPTION.as_bytes()).unwrap();
+    let forecasts: Forecasts = parse(STDIN, &text, FileType::Json).unwrap();
+    assert_eq!(forecasts.forecasts.len(), 4);
+  }
+
+  #[test]
+  fn clip_to_a_window() {
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    let (full, _, _) = crate::tableau_creation::build_tableau(&data, &config);
+
+    let (_dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    args.start = Some("2022-12-12T23:15:00Z".parse().unwrap());
+    args.end = Some("2022-12-12T23:45:00Z".parse().unwrap());
+    let (data, config) = read_data(args).unwrap();
+    assert_eq!(data.iter().map(|d| d.power).collect::<Vec<_>>(), vec![1.0, 3.0]);
+    assert_eq!(data.iter().map(|d| d.price).collect::<Vec<_>>(), vec![0.3, 0.3]);
+    let (clipped, _, _) = crate::tableau_creation::build_tableau(&data, &config);
+    assert!(clipped.len() < full.len() && clipped[0].len() < full[0].len());
+
+    // only the start, up to the end of the forecasts
+    let (_dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    args.start = Some("2022-12-12T23:30:00Z".parse().unwrap());
+    assert_eq!(read_data(args).unwrap().0.len(), 2);
+
+    let (_dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    args.end = Some("2022-12-13T01:00:00Z".parse().unwrap());
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::Window { .. }));
+    assert_eq!(
+      error.to_string(),
+      "The window from 2022-12-12 23:00:00 UTC to 2022-12-13 01:00:00 UTC is not within the forecasts"
+    );
+  }
+
+  #[test]
+  fn window_across_daylight_saving() {
+    // Berlin goes from +01:00 to +02:00 at 01:00 UTC on the 26th of March 2023, the wall clock skips 02:00 to 03:00
+    let first: DateTime<Utc> = "2023-03-25T22:00:00Z".parse().unwrap();
+    let records: Vec<String> = (0..20)
+      .map(|i| {
+        let start = first + TimeDelta::minutes(15 * i);
+        let end = start + TimeDelta::minutes(15);
+        format!(
+          r#"{{ "start": "{}", "end": "{}", "consumption_average_power_interval": 1.0 }}"#,
+          start.to_rfc3339(),
+          end.to_rfc3339()
+        )
+      })
+      .collect();
+    let consumption = format!(r#"{{ "forecasts": [{}] }}"#, records.join(","));
+    let prices = r#"{ "prices": [
+      { "start": "2023-03-25T22:00:00Z", "end": "2023-03-26T03:00:00Z", "market_price_per_kwh": 0.3 }
+    ] }"#;
+    let timezone: Tz = "Europe/Berlin".parse().unwrap();
+
+    // four hours on the wall clock are three in UTC
+    let (_dir, mut args) = input_files(&consumption, prices, CONFIG);
+    args.start = Some("2023-03-26T00:00:00".parse().unwrap());
+    args.end = Some("2023-03-26T04:00:00".parse().unwrap());
+    args.timezone = Some(timezone);
+    let (data, _) = read_data(args).unwrap();
+    assert_eq!(data.len(), 12);
+    assert_eq!(data[0].start, "2023-03-25T23:00:00Z".parse::<DateTime<Utc>>().unwrap());
+    assert!(data.iter().all(|d| d.end - d.start == TimeDelta::minutes(15)));
+
+    // the output moves to the local time, the offset changes between two intervals of the same length
+    let plan = |start: DateTime<Utc>| Plan {
+      start,
+      end: start + TimeDelta::minutes(15),
+      energy_from_battery_wh: 0.0,
+      energy_to_battery_wh: 0.0,
+      energy_exported_wh: 0.0,
+      energy_imported_wh: 0.0,
+      battery_charge_wh: 0.0,
+      consumption_power: None,
+      price: None,
+      batteries: vec![],
+      binding_constraints: vec![],
+    };
+    let before = plan(data[7].start).with_timezone(&timezone);
+    let after = plan(data[8].start).with_timezone(&timezone);
+    assert_eq!(before.start.to_rfc3339(), "2023-03-26T01:45:00+01:00");
+    assert_eq!(before.end.to_rfc3339(), "2023-03-26T03:00:00+02:00");
+    assert_eq!(after.start.to_rfc3339(), "2023-03-26T03:00:00+02:00");
+    assert_eq!(after.end - after.start, TimeDelta::minutes(15));
+
+    // half past two does not exist that night
+    let (_dir, mut args) = input_files(&consumption, prices, CONFIG);
+    args.start = Some("2023-03-26T02:30:00".parse().unwrap());
+    args.timezone = Some(timezone);
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::LocalTime { .. }));
+    assert_eq!(
+      error.to_string(),
+      "The time 2023-03-26 02:30:00 does not exist or is ambiguous in Europe/Berlin, give it with its offset"
+    );
+  }
+
+  #[test]
+  fn read_gzip_files() {
+    let (dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let gzip = |name: &str, content: &str| {
+      let path = dir.path().join(name);
+      let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(&path).unwrap(), flate2::Compression::default());
+      encoder.write_all(content.as_bytes()).unwrap();
+      encoder.finish().unwrap();
+      path.to_str().unwrap().to_string()
+    };
+    args.consumption = gzip("consumption.json.gz", CONSUMPTION);
+    args.prices = gzip("prices.csv.GZ", "start,end,market_price_per_kwh\n2022-12-12T23:00:00Z,2022-12-13T00:00:00Z,0.3\n");
+    let (data, _) = read_data(args).unwrap();
+    assert_eq!(data.len(), 4);
+    assert_eq!(data[2].power, 3.0);
+    assert_eq!(data[2].price, 0.3);
+
+    // a file that is not compressed can not be read as gzip
+    let (dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let path = dir.path().join("consumption.json.gz");
+    std::fs::write(&path, CONSUMPTION).unwrap();
+    args.consumption = path.to_str().unwrap().to_string();
+    assert!(matches!(read_data(args), Err(DataError::Read { .. })));
+  }
+
+  #[test]
+  fn only_one_file_from_stdin() {
+    let (_dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    args.consumption = STDIN.to_string();
+    args.prices = STDIN.to_string();
+    assert!(matches!(read_data(args), Err(DataError::MultipleStdin)));
+  }
+
+  #[test]
+  fn plan_table() {
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let (data, config) = read_data(args).unwrap();
+    let plans = sample_output().planning;
+    let table = PlanTable { data: &data, config: &config, plans: &plans, timezone: None }.to_string();
+    let lines: Vec<&str> = table.lines().collect();
+    // header, one line for each plan and the total
+    assert_eq!(lines.len(), 4);
+    assert!(lines.iter().all(|l| l.len() == lines[0].len()));
+    assert!(lines[1].starts_with("2022-12-12T23:00:00Z"));
+    assert_eq!(
+      lines[1].split_whitespace().collect::<Vec<_>>(),
+      ["2022-12-12T23:00:00Z", "0.3000", "0.75", "0.25", "0.00", "1.00"]
+    );
+    assert_eq!(lines[3].split_whitespace().collect::<Vec<_>>(), ["total", "1.00", "0.25", "0.50"]);
+  }
+
+  fn valid_config() -> Config {
+    toml::from_str(CONFIG).unwrap()
+  }
+
+  #[test]
+  fn validate_config() {
+    assert_eq!(valid_config().validate(), Ok(()));
+
+    let check = |change: fn(&mut Config), expected: ConfigError| {
+      let mut config = valid_config();
+      change(&mut config);
+      assert_eq!(config.validate(), Err(expected));
+    };
+    check(|c| c.battery_capacity = -1.0, ConfigError::Negative { field: "battery_capacity", value: -1.0 });
+    check(|c| c.battery_max_charge = -0.5, ConfigError::Negative { field: "battery_max_charge", value: -0.5 });
+    check(|c| c.battery_max_discharge = Some(-0.5), ConfigError::Negative { field: "battery_max_discharge", value: -0.5 });
+    check(|c| c.battery_initial_charge = -0.1, ConfigError::Negative { field: "battery_initial_charge", value: -0.1 });
+    check(|c| c.battery_final_charge = -0.1, ConfigError::Negative { field: "battery_final_charge", value: -0.1 });
+    check(|c| c.battery_efficiency = 1.1, ConfigError::Efficiency { field: "battery_efficiency", value: 1.1 });
+    check(|c| c.charge_efficiency = Some(0.0), ConfigError::Efficiency { field: "charge_efficiency", value: 0.0 });
+    check(|c| c.discharge_efficiency = Some(2.0), ConfigError::Efficiency { field: "discharge_efficiency", value: 2.0 });
+    check(
+      |c| c.battery_initial_charge = 0.6,
+      ConfigError::AboveCapacity { field: "battery_initial_charge", value: 0.6, capacity: 0.5 },
+    );
+    check(
+      |c| c.battery_final_charge = 0.6,
+      ConfigError::AboveCapacity { field: "battery_final_charge", value: 0.6, capacity: 0.5 },
+    );
+    check(|c| c.min_charge = 0.6, ConfigError::AboveCapacity { field: "min_charge", value: 0.6, capacity: 0.5 });
+    check(|c| c.self_discharge_per_hour = 1.0, ConfigError::SelfDischarge(1.0));
+    check(|c| c.grid_price_markup = -0.2, ConfigError::Negative { field: "grid_price_markup", value: -0.2 });
+    check(|c| c.battery_capacity = f64::INFINITY, ConfigError::NotFinite { field: "battery_capacity", value: f64::INFINITY });
+    let config = Config { feed_in_price: Some(f64::NAN), ..valid_config() };
+    assert!(matches!(config.validate(), Err(ConfigError::NotFinite { field: "feed_in_price", value }) if value.is_nan()));
+    // several batteries, each checked on its own
+    let batteries: Config = toml::from_str(
+      "max_consumption = 2.0
+[[batteries]]
+capacity = 1.0
+max_charge = 0.5
+[[batteries]]
+capacity = 0.5
+max_charge = 0.5
+initial_charge = 0.6
+",
+    )
+    .unwrap();
+    assert_eq!(batteries.batteries[0].charge_efficiency, 1.0);
+    let error = batteries.validate().unwrap_err();
+    assert_eq!(error.to_string(), "battery 1: initial_charge of 0.6 is more than the battery capacity of 0.5");
+    let mut batteries = batteries;
+    batteries.batteries[1].initial_charge = 0.0;
+    assert_eq!(batteries.validate(), Ok(()));
+    batteries.feed_in_price = Some(0.1);
+    assert_eq!(batteries.validate(), Err(ConfigError::NotWithBatteries("feed_in_price")));
+    // the fields of the single battery that every battery has on its own
+    batteries.feed_in_price = None;
+    let check = |change: fn(&mut Config), field: &'static str| {
+      let mut config = batteries.clone();
+      change(&mut config);
+      assert_eq!(config.validate(), Err(ConfigError::NotWithBatteries(field)));
+    };
+    check(|c| c.battery_max_discharge = Some(1.0), "battery_max_discharge");
+    check(|c| c.battery_efficiency = 0.9, "battery_efficiency");
+    check(|c| c.charge_efficiency = Some(0.5), "charge_efficiency");
+    check(|c| c.discharge_efficiency = Some(0.9), "discharge_efficiency");
+    check(|c| c.cycle_cost_per_kwh = 5.0, "cycle_cost_per_kwh");
+  }
+
+  #[test]
+  fn config_round_trip() {
+    let config =
+      valid_config().with_overrides(&["feed_in_price=0.1".to_string(), "objective=minimise_peak".to_string()]).unwrap();
+    let json = serde_json::to_string(&config).unwrap();
+    assert_eq!(Config::from_json(&json).unwrap(), config);
+    let text = toml::to_string(&config).unwrap();
+    assert_eq!(toml::from_str::<Config>(&text).unwrap(), config);
+
+    // derived when reading the data, never written
+    let mut config = config;
+    config.intervals_per_hour = 12.0;
+    assert!(!serde_json::to_string(&config).unwrap().contains("intervals_per_hour"));
+    assert_eq!(Config::from_json(&json).unwrap().intervals_per_hour, 4.0);
+  }
+
+  #[test]
+  fn config_overrides() {
+    let overrides = ["battery_capacity=3.0", "objective = minimise_peak", "feed_in_price=0.1", "currency=RON"];
+    let config = valid_config().with_overrides(&overrides.map(String::from)).unwrap();
+    assert_eq!(config.battery_capacity, 3.0);
+    assert_eq!(config.objective, Objective::MinimisePeak);
+    assert_eq!(config.feed_in_price, Some(0.1));
+    assert_eq!(config.currency, "RON");
+    assert_eq!(config.battery_max_charge, valid_config().battery_max_charge);
+
+    let error = valid_config().with_overrides(&["capacity=3.0".to_string()]).unwrap_err();
+    assert!(matches!(&error, ConfigError::UnknownKey { key, valid } if key == "capacity" && valid.len() > 10));
+    assert!(error.to_string().contains("battery_capacity"));
+    let error = valid_config().with_overrides(&["battery_capacity".to_string()]).unwrap_err();
+    assert!(matches!(error, ConfigError::InvalidOverride { .. }));
+    let error = valid_config().with_overrides(&["battery_capacity=large".to_string()]).unwrap_err();
+    assert!(matches!(error, ConfigError::InvalidOverride { .. }));
+
+    // applied before the validation when reading
+    let (_dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    args.set = vec!["max_consumption=3.5".to_string()];
+    let (_, config) = read_data(args).unwrap();
+    assert_eq!(config.max_consumption, 3.5);
+    let (_dir, mut args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    args.set = vec!["battery_final_charge=100".to_string()];
+    assert!(matches!(read_data(args), Err(DataError::Config(ConfigError::AboveCapacity { .. }))));
+  }
+
+  #[test]
+  fn config_from_vars() {
+    let vars = |vars: &[(&str, &str)]| vars.iter().map(|(name, value)| (name.into(), value.into())).collect::<Vec<_>>();
+    let config = Config::from_vars(vars(&[
+      ("MAX_CONSUMPTION", "2.0"),
+      ("BATTERY_CAPACITY", "0.5"),
+      ("OBJECTIVE", "minimise_peak"),
+      ("FEED_IN_PRICE", " 0.1 "),
+      ("PATH", "/usr/bin"),
+      ("battery_max_charge", "1.5"),
+    ]))
+    .unwrap()
+    .unwrap();
+    assert_eq!(config.max_consumption, 2.0);
+    assert_eq!(config.battery_capacity, 0.5);
+    assert_eq!(config.objective, Objective::MinimisePeak);
+    assert_eq!(config.feed_in_price, Some(0.1));
+    // only the names in capitals
+    assert_eq!(config.battery_max_charge, 0.0);
+    assert_eq!(config.currency, "EUR");
+
+    assert!(Config::from_vars(vars(&[("PATH", "/usr/bin")])).is_none());
+    assert!(matches!(Config::from_vars(vars(&[("BATTERY_CAPACITY", "0.5")])), Some(Err(ConfigError::Environment(_)))));
+    assert!(matches!(Config::from_vars(vars(&[("MAX_CONSUMPTION", "large")])), Some(Err(ConfigError::Environment(_)))));
+    // a variable that is not Unicode is skipped, the others are still read
+    #[cfg(unix)]
+    {
+      use std::os::unix::ffi::OsStringExt;
+      let mut with_bad = vars(&[("MAX_CONSUMPTION", "2.0")]);
+      with_bad.push(("BAD".into(), OsString::from_vec(vec![0xff])));
+      with_bad.push((OsString::from_vec(vec![0xff]), "1.0".into()));
+      assert_eq!(Config::from_vars(with_bad).unwrap().unwrap().max_consumption, 2.0);
+    }
+  }
+
+  #[test]
+  fn environment_only_without_the_toml() {
+    let (dir, args) = input_files(CONSUMPTION, PRICES, CONFIG);
+    let environment = || vec![(OsString::from("MAX_CONSUMPTION"), OsString::from("9.0"))];
+    let config = read_config(&args.config, || -> Vec<(OsString, OsString)> { panic!("the toml file exists") }).unwrap();
+    assert_eq!(config.max_consumption, 2.0);
+    let missing = dir.path().join("missing.toml");
+    assert_eq!(read_config(missing.to_str().unwrap(), environment).unwrap().max_consumption, 9.0);
+    // neither of them
+    let error = read_config(missing.to_str().unwrap(), Vec::new).unwrap_err();
+    assert!(matches!(error, DataError::Read { .. }));
+  }
+
+  #[test]
+  fn invalid_config_is_rejected_when_reading() {
+    let config = CONFIG.replace("battery_final_charge = 0.0", "battery_final_charge = 1.0");
+    let (_dir, args) = input_files(CONSUMPTION, PRICES, &config);
+    let error = read_data(args).unwrap_err();
+    assert!(matches!(error, DataError::Config(ConfigError::AboveCapacity { field: "battery_final_charge", .. })));
+    assert_eq!(error.to_string(), "Invalid configuration: battery_final_charge of 1 is more than the battery capacity of 0.5");
+  }
 }
@@ -1,33 +1,256 @@
 /// Implementation of two phase minimisation simplex algorithm
 /// It starts from the tableau and solves the problem
 ///
-use std::fmt::{self, Display};
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Display};
+use std::ops::{Add, ControlFlow, Div, Mul, Neg, Sub};
+use std::time::Instant;
 
-use log::debug;
+use log::{debug, info, log_enabled, warn, Level};
 
-#[derive(Debug, PartialEq)]
-enum Phase {
+/// The phase of the two phase simplex, one looks for a feasible basis, two optimises the objective
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
   One,
   Two,
 }
 
+/// Reasons for the simplex failing to find an optimum
+#[derive(Debug, PartialEq)]
+pub enum SolveError {
+  /// phase one ended with the artificial variables still above zero, the constraints can not all be met
+  Infeasible,
+  /// the objective can decrease forever, no constraint limits the entering variable
+  Unbounded,
+  /// the pivoting did not finish within the allowed number of iterations, the limit that was reached
+  IterationLimit(usize),
+  /// the tableau holds NaN or an infinity at this row and column, the pivots would be wrong
+  NotFinite { row: usize, col: usize },
+  /// the progress callback asked to stop, after this many pivots of the phase
+  Cancelled(usize),
+  /// the rows or the columns of the tableau do not add up, nothing was solved
+  Structure(StructureError),
+}
+
+impl Display for SolveError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SolveError::Infeasible => write!(f, "No feasible solution found"),
+      SolveError::Unbounded => write!(f, "The objective function is unbounded"),
+      SolveError::IterationLimit(limit) => write!(f, "No solution found after {} iterations", limit),
+      SolveError::NotFinite { row, col } => write!(f, "The tableau is not finite in row {} column {}", row, col),
+      SolveError::Cancelled(iterations) => write!(f, "Solving cancelled after {} iterations", iterations),
+      SolveError::Structure(error) => write!(f, "{}", error),
+    }
+  }
+}
+
+impl std::error::Error for SolveError {}
+
+impl From<StructureError> for SolveError {
+  fn from(error: StructureError) -> Self {
+    SolveError::Structure(error)
+  }
+}
+
+/// A tableau that can not be solved, its rows or its columns do not add up
+#[derive(Debug, PartialEq)]
+pub enum StructureError {
+  /// fewer rows than the objective and the intermediate rows
+  TooFewRows(usize),
+  /// a row that is not as long as the first one
+  Ragged { row: usize, len: usize, expected: usize },
+  /// the variables, the artificials and the right hand side need more columns than the rows have
+  TooFewColumns { columns: usize, needed: usize },
+}
+
+impl Display for StructureError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StructureError::TooFewRows(rows) => {
+        write!(f, "The tableau has {} rows, it needs at least the objective and the intermediate rows", rows)
+      }
+      StructureError::Ragged { row, len, expected } => {
+        write!(f, "Row {} of the tableau has {} columns instead of {}", row, len, expected)
+      }
+      StructureError::TooFewColumns { columns, needed } => {
+        write!(f, "The tableau has {} columns, the variables, the artificials and the limits need {}", columns, needed)
+      }
+    }
+  }
+}
+
+impl std::error::Error for StructureError {}
+
+// values closer to zero than this are treated as zero
+pub(crate) const DEFAULT_TOLERANCE: f64 = 0.0001;
+
+/// The numbers of a tableau, `f64` by default. With the `exact` feature a `BigRational` tableau pivots without
+/// any rounding, slower but exact, for small problems and for checking what a test expects.
+pub trait Scalar:
+  Clone
+  + PartialEq
+  + PartialOrd
+  + Debug
+  + Display
+  + Add<Output = Self>
+  + Sub<Output = Self>
+  + Mul<Output = Self>
+  + Div<Output = Self>
+  + Neg<Output = Self>
+{
+  fn zero() -> Self;
+  fn one() -> Self;
+  /// what is treated as zero when none is given, no tolerance is needed without rounding
+  fn default_tolerance() -> Self;
+  fn abs(&self) -> Self;
+  fn is_finite(&self) -> bool;
+}
+
+impl Scalar for f64 {
+  fn zero() -> Self {
+    0.0
+  }
+
+  fn one() -> Self {
+    1.0
+  }
+
+  fn default_tolerance() -> Self {
+    DEFAULT_TOLERANCE
+  }
+
+  fn abs(&self) -> Self {
+    f64::abs(*self)
+  }
+
+  fn is_finite(&self) -> bool {
+    f64::is_finite(*self)
+  }
+}
+
+#[cfg(feature = "exact")]
+impl Scalar for num_rational::BigRational {
+  fn zero() -> Self {
+    num_traits::Zero::zero()
+  }
+
+  fn one() -> Self {
+    num_traits::One::one()
+  }
+
+  fn default_tolerance() -> Self {
+    Self::zero()
+  }
+
+  fn abs(&self) -> Self {
+    num_traits::Signed::abs(self)
+  }
+
+  fn is_finite(&self) -> bool {
+    true
+  }
+}
+
+// number of pivots that do not improve the objective before switching to Bland's rule
+pub(crate) const DEFAULT_STALL_LIMIT: usize = 50;
+
+// pivots allowed in one call of solve for every row and column of the tableau.
+// The simplex usually needs a few pivots per row, a small tableau that goes on much longer is stuck.
+pub(crate) const ITERATIONS_PER_DIMENSION: usize = 20;
+
+/// The outcome of a single `Matrix::step`
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+  /// pivoted on (row, column), the objective of the current phase did not get worse
+  Pivoted((usize, usize)),
+  /// nothing left to pivot in the current phase, phase one found a feasible solution or phase two the optimum
+  Optimal,
+  /// phase one can not pivot any more and the constraints are still not met
+  Infeasible,
+  /// the objective can decrease forever
+  Unbounded,
+}
+
+/// How the solver got to the optimum
+#[derive(Debug, PartialEq)]
+pub struct SolveStats {
+  /// pivots over both phases
+  pub iterations: usize,
+  pub phase_one_iterations: usize,
+  /// the most positive rule stalled or a basis came back, the tableau is degenerate
+  pub cycled: bool,
+}
+
+/// Where the solver is, passed to the callback of `solve_with_callback` after every pivot
+#[derive(Debug, PartialEq)]
+pub struct SolveProgress<T = f64> {
+  pub phase: Phase,
+  /// pivots made in this phase, the one just made included
+  pub iteration: usize,
+  /// the value in the objective row of the phase, the sum of the artificials in phase one
+  pub objective: T,
+}
+
+// the rows of a tableau, the constraints then the objective and the intermediate rows, and its columns,
+// the variables, the slacks, the artificials and the limits
+pub(crate) fn check_structure<T>(data: &[Vec<T>], variables: usize, artificials: usize) -> Result<(), StructureError> {
+  if data.len() < 2 {
+    return Err(StructureError::TooFewRows(data.len()));
+  }
+  let expected = data[0].len();
+  if let Some((row, r)) = data.iter().enumerate().find(|(_, r)| r.len() != expected) {
+    return Err(StructureError::Ragged { row, len: r.len(), expected });
+  }
+  let needed = variables + artificials + 1;
+  if expected < needed {
+    return Err(StructureError::TooFewColumns { columns: expected, needed });
+  }
+  Ok(())
+}
+
+/// Whether the objective is minimised or maximised. The tableau always minimises, a maximisation
+/// is solved as the minimisation of the negated costs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ObjectiveSense {
+  #[default]
+  Minimise,
+  Maximise,
+}
+
 // add equality
 #[derive(Debug, PartialEq)]
-pub struct Matrix {
+pub struct Matrix<T: Scalar = f64> {
   phase: Phase,
   variables: usize,
   artificials: usize,
-  pub data: Vec<Vec<f64>>,
+  // after this many pivots without improvement we assume the most positive rule cycles
+  stall_limit: usize,
+  // Bland's rule: pick the lowest eligible column and the lowest leaving variable on ratio ties
+  bland: bool,
+  tolerance: T,
+  // +1 for the slack of a <= row, -1 for the surplus of a >= row, in the order of the constraints
+  slack_signs: Vec<T>,
+  // pivots made by solve, over both phases
+  iterations: usize,
+  iteration_limit: usize,
+  // set when the pivoting stalled or went back to an earlier basis
+  cycled: bool,
+  // constraint rows left with only an artificial after phase one, copies of other constraints
+  redundant: Vec<usize>,
+  // the sense of the caller, only changes the sign of the objective value
+  sense: ObjectiveSense,
+  pub data: Vec<Vec<T>>,
 }
 
-impl Display for Matrix {
+impl<T: Scalar> Display for Matrix<T> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     let num_rows = self.data.len();
     let num_cols = if num_rows > 0 { self.data[0].len() } else { 0 };
     writeln!(f, "Matrix {}x{}:", num_rows, num_cols)?;
 
     for row in &self.data {
-      for &element in row {
+      for element in row {
         write!(f, "{:.2}\t", element)?;
       }
       writeln!(f)?;
@@ -36,31 +259,166 @@ impl Display for Matrix {
   }
 }
 
-impl Matrix {
-  pub fn new(data: Vec<Vec<f64>>, variables: usize, artificials: usize) -> Self {
-    Matrix { phase: Phase::One, data, variables, artificials }
+impl<T: Scalar> Matrix<T> {
+  pub fn new(data: Vec<Vec<T>>, variables: usize, artificials: usize) -> Self {
+    Self::with_tolerance(data, variables, artificials, T::default_tolerance())
+  }
+
+  /// Same as `new`, with an error instead of a panic when the tableau is malformed
+  pub fn try_new(data: Vec<Vec<T>>, variables: usize, artificials: usize) -> Result<Self, StructureError> {
+    check_structure(&data, variables, artificials)?;
+    Ok(Self::new(data, variables, artificials))
+  }
+
+  /// Values within `tolerance` of zero are treated as zero when choosing pivots and checking feasibility.
+  /// Large problems accumulate floating point errors and might need a looser tolerance.
+  /// Panics when the tableau is malformed, see `validate_structure`.
+  pub fn with_tolerance(data: Vec<Vec<T>>, variables: usize, artificials: usize, tolerance: T) -> Self {
+    // a ragged tableau would only fail deep in a pivot, with an index out of bounds
+    if let Err(error) = check_structure(&data, variables, artificials) {
+      panic!("{}", error);
+    }
+    // the pivoting will change the slack columns, remember their original sign for the dual values
+    let num_cols = data[0].len();
+    let num_constraints = data.len().saturating_sub(2);
+    let iteration_limit = ITERATIONS_PER_DIMENSION.saturating_mul(data.len() + num_cols);
+    let slack_signs = (variables..num_cols.saturating_sub(artificials + 1))
+      .map(|col| match data[..num_constraints].iter().map(|row| &row[col]).find(|&v| *v != T::zero()) {
+        Some(v) if *v < T::zero() => -T::one(),
+        _ => T::one(),
+      })
+      .collect();
+    Matrix {
+      phase: Phase::One,
+      data,
+      variables,
+      artificials,
+      stall_limit: DEFAULT_STALL_LIMIT,
+      bland: false,
+      tolerance,
+      slack_signs,
+      iterations: 0,
+      iteration_limit,
+      cycled: false,
+      redundant: Vec::new(),
+      sense: ObjectiveSense::Minimise,
+    }
+  }
+
+  /// Checks the rows have the same length, with room for the variables, the artificials and the limits,
+  /// and that the objective and the intermediate rows are there
+  pub fn validate_structure(&self) -> Result<(), StructureError> {
+    check_structure(&self.data, self.variables, self.artificials)
   }
 
-  pub fn get(&self, row: usize, col: usize) -> f64 {
-    self.data[row][col]
+  /// Warm start: builds the matrix and pivots the columns of `basis` into it,
+  /// usually the basis returned by `get_basis` for a previous, slightly different problem.
+  /// If the basis is singular or not feasible for this data the matrix is left as a cold start.
+  pub fn from_basis(data: Vec<Vec<T>>, variables: usize, artificials: usize, basis: Vec<usize>) -> Self {
+    let mut matrix = Matrix::new(data.clone(), variables, artificials);
+    if matrix.seed_basis(&basis) {
+      matrix
+    } else {
+      debug!("Basis {:?} can not be used, starting from scratch", basis);
+      Matrix::new(data, variables, artificials)
+    }
   }
 
-  pub fn set(&mut self, row: usize, col: usize, val: f64) {
+  fn seed_basis(&mut self, basis: &[usize]) -> bool {
+    let num_rows = self.data.len() - 2;
+    let num_cols = self.data[0].len();
+    if basis.len() != num_rows {
+      return false;
+    }
+    // the rows get mixed while pivoting, each basic column goes into the unused row with the largest entry
+    let mut used = vec![false; num_rows];
+    for &col in basis {
+      if col >= num_cols - 1 {
+        return false;
+      }
+      let row = (0..num_rows)
+        .filter(|&row| !used[row] && self.get(row, col).abs() > self.tolerance)
+        .max_by(|&a, &b| self.get(a, col).abs().partial_cmp(&self.get(b, col).abs()).unwrap_or(std::cmp::Ordering::Equal));
+      let Some(row) = row else {
+        return false;
+      };
+      used[row] = true;
+      self.pivot((row, col));
+    }
+    (0..num_rows).all(|row| self.get(row, num_cols - 1) >= -self.tolerance.clone())
+  }
+
+  /// Sets how many consecutive pivots without improving the objective are tolerated
+  /// before switching to Bland's rule, which guarantees termination on degenerate tableaus.
+  pub fn set_stall_limit(&mut self, stall_limit: usize) {
+    self.stall_limit = stall_limit;
+  }
+
+  /// Sets how many pivots one call of `solve` can make before giving up with `SolveError::IterationLimit`.
+  /// By default it is 20 times the rows and the columns of the tableau.
+  pub fn set_iteration_limit(&mut self, iteration_limit: usize) {
+    self.iteration_limit = iteration_limit;
+  }
+
+  /// With `ObjectiveSense::Maximise` the tableau holds the negated costs, as `TableauBuilder::sense` makes it,
+  /// and `get_objective_value` returns the maximum instead of the minimum it solved for.
+  pub fn set_sense(&mut self, sense: ObjectiveSense) {
+    self.sense = sense;
+  }
+
+  pub fn get(&self, row: usize, col: usize) -> T {
+    self.data[row][col].clone()
+  }
+
+  pub fn set(&mut self, row: usize, col: usize, val: T) {
     self.data[row][col] = val;
   }
 
   pub fn phase_two(&mut self) {
     debug!("Switching to phase two");
+    self.drive_out_artificials();
     self.phase = Phase::Two;
   }
 
-  fn find_most_positive_in_bottom_row(&self) -> Option<(usize, f64)> {
+  // an artificial can end phase one in the basis at zero, the pivots of phase two could then raise it
+  // and break its constraint. It is swapped for any other variable of its row, with a zero limit
+  // the pivot keeps the solution feasible even on a negative value
+  fn drive_out_artificials(&mut self) {
+    let num_cols = self.data[0].len();
+    let first_artificial = num_cols - 1 - self.artificials;
+    for row in 0..self.data.len() - 2 {
+      if self.basic_variable(row).is_some_and(|col| col >= first_artificial) {
+        match (0..first_artificial).find(|&col| self.get(row, col).abs() > self.tolerance) {
+          Some(col) => {
+            debug!("Artificial still basic in row {row}, replacing it with column {col}");
+            self.pivot((row, col));
+          }
+          // a row without any other variable is redundant, it can not change any more
+          None => {
+            debug!("Row {row} is redundant");
+            self.redundant.push(row);
+          }
+        }
+      }
+    }
+  }
+
+  // the value of the objective we are currently minimising
+  fn current_objective(&self) -> T {
+    let last_row = match self.phase {
+      Phase::One => &self.data[self.data.len() - 1],
+      Phase::Two => &self.data[self.data.len() - 2],
+    };
+    last_row[last_row.len() - 1].clone()
+  }
+
+  fn find_most_positive_in_bottom_row(&self) -> Option<(usize, T)> {
     let last_row = match self.phase {
       Phase::One => &self.data[self.data.len() - 1],
       Phase::Two => &self.data[self.data.len() - 2],
     };
     debug!("last row full {:?}", last_row);
-    let mut found = None;
+    let mut found: Option<(usize, T)> = None;
     let limit = match self.phase {
       Phase::One => 1,
       Phase::Two => self.artificials + 1,
@@ -68,11 +426,16 @@ impl Matrix {
     let last_row = &last_row[..last_row.len() - limit];
     debug!("last row: {:?}", last_row);
 
-    for (col, &x) in last_row.iter().enumerate() {
-      if x > 0.0 {
+    if self.bland {
+      return last_row.iter().enumerate().find(|(_, x)| **x > self.tolerance).map(|(col, x)| (col, x.clone()));
+    }
+    // entries within the tolerance of each other are a tie and the lowest column wins, a rounding in the last
+    // digits does not decide which of two equally good columns enters
+    for (col, x) in last_row.iter().enumerate() {
+      if *x > self.tolerance {
         found = match found {
-          Some((_, val)) if x > val => Some((col, x)),
-          None => Some((col, x)),
+          Some((_, val)) if *x > val.clone() + self.tolerance.clone() => Some((col, x.clone())),
+          None => Some((col, x.clone())),
           _ => found,
         };
       }
@@ -80,27 +443,36 @@ impl Matrix {
     found
   }
 
-  fn find_pivot(&self) -> Option<(usize, usize)> {
-    let (col, _) = self.find_most_positive_in_bottom_row()?;
-    let mut min_ratio = None;
-    let mut pivot = None;
-    let limit = match self.phase {
-      Phase::One => 2,
-      Phase::Two => 1,
+  fn find_pivot(&self) -> Result<Option<(usize, usize)>, SolveError> {
+    let Some((col, _)) = self.find_most_positive_in_bottom_row() else {
+      return Ok(None);
     };
+    let mut min_ratio: Option<T> = None;
+    let mut pivot = None;
     let num_rows = self.data.len();
     let num_cols = self.data[0].len();
-    for row in 0..num_rows - limit {
+    // the objective and the intermediate objective rows are never pivot rows
+    for row in 0..num_rows - 2 {
       let a = self.get(row, col);
       let b = self.get(row, num_cols - 1);
-      // pivot must be positive
-      if a > 0.0 && b >= 0.0 {
-        let ratio = b / a;
-        match min_ratio {
-          Some(val) if ratio < val => {
+      // pivot must be positive, a limit a rounding below zero is a degenerate row at zero
+      if a > self.tolerance && b >= -self.tolerance.clone() {
+        let ratio = if b > T::zero() { b / a } else { T::zero() };
+        // only equal ratios are a tie, the lowest row wins. A ratio a bit above the minimum would leave
+        // the row of the minimum below zero after the pivot
+        match &min_ratio {
+          Some(val) if ratio < *val => {
             min_ratio = Some(ratio);
             pivot = Some((row, col));
           }
+          // Bland's rule breaks ties by the lowest index of the variable leaving the basis
+          Some(val) if ratio == *val && self.bland => {
+            if let Some((current, _)) = pivot {
+              if self.basic_variable(row) < self.basic_variable(current) {
+                pivot = Some((row, col));
+              }
+            }
+          }
           None => {
             min_ratio = Some(ratio);
             pivot = Some((row, col));
@@ -110,7 +482,34 @@ impl Matrix {
       }
     }
     debug!("pivot {:?}", pivot);
-    pivot
+    // the column would improve the objective but nothing stops it from growing
+    if pivot.is_none() && (0..num_rows - 2).all(|row| self.get(row, col) <= self.tolerance) {
+      return Err(SolveError::Unbounded);
+    }
+    Ok(pivot)
+  }
+
+  // the column of the variable that is basic in this row, the one with a 1 here and 0 in every other row
+  fn basic_variable(&self, row: usize) -> Option<usize> {
+    let num_cols = self.data[0].len();
+    (0..num_cols - 1).find(|&col| self.basic_row(col) == Some(row))
+  }
+
+  // the constraint row where this column is basic. The column has to hold a 1 in that row and 0 in the others,
+  // the objective row included, all within the tolerance. The intermediate objective is left out,
+  // phase two does not update it.
+  fn basic_row(&self, col: usize) -> Option<usize> {
+    let num_constraints = self.data.len() - 2;
+    let mut found = None;
+    for row in 0..=num_constraints {
+      let value = self.get(row, col);
+      if row < num_constraints && (value.clone() - T::one()).abs() <= self.tolerance && found.is_none() {
+        found = Some(row);
+      } else if value.abs() > self.tolerance {
+        return None;
+      }
+    }
+    found
   }
 
   fn pivot(&mut self, pivot: (usize, usize)) {
@@ -125,73 +524,211 @@ impl Matrix {
 
     // we need to make the pivot value 1, we divide the row by the pivot value
     for col in 0..num_cols {
-      self.set(pivot_row, col, self.get(pivot_row, col) / pivot_val);
+      self.set(pivot_row, col, self.get(pivot_row, col) / pivot_val.clone());
     }
+    // the tableau is mostly zeros, the columns that are zero in the pivot row do not change
+    let pivot_cols: Vec<usize> = (0..num_cols).filter(|&col| self.data[pivot_row][col] != T::zero()).collect();
     // now we need to make the other values in the column 0
     for row in 0..num_rows {
-      if row != pivot_row {
-        // our pivot value is 1 so the ratio is the very value we are trying to make 0
-        let ratio = self.get(row, pivot_col);
-        for col in 0..num_cols {
-          self.set(row, col, self.get(row, col) - ratio * self.get(pivot_row, col));
+      // our pivot value is 1 so the ratio is the very value we are trying to make 0
+      let ratio = self.get(row, pivot_col);
+      if row != pivot_row && ratio != T::zero() {
+        for &col in &pivot_cols {
+          self.set(row, col, self.get(row, col) - ratio.clone() * self.get(pivot_row, col));
         }
       }
     }
     debug!("{self}");
   }
 
-  pub fn solve(&mut self) -> Result<(), &'static str> {
-    // the algorithm is not guaranteed to terminate, we limit the number of iterations
-    for _ in 0..1000000 {
-      let pivot = self.find_pivot();
-      match pivot {
-        Some(p) => self.pivot(p),
-        None => match self.check_if_we_have_a_solution() {
-          true => return Ok(()),
-          false => return Err("No feasible solution found"),
-        },
+  /// Pivots until the objective of the current phase can not improve, returns the number of pivots made
+  pub fn solve(&mut self) -> Result<usize, SolveError> {
+    self.solve_with_callback(|_| ControlFlow::Continue(()))
+  }
+
+  /// Like `solve`, calling `callback` after every pivot. Returning `ControlFlow::Break` from it
+  /// stops the solving with `SolveError::Cancelled`, the tableau is left as it was after the pivot.
+  pub fn solve_with_callback<F: FnMut(&SolveProgress<T>) -> ControlFlow<()>>(
+    &mut self,
+    mut callback: F,
+  ) -> Result<usize, SolveError> {
+    // the most positive rule is not guaranteed to terminate, we switch to Bland's rule when stalling
+    // and still limit the number of iterations
+    // NaN compares false with everything and would be silently skipped when choosing the pivots
+    if let Some((row, col)) = self.find_not_finite() {
+      return Err(SolveError::NotFinite { row, col });
+    }
+    self.bland = false;
+    let mut stalled = 0;
+    // only timed when the timing is logged
+    let started = log_enabled!(Level::Info).then(Instant::now);
+    // close to the limit we look for a basis we have already been through
+    let watch_from = self.iteration_limit - self.iteration_limit / 10;
+    // thousands of bases for a day of quarters, a set keeps the lookups cheap
+    let mut seen_bases: HashSet<Vec<usize>> = HashSet::new();
+    for iteration in 0..self.iteration_limit {
+      if iteration >= watch_from {
+        let basis = self.get_basis();
+        if !self.cycled && seen_bases.contains(&basis) {
+          warn!("Basis {:?} repeats after {} iterations, the pivoting cycles", basis, iteration);
+          self.cycled = true;
+        }
+        seen_bases.insert(basis);
+      }
+      let before = self.current_objective();
+      let solved = match self.step() {
+        StepResult::Pivoted(_) => {
+          let progress = SolveProgress { phase: self.phase, iteration: iteration + 1, objective: self.current_objective() };
+          if callback(&progress).is_break() {
+            return Err(SolveError::Cancelled(iteration + 1));
+          }
+          if progress.objective < before {
+            stalled = 0;
+          } else {
+            stalled += 1;
+            if !self.bland && stalled >= self.stall_limit {
+              debug!("No improvement after {stalled} pivots, switching to Bland's rule");
+              self.bland = true;
+              self.cycled = true;
+            }
+          }
+          continue;
+        }
+        StepResult::Unbounded => return Err(SolveError::Unbounded),
+        StepResult::Optimal => true,
+        StepResult::Infeasible => false,
+      };
+      if let Some(started) = started {
+        info!(
+          "Phase {:?} {} after {} pivots in {:?}, objective {}",
+          self.phase,
+          if solved { "solved" } else { "infeasible" },
+          iteration,
+          started.elapsed(),
+          self.current_objective()
+        );
+      }
+      match solved {
+        true => {
+          debug!("Solved after {} iterations", self.iterations);
+          return Ok(iteration);
+        }
+        false => return Err(SolveError::Infeasible),
+      }
+    }
+    Err(SolveError::IterationLimit(self.iteration_limit))
+  }
+
+  /// Makes one pivot of the current phase, or tells why there is none to make.
+  /// Calling it until it stops returning `Pivoted` is what `solve` does, without the protection against cycling.
+  pub fn step(&mut self) -> StepResult {
+    match self.find_pivot() {
+      Ok(Some(pivot)) => {
+        self.pivot(pivot);
+        self.iterations += 1;
+        StepResult::Pivoted(pivot)
       }
+      Ok(None) if self.check_if_we_have_a_solution() => StepResult::Optimal,
+      Ok(None) => StepResult::Infeasible,
+      Err(_) => StepResult::Unbounded,
     }
-    Err("No solution found, iterated too many times")
   }
 
-  pub fn get_solution(&self) -> Vec<f64> {
-    let mut solution = vec![0.0; self.variables];
-    // the cleared columns get the solution from the last column
-    // the other columns get 0
-    let num_rows = self.data.len();
+  /// The current phase is finished, no pivot improves its objective and in phase one the constraints are met
+  pub fn is_optimal(&self) -> bool {
+    matches!(self.find_pivot(), Ok(None)) && self.check_if_we_have_a_solution()
+  }
+
+  /// Runs both phases and reports how many pivots they took
+  pub fn solve_with_stats(&mut self) -> Result<SolveStats, SolveError> {
+    let phase_one_iterations = self.solve()?;
+    self.phase_two();
+    let phase_two_iterations = self.solve()?;
+    Ok(SolveStats { iterations: phase_one_iterations + phase_two_iterations, phase_one_iterations, cycled: self.cycled })
+  }
+
+  // the first NaN or infinity, row by row
+  fn find_not_finite(&self) -> Option<(usize, usize)> {
+    self.data.iter().enumerate().find_map(|(row, r)| r.iter().position(|v| !v.is_finite()).map(|col| (row, col)))
+  }
+
+  /// The artificial columns still above zero with their values. After phase one failed they point
+  /// to the constraints that could not be met.
+  pub fn remaining_artificials(&self) -> Vec<(usize, T)> {
     let num_cols = self.data[0].len();
-    #[allow(clippy::needless_range_loop)]
-    for col in 0..self.variables {
-      // the column should contain only one 1, the rest should be 0
-      let mut num_zeroes = 0;
-      let mut num_ones = 0;
-      let mut val = 0.0;
-      for row in 0..num_rows {
-        if self.get(row, col) == 0.0 {
-          num_zeroes += 1;
-        } else {
-          num_ones += 1;
-          val = self.get(row, num_cols - 1);
+    (num_cols - 1 - self.artificials..num_cols - 1)
+      .filter_map(|col| self.basic_row(col).map(|row| (col, self.get(row, num_cols - 1))))
+      .filter(|(_, value)| *value > self.tolerance)
+      .collect()
+  }
+
+  /// The constraint rows found redundant when switching to phase two, their constraint follows from the others
+  pub fn redundant_rows(&self) -> &[usize] {
+    &self.redundant
+  }
+
+  /// The basic variable of every constraint row, in row order. Can be used to warm start
+  /// a similar problem with `from_basis`.
+  pub fn get_basis(&self) -> Vec<usize> {
+    (0..self.data.len() - 2).filter_map(|row| self.basic_variable(row)).collect()
+  }
+
+  pub fn get_solution(&self) -> Vec<T> {
+    self.basic_values(0..self.variables)
+  }
+
+  /// The value of every slack/surplus variable at the optimum, in the order of `get_dual_values`.
+  /// How far a constraint is from its limit, zero when it is binding. The equalities have none.
+  pub fn get_slacks(&self) -> Vec<T> {
+    self.basic_values(self.variables..self.variables + self.slack_signs.len())
+  }
+
+  fn basic_values(&self, columns: std::ops::Range<usize>) -> Vec<T> {
+    // the basic columns get the solution from the last column, the other columns get 0.
+    // Two columns can look basic in the same row when they are equal, only the first one is
+    let num_cols = self.data[0].len();
+    let mut taken = vec![false; self.data.len()];
+    columns
+      .map(|col| match self.basic_row(col) {
+        Some(row) if !taken[row] => {
+          taken[row] = true;
+          self.get(row, num_cols - 1)
         }
-      }
-      if num_zeroes == num_rows - 1 && num_ones == 1 {
-        solution[col] = val;
-      } else {
-        solution[col] = 0.0;
-      }
+        _ => T::zero(),
+      })
+      .collect()
+  }
+
+  /// Value of the objective function at the optimum.
+  /// The objective row holds the negated costs, -c, so after pivoting its last column
+  /// holds c * x directly and no sign change is needed. A maximisation solved the negated costs,
+  /// the value is turned back to the sense of the caller.
+  pub fn get_objective_value(&self) -> T {
+    let objective_row = &self.data[self.data.len() - 2];
+    let value = objective_row[objective_row.len() - 1].clone();
+    match self.sense {
+      ObjectiveSense::Minimise => value,
+      ObjectiveSense::Maximise => -value,
     }
+  }
 
-    solution
+  /// The shadow prices of the constraints, how much the objective changes when the right hand side
+  /// of a constraint increases by one unit. There is one value per slack/surplus column, in the order
+  /// of the constraint rows. For the tableau made by `build_tableau` these are the max charge
+  /// constraints, then the battery capacity, the discharge and finally the final charge constraint.
+  /// The right hand side is the one in the tableau, after the rows with negative limits got negated.
+  pub fn get_dual_values(&self) -> Vec<T> {
+    let objective_row = &self.data[self.data.len() - 2];
+    // the objective row holds the dual value multiplied by the original slack coefficient
+    self.slack_signs.iter().enumerate().map(|(i, sign)| objective_row[self.variables + i].clone() * sign.clone()).collect()
   }
 
   pub fn check_if_we_have_a_solution(&self) -> bool {
     match self.phase {
       Phase::One => {
         if let Some(last_row) = self.data.last() {
-          if let Some(&last) = last_row.last() {
-            let tolerance = 0.0001;
-            last.abs() < tolerance
+          if let Some(last) = last_row.last() {
+            last.abs() <= self.tolerance
           } else {
             false
           }
@@ -204,12 +741,163 @@ impl Matrix {
   }
 }
 
+impl Matrix {
+  /// The state of the matrix, for debugging or following the algorithm step by step
+  pub fn to_debug_json(&self) -> serde_json::Value {
+    serde_json::json!({
+      "data": self.data,
+      "variables": self.variables,
+      "artificials": self.artificials,
+      "phase": format!("{:?}", self.phase),
+      "basis": self.get_basis(),
+      "solution": self.get_solution(),
+      "objective_value": self.get_objective_value(),
+    })
+  }
+}
+
+/// Runs both phases on a tableau made by hand or by `TableauBuilder`, for a linear program of its own without
+/// any of the files. Returns the values of the variables and the objective, `SolveError::Structure` when the
+/// tableau is malformed.
+pub fn solve_tableau<T: Scalar>(data: Vec<Vec<T>>, variables: usize, artificials: usize) -> Result<(Vec<T>, T), SolveError> {
+  let mut matrix = Matrix::try_new(data, variables, artificials)?;
+  matrix.solve_with_stats()?;
+  Ok((matrix.get_solution(), matrix.get_objective_value()))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Relation {
+  LessOrEqual,
+  GreaterOrEqual,
+  Equal,
+}
+
+/// Assembles the tableau of a minimisation problem from its constraints, placing the slack,
+/// surplus and artificial columns and building the intermediate objective of phase one.
+/// `build` returns the data, the number of variables and of artificials, ready for `Matrix::new`.
+pub struct TableauBuilder {
+  variables: usize,
+  constraints: Vec<(Vec<f64>, Relation, f64)>,
+  objective: Vec<f64>,
+  sense: ObjectiveSense,
+}
+
+impl TableauBuilder {
+  pub fn new(variables: usize) -> Self {
+    TableauBuilder { variables, constraints: Vec::new(), objective: vec![0.0; variables], sense: ObjectiveSense::Minimise }
+  }
+
+  /// coeffs * x <= rhs
+  pub fn add_le(&mut self, coeffs: &[f64], rhs: f64) -> &mut Self {
+    self.add(coeffs, Relation::LessOrEqual, rhs)
+  }
+
+  /// coeffs * x >= rhs
+  pub fn add_ge(&mut self, coeffs: &[f64], rhs: f64) -> &mut Self {
+    self.add(coeffs, Relation::GreaterOrEqual, rhs)
+  }
+
+  /// coeffs * x = rhs, an equality has an artificial but no slack column
+  pub fn add_eq(&mut self, coeffs: &[f64], rhs: f64) -> &mut Self {
+    self.add(coeffs, Relation::Equal, rhs)
+  }
+
+  /// The costs, the tableau minimises coeffs * x, or maximises it with `ObjectiveSense::Maximise`
+  pub fn objective(&mut self, coeffs: &[f64]) -> &mut Self {
+    assert_eq!(coeffs.len(), self.variables, "one cost for every variable");
+    self.objective = coeffs.to_vec();
+    self
+  }
+
+  /// A maximisation is built with the negated costs, the matrix needs the same sense with `Matrix::set_sense`
+  /// to report the maximum, `matrix` does both
+  pub fn sense(&mut self, sense: ObjectiveSense) -> &mut Self {
+    self.sense = sense;
+    self
+  }
+
+  /// The built tableau as a matrix that knows the sense of its objective
+  pub fn matrix(&self) -> Matrix {
+    let (data, variables, artificials) = self.build();
+    let mut matrix = Matrix::new(data, variables, artificials);
+    matrix.set_sense(self.sense);
+    matrix
+  }
+
+  fn add(&mut self, coeffs: &[f64], relation: Relation, rhs: f64) -> &mut Self {
+    assert_eq!(coeffs.len(), self.variables, "one coefficient for every variable");
+    self.constraints.push((coeffs.to_vec(), relation, rhs));
+    self
+  }
+
+  pub fn build(&self) -> (Vec<Vec<f64>>, usize, usize) {
+    let num_s = self.constraints.iter().filter(|(_, relation, _)| *relation != Relation::Equal).count();
+    // a negative limit turns the row around, only the rows that are >= after that need an artificial
+    let needs_artificial = |&(_, relation, rhs): &(Vec<f64>, Relation, f64)| match relation {
+      Relation::LessOrEqual => rhs < 0.0,
+      Relation::GreaterOrEqual => rhs >= 0.0,
+      Relation::Equal => true,
+    };
+    let num_a = self.constraints.iter().filter(|c| needs_artificial(c)).count();
+    let cols = self.variables + num_s + num_a + 1;
+
+    let mut result: Vec<Vec<f64>> = Vec::with_capacity(self.constraints.len() + 2);
+    let mut intermediate = vec![0.0; cols];
+    let mut s_offset = self.variables;
+    let mut a_offset = self.variables + num_s;
+    for constraint in &self.constraints {
+      let (coeffs, relation, rhs) = constraint;
+      let mut equation = vec![0.0; cols];
+      equation[..self.variables].copy_from_slice(coeffs);
+      let slack = match relation {
+        Relation::LessOrEqual => 1.0,
+        Relation::GreaterOrEqual => -1.0,
+        Relation::Equal => 0.0,
+      };
+      if slack != 0.0 {
+        equation[s_offset] = slack;
+        s_offset += 1;
+      }
+      equation[cols - 1] = *rhs;
+      if *rhs < 0.0 {
+        for z in equation.iter_mut().filter(|z| **z != 0.0) {
+          *z = -*z;
+        }
+      }
+      if needs_artificial(constraint) {
+        for (t, v) in intermediate.iter_mut().zip(&equation) {
+          *t += v;
+        }
+        equation[a_offset] = 1.0;
+        a_offset += 1;
+      }
+      result.push(equation);
+    }
+
+    let mut objective = vec![0.0; cols];
+    for (o, c) in objective.iter_mut().zip(&self.objective) {
+      if *c != 0.0 {
+        *o = match self.sense {
+          ObjectiveSense::Minimise => -c,
+          ObjectiveSense::Maximise => *c,
+        };
+      }
+    }
+    result.push(objective);
+    result.push(intermediate);
+    (result, self.variables, num_a)
+  }
+}
+
 #[cfg(test)]
 mod tests {
 
   use super::*;
   use crate::tests::init;
+  use iter_tools::Itertools;
   use log::info;
+  use proptest::{prelude::*, test_runner::TestCaseError};
+  use std::cmp::Ordering;
 
   #[test]
   fn test_artificial_variables_stage_1() {
@@ -232,17 +920,15 @@ mod tests {
     info!("{m}");
   }
 
-  #[test]
-  fn test_four_intervals() {
-    init();
-    // Optimisation for intervals
-    // 1. consumption 0, price 1
-    // 2. consumption 3, price 2
-    // 3. consumption 1, price 2
-    // 4. consumption 3, price 1
-    // battery starts empty, can not charge more than 1.5
-    // battery capacity is 2
-    let mut m = Matrix::new(
+  // Optimisation for intervals
+  // 1. consumption 0, price 1
+  // 2. consumption 3, price 2
+  // 3. consumption 1, price 2
+  // 4. consumption 3, price 1
+  // battery starts empty, can not charge more than 1.5
+  // battery capacity is 2
+  fn four_intervals_tableau() -> Matrix {
+    Matrix::new(
       vec![
         //   x1   x2   s1   s2   s3   s4   s5   s6   a1   a2   limit
         // max charge constraints
@@ -267,7 +953,13 @@ mod tests {
       ],
       2,
       2,
-    );
+    )
+  }
+
+  #[test]
+  fn test_four_intervals() {
+    init();
+    let mut m = four_intervals_tableau();
 
     info!("{m}");
     assert!(m.solve().is_ok());
@@ -275,6 +967,42 @@ mod tests {
     m.phase_two();
     assert!(m.solve().is_ok());
     assert_eq!(vec![1.5, 0.5], m.get_solution()[0..2]);
+    // 1.5 * 1 + 0.5 * 2
+    assert_eq!(2.5, m.get_objective_value());
+  }
+
+  #[cfg(feature = "exact")]
+  #[test]
+  fn exact_four_intervals() {
+    use num_rational::BigRational;
+    let data: Vec<Vec<BigRational>> = four_intervals_tableau()
+      .data
+      .iter()
+      .map(|row| row.iter().map(|&v| BigRational::from_float(v).unwrap()).collect())
+      .collect();
+    // no tolerance, the solution is exactly what test_four_intervals expects
+    let (solution, objective) = solve_tableau(data, 2, 2).unwrap();
+    let halves = |n: i64| BigRational::new(n.into(), 2.into());
+    assert_eq!(solution[0..2], [halves(3), halves(1)]);
+    assert_eq!(objective, halves(5));
+  }
+
+  #[test]
+  fn solve_in_memory_tableau() {
+    let (solution, objective) = solve_tableau(four_intervals_tableau().data, 2, 2).unwrap();
+    assert_eq!(vec![1.5, 0.5], solution[0..2]);
+    assert_eq!(2.5, objective);
+    // an infeasible program is an error, x <= 1 and x >= 2
+    let (data, variables, artificials) = TableauBuilder::new(1).add_le(&[1.0], 1.0).add_ge(&[1.0], 2.0).build();
+    assert_eq!(solve_tableau(data, variables, artificials), Err(SolveError::Infeasible));
+    // a ragged tableau is an error too, not a panic
+    let mut ragged = four_intervals_tableau().data;
+    ragged[1].pop();
+    let expected = ragged[0].len();
+    assert_eq!(
+      solve_tableau(ragged, 2, 2),
+      Err(SolveError::Structure(StructureError::Ragged { row: 1, len: expected - 1, expected }))
+    );
   }
 
   // Tableau for the following minimization problem:
@@ -306,26 +1034,155 @@ mod tests {
     )
   }
 
+  #[test]
+  fn test_builder_reproduces_tableau() {
+    let (data, variables, artificials) = TableauBuilder::new(2)
+      .add_le(&[1.0, 0.0], 1.5)
+      .add_le(&[0.0, 1.0], 1.0)
+      .add_ge(&[1.0, 0.0], 1.0)
+      .add_ge(&[1.0, 1.0], 2.0)
+      .objective(&[1.0, 2.0])
+      .build();
+    assert_eq!(Matrix::new(data, variables, artificials), tableau_without_max_capacity());
+  }
+
+  #[test]
+  fn maximisation() {
+    // maximise 3x + 2y with x + y <= 4, x + 3y <= 6 and x <= 3, the optimum is 11 at (3, 1)
+    let mut matrix = TableauBuilder::new(2)
+      .add_le(&[1.0, 1.0], 4.0)
+      .add_le(&[1.0, 3.0], 6.0)
+      .add_le(&[1.0, 0.0], 3.0)
+      .objective(&[3.0, 2.0])
+      .sense(ObjectiveSense::Maximise)
+      .matrix();
+    matrix.solve_with_stats().unwrap();
+    assert!((matrix.get_objective_value() - 11.0).abs() < 1e-9);
+    let solution = matrix.get_solution();
+    assert!((solution[0] - 3.0).abs() < 1e-9 && (solution[1] - 1.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn leaving_row_with_the_smallest_ratio() {
+    // the ratios 1 + tol / 2 and 1 differ by less than the tolerance, pivoting on the first row
+    // would leave the slack of the second one at -500 tol
+    let mut matrix = TableauBuilder::new(1)
+      .add_le(&[1.0], 1.0 + DEFAULT_TOLERANCE / 2.0)
+      .add_le(&[1000.0], 1000.0)
+      .objective(&[1.0])
+      .sense(ObjectiveSense::Maximise)
+      .matrix();
+    matrix.solve_with_stats().unwrap();
+    assert!(matrix.get_solution().iter().chain(&matrix.get_slacks()).all(|&value| value >= -DEFAULT_TOLERANCE));
+    assert!((matrix.get_solution()[0] - 1.0).abs() < DEFAULT_TOLERANCE);
+  }
+
+  #[test]
+  fn progress_callback() {
+    let mut matrix = tableau_without_max_capacity();
+    let stats = matrix.solve_with_stats().unwrap();
+
+    let mut matrix = tableau_without_max_capacity();
+    let mut progress = Vec::new();
+    let mut record = |p: &SolveProgress| {
+      progress.push((p.phase, p.iteration));
+      ControlFlow::Continue(())
+    };
+    let phase_one = matrix.solve_with_callback(&mut record).unwrap();
+    matrix.phase_two();
+    let phase_two = matrix.solve_with_callback(&mut record).unwrap();
+    assert_eq!((phase_one, phase_one + phase_two), (stats.phase_one_iterations, stats.iterations));
+    assert_eq!(progress.len(), stats.iterations);
+    assert_eq!(progress.iter().filter(|(phase, _)| *phase == Phase::One).count(), phase_one);
+    assert_eq!(progress[0], (Phase::One, 1));
+
+    // stopping after the first pivot
+    let mut matrix = tableau_without_max_capacity();
+    assert_eq!(matrix.solve_with_callback(|_| ControlFlow::Break(())), Err(SolveError::Cancelled(1)));
+  }
+
+  #[test]
+  fn malformed_tableau() {
+    let mut ragged = tableau_without_max_capacity().data;
+    ragged[1].pop();
+    assert_eq!(Matrix::try_new(ragged, 2, 2).err(), Some(StructureError::Ragged { row: 1, len: 8, expected: 9 }));
+    // only an objective row
+    assert_eq!(Matrix::try_new(vec![vec![1.0, 0.0]], 1, 0).err(), Some(StructureError::TooFewRows(1)));
+    // two variables and two artificials do not fit in four columns with the limits
+    let narrow: Vec<Vec<f64>> = tableau_without_max_capacity().data.iter().map(|r| r[..4].to_vec()).collect();
+    assert_eq!(Matrix::try_new(narrow, 2, 2).err(), Some(StructureError::TooFewColumns { columns: 4, needed: 5 }));
+
+    let matrix = Matrix::try_new(tableau_without_max_capacity().data, 2, 2).unwrap();
+    assert_eq!(matrix.validate_structure(), Ok(()));
+  }
+
+  #[test]
+  fn ties_go_to_the_lowest_index() {
+    // minimise -x - y with x + y <= 1, every point of the edge is optimal. The cost of y is a rounding larger,
+    // the tie still goes to the lowest column and x takes it all
+    let (data, variables, artificials) = TableauBuilder::new(2).add_le(&[1.0, 1.0], 1.0).objective(&[-1.0, -1.0 - 1e-12]).build();
+    let mut m = Matrix::new(data, variables, artificials);
+    assert!(m.solve_with_stats().is_ok());
+    assert_eq!(m.get_solution(), vec![1.0, 0.0]);
+    // both limits give the same ratio, the tie goes to the first row
+    let (data, variables, artificials) =
+      TableauBuilder::new(1).add_le(&[2.0], 2.0).add_le(&[1.0], 1.0).objective(&[-1.0]).build();
+    let mut m = Matrix::new(data, variables, artificials);
+    assert!(m.solve_with_stats().is_ok());
+    assert_eq!(m.get_basis(), vec![0, 2]);
+    #[cfg(feature = "revised")]
+    {
+      let (data, variables, artificials) =
+        TableauBuilder::new(1).add_le(&[2.0], 2.0).add_le(&[1.0], 1.0).objective(&[-1.0]).build();
+      let mut revised = crate::revised_simplex::RevisedSimplex::new(data, variables, artificials);
+      assert!(revised.solve_with_stats().is_ok());
+      assert_eq!(revised.get_basis(), vec![0, 2]);
+    }
+  }
+
+  #[test]
+  fn test_builder_with_equality_and_negative_limit() {
+    // minimise x + y with x + y = 2 and -x <= -0.5, the same as x >= 0.5
+    let (data, variables, artificials) =
+      TableauBuilder::new(2).add_eq(&[1.0, 1.0], 2.0).add_le(&[-1.0, 0.0], -0.5).objective(&[1.0, 1.0]).build();
+    assert_eq!(artificials, 2);
+    assert_eq!(
+      data,
+      vec![
+        //   x1   x2   s1    a1   a2   limit
+        vec![1.0, 1.0, 0.0, 1.0, 0.0, 2.0],
+        vec![1.0, 0.0, -1.0, 0.0, 1.0, 0.5],
+        vec![-1.0, -1.0, 0.0, 0.0, 0.0, 0.0],
+        vec![2.0, 1.0, -1.0, 0.0, 0.0, 2.5],
+      ]
+    );
+    let mut m = Matrix::new(data, variables, artificials);
+    assert!(m.solve().is_ok());
+    m.phase_two();
+    assert!(m.solve().is_ok());
+    assert_eq!(m.get_objective_value(), 2.0);
+  }
+
   #[test]
   fn test_without_max_capacity_individual_steps() {
     init();
 
     let mut m = tableau_without_max_capacity();
-    let pivot = m.find_pivot();
+    let pivot = m.find_pivot().unwrap();
     assert_eq!(pivot, Some((2, 0)));
     m.pivot(pivot.unwrap());
 
-    let pivot = m.find_pivot();
+    let pivot = m.find_pivot().unwrap();
     assert_eq!(pivot, Some((1, 1)));
     m.pivot(pivot.unwrap());
 
-    let pivot = m.find_pivot();
+    let pivot = m.find_pivot().unwrap();
     assert_eq!(pivot, Some((3, 4)));
     m.pivot(pivot.unwrap());
 
     // check intermediate objective function is zero
     m.phase_two();
-    let pivot = m.find_pivot();
+    let pivot = m.find_pivot().unwrap();
     assert_eq!(pivot, Some((0, 3)));
     m.pivot(pivot.unwrap());
 
@@ -334,6 +1191,32 @@ mod tests {
     assert_eq!(vec![1.5, 0.5], solution);
   }
 
+  #[test]
+  fn solve_step_by_step() {
+    init();
+
+    let mut m = tableau_without_max_capacity();
+    let mut pivots = Vec::new();
+    for phase in 1..=2 {
+      if phase == 2 {
+        m.phase_two();
+      }
+      assert!(!m.is_optimal());
+      loop {
+        match m.step() {
+          StepResult::Pivoted(pivot) => pivots.push(pivot),
+          result => {
+            assert_eq!(result, StepResult::Optimal);
+            break;
+          }
+        }
+      }
+      assert!(m.is_optimal());
+    }
+    assert_eq!(pivots, vec![(2, 0), (1, 1), (3, 4), (0, 3)]);
+    assert_eq!(vec![1.5, 0.5], m.get_solution());
+  }
+
   #[test]
   fn test_without_max_capacity() {
     init();
@@ -351,6 +1234,117 @@ mod tests {
     assert_eq!(vec![1.5, 0.5], solution);
   }
 
+  #[test]
+  fn test_sparse_pivot_matches_dense() {
+    init();
+
+    let mut m = tableau_without_max_capacity();
+    while let Some((pivot_row, pivot_col)) = m.find_pivot().unwrap() {
+      // the textbook update of every cell
+      let mut expected = m.data.clone();
+      let pivot_val = expected[pivot_row][pivot_col];
+      expected[pivot_row].iter_mut().for_each(|v| *v /= pivot_val);
+      for row in 0..expected.len() {
+        if row != pivot_row {
+          let ratio = expected[row][pivot_col];
+          for col in 0..expected[row].len() {
+            expected[row][col] -= ratio * expected[pivot_row][col];
+          }
+        }
+      }
+      m.pivot((pivot_row, pivot_col));
+      for (row, expected_row) in m.data.iter().zip(&expected) {
+        assert!(row.iter().zip(expected_row).all(|(a, b)| (a - b).abs() < 1e-12), "{row:?} != {expected_row:?}");
+      }
+    }
+  }
+
+  #[test]
+  fn test_solution_with_rounding_errors() {
+    init();
+
+    let mut m = tableau_without_max_capacity();
+    m.solve_with_stats().unwrap();
+    assert_eq!(m.get_solution(), vec![1.5, 0.5]);
+    // the floating point errors of a longer run
+    for col in 0..2 {
+      let row = m.basic_row(col).unwrap();
+      m.set(row, col, 0.9999999);
+      m.set((row + 1) % 4, col, 1e-15);
+    }
+    assert_eq!(m.get_solution(), vec![1.5, 0.5]);
+  }
+
+  #[test]
+  fn test_debug_json() {
+    init();
+
+    let mut m = tableau_without_max_capacity();
+    m.solve_with_stats().unwrap();
+    let json = m.to_debug_json();
+    let data: Vec<Vec<f64>> = serde_json::from_value(json["data"].clone()).unwrap();
+    assert_eq!(data, m.data);
+    assert_eq!((data.len(), data[0].len()), (6, 9));
+    assert_eq!(json["variables"], 2);
+    assert_eq!(json["artificials"], 2);
+    assert_eq!(json["phase"], "Two");
+    assert_eq!(json["solution"], serde_json::json!([1.5, 0.5]));
+    assert_eq!(json["basis"].as_array().unwrap().len(), 4);
+  }
+
+  #[test]
+  fn test_dual_values() {
+    init();
+
+    let mut m = tableau_without_max_capacity();
+    assert!(m.solve().is_ok());
+    m.phase_two();
+    assert!(m.solve().is_ok());
+    // raising the x limit lets the cheaper x replace y, -1 per unit
+    // y <= 1 and x >= 1 are not binding
+    // raising x + y >= 2 needs more of y, +2 per unit
+    assert_eq!(vec![-1.0, 0.0, 0.0, 2.0], m.get_dual_values());
+  }
+
+  #[test]
+  fn test_warm_start() {
+    init();
+
+    let mut m = tableau_without_max_capacity();
+    assert!(m.solve().is_ok());
+    m.phase_two();
+    assert!(m.solve().is_ok());
+    let basis = m.get_basis();
+    info!("basis: {:?}", basis);
+
+    // the price of x changes a bit
+    let mut perturbed = tableau_without_max_capacity().data;
+    perturbed[4][0] = -1.1;
+
+    let mut cold = Matrix::new(perturbed.clone(), 2, 2);
+    assert!(cold.solve().is_ok());
+    cold.phase_two();
+    assert!(cold.solve().is_ok());
+
+    let mut warm = Matrix::from_basis(perturbed, 2, 2, basis);
+    assert!(warm.solve().is_ok());
+    warm.phase_two();
+    assert!(warm.solve().is_ok());
+
+    info!("cold start: {} iterations, warm start: {} iterations", cold.iterations, warm.iterations);
+    assert!(warm.iterations < cold.iterations);
+    assert_eq!(cold.get_solution(), warm.get_solution());
+  }
+
+  #[test]
+  fn test_warm_start_with_unusable_basis() {
+    init();
+
+    // x2 twice, the basis is singular and we start from scratch
+    let m = Matrix::from_basis(tableau_without_max_capacity().data, 2, 2, vec![1, 1, 6, 7]);
+    assert_eq!(m, tableau_without_max_capacity());
+  }
+
   #[test]
   fn test_reverse_coefficients() {
     init();
@@ -376,4 +1370,432 @@ mod tests {
     let solution = m.get_solution();
     assert_eq!(vec![0.5, 0.0], solution);
   }
+
+  // Beale's example, it cycles forever with the most positive coefficient rule
+  // minimize -3/4 x4 + 150 x5 - 1/50 x6 + 6 x7
+  fn beale_tableau() -> Matrix {
+    Matrix::new(
+      vec![
+        //   x4     x5     x6    x7   s1   s2   s3   limit
+        vec![0.25, -60.0, -0.04, 9.0, 1.0, 0.0, 0.0, 0.0],
+        vec![0.5, -90.0, -0.02, 3.0, 0.0, 1.0, 0.0, 0.0],
+        vec![0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 1.0],
+        // objective function
+        vec![0.75, -150.0, 0.02, -6.0, 0.0, 0.0, 0.0, 0.0],
+        // no artificial variables, the intermediate objective is empty
+        vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+      ],
+      4,
+      0,
+    )
+  }
+
+  #[test]
+  fn test_blands_rule_prevents_cycling() {
+    init();
+
+    let mut m = beale_tableau();
+    assert!(m.solve().is_ok());
+    m.phase_two();
+    assert!(m.solve().is_ok());
+    let solution = m.get_solution();
+    info!("solution: {:?}", solution);
+    let expected = [0.04, 0.0, 1.0, 0.0];
+    let tolerance = 0.0001;
+    for (s, e) in solution.iter().zip(expected.iter()) {
+      assert!((s - e).abs() < tolerance);
+    }
+    assert!((m.get_objective_value() + 0.05).abs() < tolerance);
+  }
+
+  #[test]
+  fn test_cycling_without_blands_rule() {
+    init();
+
+    let mut m = beale_tableau();
+    m.set_stall_limit(usize::MAX);
+    assert!(m.solve().is_ok());
+    m.phase_two();
+    // the default limit follows the size of the tableau, a small one gives up quickly
+    let limit = ITERATIONS_PER_DIMENSION * (m.data.len() + m.data[0].len());
+    assert_eq!(m.solve(), Err(SolveError::IterationLimit(limit)));
+  }
+
+  // minimise x1 + 2 x2 with x1 <= 1, x2 <= 1.5, 0.9 x1 <= 0.5, 0.9 x1 + 0.9 x2 <= 0.5
+  // and 0.9 x1 + 0.9 x2 >= 0.5, phase one ends with a1 in the basis at zero
+  fn artificial_left_tableau() -> Matrix {
+    Matrix::new(
+      vec![
+        //   x1   x2   s1   s2   s3   s4   s5    a1   limit
+        vec![1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+        vec![0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.5],
+        vec![0.9, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.5],
+        vec![0.9, 0.9, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.5],
+        vec![0.9, 0.9, 0.0, 0.0, 0.0, 0.0, -1.0, 1.0, 0.5],
+        vec![-1.0, -2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        vec![0.9, 0.9, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.5],
+      ],
+      2,
+      1,
+    )
+  }
+
+  #[test]
+  fn test_artificial_left_in_basis() {
+    init();
+
+    let mut m = artificial_left_tableau();
+    m.solve_with_stats().unwrap();
+    assert!(!m.get_basis().contains(&7));
+    let solution = m.get_solution();
+    assert!((solution[0] - 0.5 / 0.9).abs() < 0.0001);
+    assert_eq!(solution[1], 0.0);
+  }
+
+  #[test]
+  fn test_redundant_constraint() {
+    init();
+
+    // minimise x1 + x2 with x1 = 1 twice and x2 <= 1, one of the artificials stays at zero
+    // in a row that has nothing else left
+    let (data, variables, artificials) = TableauBuilder::new(2)
+      .add_eq(&[1.0, 0.0], 1.0)
+      .add_eq(&[1.0, 0.0], 1.0)
+      .add_le(&[0.0, 1.0], 1.0)
+      .objective(&[1.0, 1.0])
+      .build();
+    let mut m = Matrix::new(data, variables, artificials);
+    m.solve_with_stats().unwrap();
+    assert_eq!(m.redundant_rows().len(), 1);
+    assert_eq!(m.get_solution(), vec![1.0, 0.0]);
+    assert_eq!(m.get_objective_value(), 1.0);
+  }
+
+  #[test]
+  fn test_stats_of_degenerate_tableau() {
+    init();
+
+    let mut m = beale_tableau();
+    let stats = m.solve_with_stats().unwrap();
+    // no artificials, all the pivoting happens in phase two and stalls until Bland's rule takes over
+    assert_eq!(stats.phase_one_iterations, 0);
+    assert!(stats.iterations > DEFAULT_STALL_LIMIT);
+    assert!(stats.cycled);
+  }
+
+  #[test]
+  fn test_not_finite_tableau() {
+    init();
+
+    let mut m = beale_tableau();
+    m.data[1][3] = f64::NAN;
+    assert_eq!(m.solve(), Err(SolveError::NotFinite { row: 1, col: 3 }));
+    m.data[1][3] = 3.0;
+    m.data[2][7] = f64::INFINITY;
+    assert_eq!(m.solve(), Err(SolveError::NotFinite { row: 2, col: 7 }));
+  }
+
+  #[test]
+  fn test_repeating_basis_is_detected() {
+    init();
+
+    let mut m = beale_tableau();
+    m.set_stall_limit(usize::MAX);
+    m.set_iteration_limit(100);
+    assert_eq!(m.solve_with_stats(), Err(SolveError::IterationLimit(100)));
+    assert!(m.cycled);
+    assert_eq!(m.iterations, 100);
+  }
+
+  #[test]
+  fn test_stats_without_cycling() {
+    init();
+
+    let mut m = tableau_without_max_capacity();
+    let stats = m.solve_with_stats().unwrap();
+    assert_eq!(stats, SolveStats { iterations: 4, phase_one_iterations: 3, cycled: false });
+  }
+
+  // 0.7 * x >= b, for this b the rounding leaves 0.00024 in the intermediate objective
+  fn scaled_up_tableau(tolerance: f64) -> Matrix {
+    let b = 1857142857142.8572;
+    Matrix::with_tolerance(
+      vec![
+        //   x    s1    a1   limit
+        vec![0.7, -1.0, 1.0, b],
+        // objective function
+        vec![-1.0, 0.0, 0.0, 0.0],
+        // intermediate objective function
+        vec![0.7, -1.0, 0.0, b],
+      ],
+      1,
+      1,
+      tolerance,
+    )
+  }
+
+  #[test]
+  fn test_scaled_up_tableau_needs_looser_tolerance() {
+    init();
+
+    let mut m = scaled_up_tableau(DEFAULT_TOLERANCE);
+    assert_eq!(m.solve(), Err(SolveError::Infeasible));
+
+    let mut m = scaled_up_tableau(0.001);
+    assert!(m.solve().is_ok());
+    m.phase_two();
+    assert!(m.solve().is_ok());
+    let solution = m.get_solution();
+    assert!((solution[0] - 1857142857142.8572 / 0.7).abs() < 0.001);
+  }
+
+  #[test]
+  fn test_unbounded() {
+    init();
+
+    // minimize -x subject to x - y <= 1, x can grow together with y
+    let mut m = Matrix::new(
+      vec![
+        //   x    y    s1   limit
+        vec![1.0, -1.0, 1.0, 1.0],
+        // objective function
+        vec![1.0, 0.0, 0.0, 0.0],
+        // intermediate objective function
+        vec![0.0, 0.0, 0.0, 0.0],
+      ],
+      2,
+      0,
+    );
+    assert!(m.solve().is_ok());
+    m.phase_two();
+    assert_eq!(m.solve(), Err(SolveError::Unbounded));
+  }
+
+  // the revised simplex has to reach the objective of the tableau, or fail the same way. The solutions can differ
+  // when there are several optima
+  #[cfg(feature = "revised")]
+  fn check_revised(m: &Matrix) -> Result<crate::revised_simplex::RevisedSimplex, TestCaseError> {
+    let mut revised =
+      crate::revised_simplex::RevisedSimplex::with_tolerance(m.data.clone(), m.variables, m.artificials, m.tolerance);
+    let mut m = Matrix::with_tolerance(m.data.clone(), m.variables, m.artificials, m.tolerance);
+    let (expected, result) = (m.solve_with_stats().map(|_| ()), revised.solve_with_stats().map(|_| ()));
+    prop_assert_eq!(&expected, &result);
+    if expected.is_ok() {
+      prop_assert!((m.get_objective_value() - revised.get_objective_value()).abs() < 1e-6);
+      prop_assert_eq!(m.redundant_rows(), revised.redundant_rows());
+    }
+    Ok(revised)
+  }
+
+  // a linear program in x >= 0, minimising costs * x, each constraint is a <= or a >= row
+  #[derive(Clone, Debug)]
+  struct Program {
+    constraints: Vec<(Vec<f64>, Ordering, f64)>,
+    costs: Vec<f64>,
+  }
+
+  impl Program {
+    // the tableau as build_tableau makes it: a slack per row, an artificial per >= row after a negative limit
+    // is turned around, the negated costs and the intermediate goal
+    fn tableau(&self) -> Matrix {
+      let n = self.costs.len();
+      let rows: Vec<_> = self
+        .constraints
+        .iter()
+        .map(|(a, ordering, b)| {
+          if *b < 0.0 {
+            (a.iter().map(|v| -v).collect::<Vec<_>>(), ordering.reverse(), -b)
+          } else {
+            (a.clone(), *ordering, *b)
+          }
+        })
+        .collect();
+      let num_s = rows.len();
+      let num_a = rows.iter().filter(|(_, ordering, _)| *ordering == Ordering::Greater).count();
+      let cols = n + num_s + num_a + 1;
+      let mut data = Vec::new();
+      let mut intermediate = vec![0.0; cols];
+      let mut a_offset = n + num_s;
+      for (row, (a, ordering, b)) in rows.iter().enumerate() {
+        let mut equation = vec![0.0; cols];
+        equation[..n].copy_from_slice(a);
+        equation[cols - 1] = *b;
+        if *ordering == Ordering::Less {
+          equation[n + row] = 1.0;
+        } else {
+          equation[n + row] = -1.0;
+          for (t, v) in intermediate.iter_mut().zip(&equation) {
+            *t += v;
+          }
+          equation[a_offset] = 1.0;
+          a_offset += 1;
+        }
+        data.push(equation);
+      }
+      let mut objective = vec![0.0; cols];
+      for (o, c) in objective.iter_mut().zip(&self.costs) {
+        *o = -c;
+      }
+      data.push(objective);
+      data.push(intermediate);
+      Matrix::new(data, n, num_a)
+    }
+
+    fn is_feasible(&self, x: &[f64], tolerance: f64) -> bool {
+      x.iter().all(|&v| v >= -tolerance)
+        && self.constraints.iter().all(|(a, ordering, b)| {
+          let lhs: f64 = a.iter().zip(x).map(|(a, x)| a * x).sum();
+          match ordering {
+            Ordering::Less => lhs <= b + tolerance,
+            _ => lhs >= b - tolerance,
+          }
+        })
+    }
+
+    fn cost(&self, x: &[f64]) -> f64 {
+      self.costs.iter().zip(x).map(|(c, x)| c * x).sum()
+    }
+
+    // the optimum is on a vertex, where n of the constraints and of the x >= 0 bounds meet
+    fn brute_force_minimum(&self) -> Option<f64> {
+      let n = self.costs.len();
+      let mut planes: Vec<(Vec<f64>, f64)> = self.constraints.iter().map(|(a, _, b)| (a.clone(), *b)).collect();
+      planes.extend((0..n).map(|i| ((0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect(), 0.0)));
+      let mut best: Option<f64> = None;
+      for chosen in (0..planes.len()).combinations(n) {
+        let system: Vec<_> = chosen.iter().map(|&i| planes[i].clone()).collect();
+        if let Some(x) = solve_system(system).filter(|x| self.is_feasible(x, 1e-9)) {
+          best = Some(best.map_or(self.cost(&x), |best| best.min(self.cost(&x))));
+        }
+      }
+      best
+    }
+  }
+
+  // Gaussian elimination with partial pivoting, None when the planes do not meet in a single point
+  fn solve_system(mut rows: Vec<(Vec<f64>, f64)>) -> Option<Vec<f64>> {
+    let n = rows.len();
+    for col in 0..n {
+      let pivot = (col..n).max_by(|&a, &b| rows[a].0[col].abs().total_cmp(&rows[b].0[col].abs()))?;
+      if rows[pivot].0[col].abs() < 1e-9 {
+        return None;
+      }
+      rows.swap(col, pivot);
+      for row in 0..n {
+        if row != col {
+          let factor = rows[row].0[col] / rows[col].0[col];
+          let (a, b) = (rows[col].0.clone(), rows[col].1);
+          rows[row].0.iter_mut().zip(&a).for_each(|(v, a)| *v -= factor * a);
+          rows[row].1 -= factor * b;
+        }
+      }
+    }
+    Some(rows.iter().enumerate().map(|(i, (a, b))| b / a[i]).collect())
+  }
+
+  // both phases, then the solution has to keep to every constraint and reach the brute force minimum
+  fn check_program(program: &Program) -> Result<(), TestCaseError> {
+    let mut m = program.tableau();
+    prop_assert!(m.solve_with_stats().is_ok(), "{:?}", program);
+    let x = m.get_solution();
+    prop_assert!(program.is_feasible(&x, 1e-6), "{:?} breaks a constraint of {:?}", x, program);
+    let minimum = program.brute_force_minimum().expect("the programs are feasible by construction");
+    prop_assert!((program.cost(&x) - minimum).abs() < 1e-6, "{} instead of {} for {:?}", program.cost(&x), minimum, program);
+    prop_assert!((m.get_objective_value() - minimum).abs() < 1e-6);
+    Ok(())
+  }
+
+  // the constraints are built around a point, which makes them feasible, and the <= rows have positive
+  // coefficients for every variable, which bounds the program
+  fn random_program() -> impl Strategy<Value = Program> {
+    (2..=3usize).prop_flat_map(|n| {
+      let point = prop::collection::vec(0..=3i32, n);
+      let less = prop::collection::vec((prop::collection::vec(1..=5i32, n), 0..=4i32), 1..=3);
+      let greater = prop::collection::vec((prop::collection::vec(0..=5i32, n), 0..=4i32), 0..=2);
+      let costs = prop::collection::vec(-5..=5i32, n);
+      (point, less, greater, costs).prop_map(|(point, less, greater, costs)| {
+        let at_point = |a: &[i32]| a.iter().zip(&point).map(|(a, x)| a * x).sum::<i32>();
+        let to_f64 = |a: &[i32]| a.iter().map(|&v| v as f64).collect::<Vec<_>>();
+        let less = less.iter().map(|(a, slack)| (to_f64(a), Ordering::Less, (at_point(a) + slack) as f64));
+        let greater = greater.iter().map(|(a, slack)| (to_f64(a), Ordering::Greater, (at_point(a) - slack) as f64));
+        Program { constraints: less.chain(greater).collect(), costs: to_f64(&costs) }
+      })
+    })
+  }
+
+  proptest! {
+    #[test]
+    fn random_programs_reach_the_optimum(program in random_program()) {
+      check_program(&program)?;
+    }
+  }
+
+  #[test]
+  fn known_programs_reach_the_optimum() {
+    init();
+
+    // Beale's degenerate program, the objective row of beale_tableau holds the negated costs
+    let beale = Program {
+      constraints: vec![
+        (vec![0.25, -60.0, -0.04, 9.0], Ordering::Less, 0.0),
+        (vec![0.5, -90.0, -0.02, 3.0], Ordering::Less, 0.0),
+        (vec![0.0, 0.0, 1.0, 0.0], Ordering::Less, 1.0),
+      ],
+      costs: vec![-0.75, 150.0, -0.02, 6.0],
+    };
+    check_program(&beale).unwrap();
+    assert!((beale.brute_force_minimum().unwrap() + 0.05).abs() < 1e-9);
+
+    // a >= row with a negative limit becomes a <= row, one with a positive limit needs an artificial
+    let mixed = Program {
+      constraints: vec![
+        (vec![1.0, 1.0], Ordering::Less, 4.0),
+        (vec![1.0, 0.0], Ordering::Greater, 1.0),
+        (vec![0.0, 1.0], Ordering::Greater, -2.0),
+      ],
+      costs: vec![1.0, -1.0],
+    };
+    check_program(&mixed).unwrap();
+  }
+
+  #[cfg(feature = "revised")]
+  #[test]
+  fn revised_simplex_agrees_with_the_tableau() {
+    init();
+
+    let (redundant, variables, artificials) = TableauBuilder::new(2)
+      .add_eq(&[1.0, 0.0], 1.0)
+      .add_eq(&[1.0, 0.0], 1.0)
+      .add_le(&[0.0, 1.0], 1.0)
+      .objective(&[1.0, 1.0])
+      .build();
+    let unbounded = Matrix::new(vec![vec![1.0, -1.0, 1.0, 1.0], vec![1.0, 0.0, 0.0, 0.0], vec![0.0, 0.0, 0.0, 0.0]], 2, 0);
+    let tableaus = [
+      four_intervals_tableau(),
+      tableau_without_max_capacity(),
+      beale_tableau(),
+      artificial_left_tableau(),
+      Matrix::new(redundant, variables, artificials),
+      scaled_up_tableau(DEFAULT_TOLERANCE),
+      scaled_up_tableau(0.001),
+      unbounded,
+    ];
+    for m in &tableaus {
+      let revised = check_revised(m).unwrap();
+      let mut m = Matrix::with_tolerance(m.data.clone(), m.variables, m.artificials, m.tolerance);
+      if m.solve_with_stats().is_ok() {
+        let (expected, solution) = (m.get_solution(), revised.get_solution());
+        assert!(expected.iter().zip(&solution).all(|(a, b)| (a - b).abs() < 1e-6), "{expected:?} != {solution:?}");
+      }
+    }
+  }
+
+  #[cfg(feature = "revised")]
+  proptest! {
+    #[test]
+    fn revised_simplex_agrees_on_random_programs(program in random_program()) {
+      let revised = check_revised(&program.tableau())?;
+      prop_assert!(program.is_feasible(&revised.get_solution(), 1e-6));
+    }
+  }
 }
@@ -0,0 +1,374 @@
+//! Plans the charging and discharging of a battery to keep the electricity consumption under a limit
+//! at the lowest price. The plan comes from a linear program solved with the simplex in `dual_simplex`.
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand};
+use data::{DataError, EnergyUnit, Out, OutputFormat, Planning, Summary, TimeArg};
+use dual_simplex::SolveError;
+use log::LevelFilter;
+use std::io::{Read, Write};
+
+#[cfg(feature = "milp")]
+pub mod branch_and_bound;
+pub mod calculation;
+pub mod data;
+pub mod dual_simplex;
+#[cfg(feature = "revised")]
+pub mod revised_simplex;
+pub mod tableau_creation;
+
+pub use calculation::calculation;
+pub use data::{Config, Data, Plan};
+pub use dual_simplex::{solve_tableau, Matrix, SolveProgress, StepResult};
+pub use tableau_creation::{build_tableau, TableauTemplate};
+
+/// The command line arguments
+#[derive(Parser)]
+pub struct Args {
+  // solve when missing, for the command lines written before the subcommands
+  #[command(subcommand)]
+  pub command: Option<Command>,
+  #[arg(global = true, short, long, default_value = "consumption.json", help = "json file with the predicted power consumption")]
+  pub consumption: String,
+  #[arg(global = true, short, long, default_value = "prices.json", help = "json file with the predicted prices")]
+  pub prices: String,
+  #[arg(
+    global = true,
+    short = 'i',
+    long,
+    default_value = "config.toml",
+    help = "toml file with customer configuration, max power, battery capacity, etc."
+  )]
+  pub config: String,
+  #[arg(
+    global = true,
+    long,
+    help = "json file with the forecasts, the prices and optionally the config, replaces the other files"
+  )]
+  pub input: Option<String>,
+  #[arg(
+    global = true,
+    long,
+    value_name = "RFC3339",
+    help = "plan only the intervals starting at or after this time, without an offset it is in the --timezone"
+  )]
+  pub start: Option<TimeArg>,
+  #[arg(
+    global = true,
+    long,
+    value_name = "RFC3339",
+    help = "plan only the intervals ending at or before this time, without an offset it is in the --timezone"
+  )]
+  pub end: Option<TimeArg>,
+  #[arg(
+    global = true,
+    long,
+    value_name = "IANA",
+    help = "time zone of the times in the output and of --start and --end, Europe/Berlin for example, UTC when missing"
+  )]
+  pub timezone: Option<Tz>,
+  #[arg(global = true, long, value_enum, default_value_t = OutputFormat::Json, help = "format of the plan")]
+  pub output_format: OutputFormat,
+  #[arg(
+    global = true,
+    long,
+    value_enum,
+    help = "unit of the energies in the plan, the summary and the --summary table, energy_unit of the config when missing"
+  )]
+  pub energy_unit: Option<EnergyUnit>,
+  #[arg(global = true, short, long, help = "file to write the plan to, stdout if missing")]
+  pub output: Option<String>,
+  #[arg(global = true, long, help = "print an aligned table of the plan instead of the serialized output")]
+  pub summary: bool,
+  #[arg(
+    global = true,
+    long,
+    conflicts_with_all = ["summary", "energy_unit"],
+    help = "print the energy balance of the battery in every interval, with the units, instead of the plan"
+  )]
+  pub explain_units: bool,
+  #[arg(global = true, long, value_name = "PATH", help = "write the final tableau, basis and solution as json to this file")]
+  pub debug_dump: Option<String>,
+  #[arg(global = true, long, help = "print the consumption joined with the prices as json to stderr before solving")]
+  pub echo_input: bool,
+  #[arg(
+    global = true,
+    long,
+    value_name = "N",
+    help = "reject a plan that goes from charging to discharging or back more than N times"
+  )]
+  pub max_switches: Option<usize>,
+  #[arg(
+    global = true,
+    long,
+    help = "read the consumption, prices and config as one json document from stdin, write the plan as json"
+  )]
+  pub batch: bool,
+  #[arg(
+    global = true,
+    long = "set",
+    visible_alias = "config-override",
+    value_name = "KEY=VALUE",
+    help = "override a field of the configuration, can be repeated"
+  )]
+  pub set: Vec<String>,
+  #[arg(global = true, short, long, conflicts_with = "verbose", help = "only log errors")]
+  pub quiet: bool,
+  #[arg(global = true, short, long, action = clap::ArgAction::Count, help = "log more, -v for the solver phases, -vv to follow every step")]
+  pub verbose: u8,
+  #[arg(global = true, long, help = "add the consumption and the price of every interval to the plan")]
+  pub verbose_output: bool,
+}
+
+/// What the binary does with the inputs
+#[derive(Clone, Copy, Debug, PartialEq, Subcommand)]
+pub enum Command {
+  /// Plan the battery, the default
+  Solve,
+  /// Read and check the inputs and the configuration without planning
+  Validate,
+  /// Tell which constraint can not be met when there is no plan
+  Explain,
+}
+
+impl Args {
+  /// The level asked for on the command line, it wins over RUST_LOG
+  pub fn log_level(&self) -> Option<LevelFilter> {
+    match (self.quiet, self.verbose) {
+      (true, _) => Some(LevelFilter::Error),
+      (false, 0) => None,
+      (false, 1) => Some(LevelFilter::Info),
+      (false, 2) => Some(LevelFilter::Debug),
+      (false, _) => Some(LevelFilter::Trace),
+    }
+  }
+}
+
+/// Why a run failed, every kind exits with its own code so that scripts can tell them apart.
+/// The codes start at 3, clap already exits with 2 on a wrong command line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureKind {
+  /// a file could not be read or written
+  Io,
+  /// an input is not valid json, toml or csv
+  Parse,
+  /// the input parsed but does not make sense, the time series do not match or the config is wrong
+  Validation,
+  Infeasible,
+  Unbounded,
+  IterationLimit,
+  /// the caller stopped the solver before the optimum, from the callback of `Matrix::solve_with_callback`
+  Cancelled,
+}
+
+impl FailureKind {
+  pub fn exit_code(self) -> u8 {
+    match self {
+      FailureKind::Io => 3,
+      FailureKind::Parse => 4,
+      FailureKind::Validation => 5,
+      FailureKind::Infeasible => 6,
+      FailureKind::Unbounded => 7,
+      FailureKind::IterationLimit => 8,
+      FailureKind::Cancelled => 9,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      FailureKind::Io => "io",
+      FailureKind::Parse => "parse",
+      FailureKind::Validation => "validation",
+      FailureKind::Infeasible => "infeasible",
+      FailureKind::Unbounded => "unbounded",
+      FailureKind::IterationLimit => "iteration_limit",
+      FailureKind::Cancelled => "cancelled",
+    }
+  }
+}
+
+/// A failed run with what went wrong
+#[derive(Debug)]
+pub struct Failure {
+  pub kind: FailureKind,
+  pub message: String,
+}
+
+impl Failure {
+  pub fn io(message: String) -> Self {
+    Failure { kind: FailureKind::Io, message }
+  }
+
+  /// The failure as one line of json, for stderr
+  pub fn to_json(&self) -> String {
+    serde_json::json!({ "error": self.message, "kind": self.kind.name(), "code": self.kind.exit_code() }).to_string()
+  }
+}
+
+impl From<DataError> for Failure {
+  fn from(error: DataError) -> Self {
+    let kind = match error {
+      DataError::Read { .. } => FailureKind::Io,
+      DataError::Parse { .. } => FailureKind::Parse,
+      _ => FailureKind::Validation,
+    };
+    Failure { kind, message: error.to_string() }
+  }
+}
+
+impl From<SolveError> for Failure {
+  fn from(error: SolveError) -> Self {
+    let kind = match error {
+      SolveError::Infeasible => FailureKind::Infeasible,
+      SolveError::Unbounded => FailureKind::Unbounded,
+      SolveError::IterationLimit(_) => FailureKind::IterationLimit,
+      SolveError::Cancelled(_) => FailureKind::Cancelled,
+      SolveError::NotFinite { .. } | SolveError::Structure(_) => FailureKind::Validation,
+    };
+    Failure { kind, message: error.to_string() }
+  }
+}
+
+/// Runs `calculation`, an infeasible plan is explained with the constraint that can not be met
+pub fn plan(data: &[Data], config: &Config) -> Result<Planning, Failure> {
+  plan_with_dump(data, config, None)
+}
+
+/// Same as `plan`, with the state of the solver at the end in `dump`, see `calculation::calculation_with_dump`
+pub fn plan_with_dump(data: &[Data], config: &Config, dump: Option<&mut serde_json::Value>) -> Result<Planning, Failure> {
+  calculation::calculation_with_dump(data, config, dump).map_err(|e| {
+    let mut failure = Failure::from(e);
+    if let Some(infeasibility) = calculation::explain(data, config) {
+      failure.message = format!("{}: {}", failure.message, infeasibility);
+    }
+    failure
+  })
+}
+
+/// Rejects a plan that switches between charging and discharging more often than the inverter should
+pub fn check_switches(planning: &Planning, max_switches: Option<usize>) -> Result<(), Failure> {
+  let switches = calculation::count_mode_switches(&planning.plans);
+  match max_switches {
+    Some(max) if switches > max => Err(Failure {
+      kind: FailureKind::Infeasible,
+      message: format!("The plan switches {switches} times between charging and discharging, at most {max} are allowed"),
+    }),
+    _ => Ok(()),
+  }
+}
+
+/// Plans from a single json document with the consumption, the prices and the config, for callers in other
+/// languages. The plan is written as json, a failure is returned, nothing panics or exits
+pub fn batch<R: Read, W: Write>(input: R, output: W) -> Result<(), Failure> {
+  let (data, config) = data::read_batch(input)?;
+  let planning = plan(&data, &config)?.without_inputs().in_unit(config.energy_unit);
+  let summary = Summary::new(&data, &config, &planning);
+  let out = Out { planning: planning.plans, summary };
+  data::write_output(&out, OutputFormat::Json, output).map_err(|e| Failure::io(format!("Unable to write the plan: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::data::{Config, EnergyUnit, Objective};
+  use std::sync::Once;
+
+  static INIT: Once = Once::new();
+
+  pub fn init() {
+    INIT.call_once(|| {
+      let _ = env_logger::builder().is_test(true).format_timestamp(None).try_init();
+    });
+  }
+
+  /// A battery of 0.5 Wh charging at 1.5 W without losses under a limit of 2 W, in quarters of an hour and with
+  /// nothing else configured. The tests only spell out what they change
+  pub fn config() -> Config {
+    Config {
+      max_consumption: 2.0,
+      battery_capacity: 2.0 / 4.0,
+      battery_max_charge: 1.5,
+      battery_max_discharge: None,
+      battery_initial_charge: 0.0,
+      battery_efficiency: 1.0,
+      battery_final_charge: 0.0,
+      final_charge_penalty: None,
+      intervals_per_hour: 4.0,
+      feed_in_price: None,
+      charge_efficiency: None,
+      discharge_efficiency: None,
+      self_discharge_per_hour: 0.0,
+      min_charge: 0.0,
+      cycle_cost_per_kwh: 0.0,
+      objective: Objective::MinimiseCost,
+      demand_charge_per_kw: 0.0,
+      min_grid_power: 0.0,
+      daily_import_quota_wh: None,
+      max_ramp_per_interval: None,
+      grid_price_markup: 0.0,
+      grid_price_fixed_adder: 0.0,
+      batteries: vec![],
+      efficiency_segments: vec![],
+      schedule: vec![],
+      currency: "EUR".to_string(),
+      energy_unit: EnergyUnit::Wh,
+    }
+  }
+
+  const BATCH: &str = r#"{
+    "consumption": [
+      { "start": "2024-01-01T00:00:00Z", "end": "2024-01-01T00:15:00Z", "consumption_average_power_interval": 0.0 },
+      { "start": "2024-01-01T00:15:00Z", "end": "2024-01-01T00:30:00Z", "consumption_average_power_interval": 3.0 }
+    ],
+    "prices": [
+      { "start": "2024-01-01T00:00:00Z", "end": "2024-01-01T00:15:00Z", "market_price_per_kwh": 1.0 },
+      { "start": "2024-01-01T00:15:00Z", "end": "2024-01-01T00:30:00Z", "market_price_per_kwh": 2.0 }
+    ],
+    "config": {
+      "max_consumption": 2.0,
+      "battery_capacity": 1.0,
+      "battery_max_charge": 1.5,
+      "battery_initial_charge": 0.0,
+      "battery_efficiency": 1.0,
+      "battery_final_charge": 0.0
+    }
+  }"#;
+
+  #[test]
+  fn batch_plan() {
+    init();
+    let mut output = Vec::new();
+    super::batch(BATCH.as_bytes(), &mut output).unwrap();
+    let out: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(out["planning"].as_array().unwrap().len(), 2);
+    assert_eq!(out["planning"][0]["energy_to_battery_wh"], 0.25);
+    assert!(out["summary"]["savings"].is_number());
+  }
+
+  #[test]
+  fn batch_failure() {
+    init();
+    let mut output = Vec::new();
+    let error = super::batch(BATCH.replace("\"config\"", "\"settings\"").as_bytes(), &mut output).unwrap_err();
+    assert!(output.is_empty());
+    let report: serde_json::Value = serde_json::from_str(&error.to_json()).unwrap();
+    assert_eq!(report["error"], "No configuration in the batch input");
+    assert_eq!(report["kind"], "validation");
+    assert_eq!(report["code"], 5);
+
+    let error = super::batch("not json".as_bytes(), &mut output).unwrap_err();
+    let report: serde_json::Value = serde_json::from_str(&error.to_json()).unwrap();
+    assert!(report["error"].as_str().unwrap().starts_with("Unable to parse"));
+    assert_eq!(error.kind, super::FailureKind::Parse);
+
+    let error =
+      super::batch(BATCH.replace("\"battery_capacity\": 1.0", "\"battery_capacity\": 0.0").as_bytes(), &mut output).unwrap_err();
+    assert_eq!(error.kind, super::FailureKind::Infeasible);
+    assert!(error.message.starts_with("No feasible solution found: ") && error.message.contains("constraint"));
+  }
+
+  #[test]
+  fn cancelled_failure() {
+    let failure = super::Failure::from(super::SolveError::Cancelled(3));
+    assert_eq!((failure.kind.name(), failure.kind.exit_code()), ("cancelled", 9));
+    assert_eq!(failure.message, "Solving cancelled after 3 iterations");
+  }
+}
@@ -1,42 +1,116 @@
+use battery_optimisation::{
+  batch,
+  calculation::{self, calculation},
+  check_switches,
+  data::{self, print_output, EnergyBalance, PlanTable},
+  dual_simplex::SolveError,
+  plan_with_dump, Args, Command, Failure,
+};
 use clap::Parser;
-use data::print_output;
+use log::LevelFilter;
+use std::{
+  fs::File,
+  io::{BufWriter, Write},
+  process::ExitCode,
+};
 
-mod calculation;
-mod data;
-mod dual_simplex;
-mod tableau_creation;
-
-#[derive(Parser)]
-struct Args {
-  #[arg(short, long, default_value = "consumption.json", help = "json file with the predicted power consumption")]
-  consumption: String,
-  #[arg(short, long, default_value = "prices.json", help = "json file with the predicted prices")]
-  prices: String,
-  #[arg(
-    short = 'i',
-    long,
-    default_value = "config.toml",
-    help = "toml file with customer configuration, max power, battery capacity, etc."
-  )]
-  config: String,
-}
-
-fn main() {
+fn main() -> ExitCode {
   let args = Args::parse();
-  let (data, config) = data::read_data(args);
-  let planning = calculation::calculation(&data, &config).expect("Calculation failed");
-  print_output(planning);
+  // warnings by default, RUST_LOG can change that and the command line flags change it again
+  let mut logger = env_logger::Builder::new();
+  logger.filter_level(LevelFilter::Warn).parse_default_env();
+  if let Some(level) = args.log_level() {
+    logger.filter_level(level);
+  }
+  logger.init();
+  let result = match args.command.unwrap_or(Command::Solve) {
+    _ if args.batch => batch(std::io::stdin().lock(), std::io::stdout().lock()),
+    Command::Solve => run(args),
+    Command::Validate => validate(args),
+    Command::Explain => explain(args),
+  };
+  match result {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(failure) => {
+      eprintln!("{}", failure.to_json());
+      ExitCode::from(failure.kind.exit_code())
+    }
+  }
 }
 
-#[cfg(test)]
-mod tests {
-  use std::sync::Once;
+fn run(args: Args) -> Result<(), Failure> {
+  let output_format = args.output_format;
+  let output = args.output.clone();
+  let summary = args.summary;
+  let explain_units = args.explain_units;
+  let debug_dump = args.debug_dump.clone();
+  let verbose_output = args.verbose_output;
+  let echo_input = args.echo_input;
+  let max_switches = args.max_switches;
+  let energy_unit = args.energy_unit;
+  let timezone = args.timezone;
+  let (data, mut config) = data::read_data(args)?;
+  if let Some(unit) = energy_unit {
+    config.energy_unit = unit;
+  }
+  // stderr, the plan might be on stdout
+  if echo_input {
+    data::echo_input(&data, std::io::stderr().lock()).map_err(|e| Failure::io(format!("Unable to echo the input: {e}")))?;
+  }
+  // the solver of the plan itself, written even when it fails
+  let mut dump = debug_dump.as_ref().map(|_| serde_json::Value::Null);
+  let planning = plan_with_dump(&data, &config, dump.as_mut());
+  if let (Some(path), Some(mut dump)) = (debug_dump, dump.filter(|dump| !dump.is_null())) {
+    // the effective configuration, after the overrides, to reproduce the run
+    dump["config"] = serde_json::to_value(&config).expect("Unable to serialize the config");
+    let json = serde_json::to_string_pretty(&dump).expect("Unable to serialize the matrix");
+    std::fs::write(&path, json).map_err(|e| Failure::io(format!("Unable to write {path}: {e}")))?;
+  }
+  let planning = planning?;
+  check_switches(&planning, max_switches)?;
+  let planning = if verbose_output { planning } else { planning.without_inputs() };
+  let mut writer: Box<dyn Write> = match output {
+    Some(filename) => match File::create(&filename) {
+      Ok(file) => Box::new(BufWriter::new(file)),
+      Err(e) => return Err(Failure::io(format!("Unable to create {filename}: {e}"))),
+    },
+    None => Box::new(std::io::stdout().lock()),
+  };
+  let written = if explain_units {
+    write!(writer, "{}", EnergyBalance { config: &config, planning: &planning })
+  } else if summary {
+    write!(writer, "{}", PlanTable { data: &data, config: &config, plans: &planning.plans, timezone })
+  } else {
+    print_output(&data, &config, planning, output_format, timezone, writer)
+  };
+  written.map_err(|e| Failure::io(format!("Unable to write the plan: {e}")))
+}
 
-  static INIT: Once = Once::new();
+// reading the inputs checks them, nothing is solved
+fn validate(args: Args) -> Result<(), Failure> {
+  let (data, config) = data::read_data(args)?;
+  if let (Some(first), Some(last)) = (data.first(), data.last()) {
+    println!(
+      "{} intervals from {} to {}, {} per hour, the inputs and the configuration are valid",
+      data.len(),
+      first.start,
+      last.end,
+      config.intervals_per_hour
+    );
+  }
+  Ok(())
+}
 
-  pub fn init() {
-    INIT.call_once(|| {
-      let _ = env_logger::builder().is_test(true).format_timestamp(None).try_init();
-    });
+// an infeasible plan is what this command is for, it is reported on stdout and is not a failure
+fn explain(args: Args) -> Result<(), Failure> {
+  let (data, config) = data::read_data(args)?;
+  match calculation(&data, &config) {
+    Ok(planning) => println!("There is a plan, it costs {:.2} {}", planning.total_cost, config.currency),
+    Err(SolveError::Infeasible) => match calculation::explain(&data, &config) {
+      Some(infeasibility) => println!("There is no plan, the {infeasibility}"),
+      None => println!("There is no plan, an overload falls where the battery can not cover it"),
+    },
+    Err(error) => return Err(error.into()),
   }
+  Ok(())
 }
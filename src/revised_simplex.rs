@@ -0,0 +1,413 @@
+//! The revised simplex, the same two phases and pivoting rules as `Matrix` on the same tableau. Only the inverse
+//! of the basis is updated by the pivots, the entering column and the rows of the objectives are computed from
+//! the original tableau when needed. The inverse is computed again from scratch every few pivots and between the
+//! phases, the rounding errors of the updates do not pile up over a long run.
+use std::collections::HashSet;
+use std::time::Instant;
+
+use log::{debug, info, log_enabled, warn, Level};
+
+use crate::dual_simplex::{
+  check_structure, Phase, SolveError, SolveStats, StepResult, DEFAULT_STALL_LIMIT, DEFAULT_TOLERANCE, ITERATIONS_PER_DIMENSION,
+};
+
+// pivots between two inversions of the basis
+const REINVERT_INTERVAL: usize = 50;
+
+// a pivot smaller than this makes the basis singular when inverting it
+const SINGULAR: f64 = 1e-12;
+
+#[derive(Debug)]
+pub struct RevisedSimplex {
+  phase: Phase,
+  variables: usize,
+  artificials: usize,
+  stall_limit: usize,
+  bland: bool,
+  tolerance: f64,
+  // the constraint columns of the tableau without the limits, only the rows that are not zero
+  columns: Vec<Vec<(usize, f64)>>,
+  limits: Vec<f64>,
+  // the objective and the intermediate objective rows as built, the limit last
+  objective: Vec<f64>,
+  intermediate: Vec<f64>,
+  // the basic column of every constraint row
+  basis: Vec<usize>,
+  // the inverse of the basic columns, its rows follow the rows of the basis
+  inverse: Vec<Vec<f64>>,
+  // the values of the basic variables, the inverse times the limits
+  values: Vec<f64>,
+  pivots_since_inversion: usize,
+  iterations: usize,
+  iteration_limit: usize,
+  cycled: bool,
+  redundant: Vec<usize>,
+  // the first NaN or infinity of the tableau, solve refuses it
+  not_finite: Option<(usize, usize)>,
+}
+
+impl RevisedSimplex {
+  /// Takes the tableau as `Matrix::new` does. Every constraint row needs a column with a 1 in it and zeros in
+  /// the other rows and the objective, its slack or its artificial, as `build_tableau` and `TableauBuilder` make.
+  pub fn new(data: Vec<Vec<f64>>, variables: usize, artificials: usize) -> Self {
+    Self::with_tolerance(data, variables, artificials, DEFAULT_TOLERANCE)
+  }
+
+  /// Values within `tolerance` of zero are treated as zero, like in `Matrix::with_tolerance`.
+  /// Panics when the tableau is malformed, like `Matrix::new`
+  pub fn with_tolerance(data: Vec<Vec<f64>>, variables: usize, artificials: usize, tolerance: f64) -> Self {
+    if let Err(error) = check_structure(&data, variables, artificials) {
+      panic!("{}", error);
+    }
+    let not_finite = data.iter().enumerate().find_map(|(row, r)| r.iter().position(|v| !v.is_finite()).map(|col| (row, col)));
+    let num_rows = data.len() - 2;
+    let num_cols = data[0].len() - 1;
+    let mut columns = vec![Vec::new(); num_cols];
+    for (row, r) in data[..num_rows].iter().enumerate() {
+      for (col, &value) in r[..num_cols].iter().enumerate() {
+        if value != 0.0 {
+          columns[col].push((row, value));
+        }
+      }
+    }
+    // the slacks and the artificials come last, the unit column found from the end is one of them
+    let objective = data[num_rows].clone();
+    let basis: Vec<usize> = (0..num_rows)
+      .map(|row| {
+        (0..num_cols)
+          .rev()
+          .find(|&col| columns[col] == [(row, 1.0)] && objective[col] == 0.0)
+          .expect("every constraint row has a slack or an artificial")
+      })
+      .collect();
+    let inverse = (0..num_rows).map(|row| (0..num_rows).map(|i| if i == row { 1.0 } else { 0.0 }).collect()).collect();
+    let limits: Vec<f64> = data[..num_rows].iter().map(|r| r[num_cols]).collect();
+    RevisedSimplex {
+      phase: Phase::One,
+      variables,
+      artificials,
+      stall_limit: DEFAULT_STALL_LIMIT,
+      bland: false,
+      tolerance,
+      columns,
+      values: limits.clone(),
+      limits,
+      objective,
+      intermediate: data[num_rows + 1].clone(),
+      basis,
+      inverse,
+      pivots_since_inversion: 0,
+      iterations: 0,
+      iteration_limit: ITERATIONS_PER_DIMENSION.saturating_mul(data.len() + num_cols + 1),
+      cycled: false,
+      redundant: Vec::new(),
+      not_finite,
+    }
+  }
+
+  /// Sets how many consecutive pivots without improving the objective are tolerated before Bland's rule
+  pub fn set_stall_limit(&mut self, stall_limit: usize) {
+    self.stall_limit = stall_limit;
+  }
+
+  /// Sets how many pivots one call of `solve` can make, 20 times the rows and the columns by default
+  pub fn set_iteration_limit(&mut self, iteration_limit: usize) {
+    self.iteration_limit = iteration_limit;
+  }
+
+  pub fn phase_two(&mut self) {
+    debug!("Switching to phase two");
+    self.reinvert();
+    self.drive_out_artificials();
+    self.phase = Phase::Two;
+  }
+
+  // an artificial left in the basis at zero is swapped for any other column of its row, see `Matrix`
+  fn drive_out_artificials(&mut self) {
+    let first_artificial = self.columns.len() - self.artificials;
+    for row in 0..self.basis.len() {
+      if self.basis[row] >= first_artificial {
+        match (0..first_artificial).find(|&col| self.row_entry(row, col).abs() > self.tolerance) {
+          Some(col) => {
+            debug!("Artificial still basic in row {row}, replacing it with column {col}");
+            self.pivot(row, col, &self.column(col));
+          }
+          None => {
+            debug!("Row {row} is redundant");
+            self.redundant.push(row);
+          }
+        }
+      }
+    }
+  }
+
+  // the row of the objective minimised in the current phase
+  fn costs(&self) -> &[f64] {
+    match self.phase {
+      Phase::One => &self.intermediate,
+      Phase::Two => &self.objective,
+    }
+  }
+
+  // the value of the objective we are currently minimising, the limit of its row once pivoted
+  fn current_objective(&self) -> f64 {
+    let costs = self.costs();
+    costs[self.columns.len()] - self.basis.iter().zip(&self.values).map(|(&col, value)| costs[col] * value).sum::<f64>()
+  }
+
+  // the entering column is pivoted in the tableau as the inverse times the original column
+  fn column(&self, col: usize) -> Vec<f64> {
+    self.inverse.iter().map(|inverse| self.columns[col].iter().map(|&(row, value)| inverse[row] * value).sum()).collect()
+  }
+
+  // a single entry of the pivoted tableau
+  fn row_entry(&self, row: usize, col: usize) -> f64 {
+    self.columns[col].iter().map(|&(i, value)| self.inverse[row][i] * value).sum()
+  }
+
+  // the entering column, the most positive entry of the pivoted objective row or the first positive with Bland's rule
+  fn entering(&self) -> Option<usize> {
+    let costs = self.costs();
+    let num_rows = self.basis.len();
+    // the prices of the rows, the objective row is the original one less these times the constraint rows
+    let prices: Vec<f64> =
+      (0..num_rows).map(|i| self.basis.iter().enumerate().map(|(row, &col)| costs[col] * self.inverse[row][i]).sum()).collect();
+    let limit = match self.phase {
+      Phase::One => self.columns.len(),
+      Phase::Two => self.columns.len() - self.artificials,
+    };
+    let mut basic = vec![false; self.columns.len()];
+    self.basis.iter().for_each(|&col| basic[col] = true);
+    let mut found: Option<(usize, f64)> = None;
+    for col in (0..limit).filter(|&col| !basic[col]) {
+      let reduced = costs[col] - self.columns[col].iter().map(|&(row, value)| prices[row] * value).sum::<f64>();
+      if reduced > self.tolerance {
+        if self.bland {
+          return Some(col);
+        }
+        if found.is_none_or(|(_, best)| reduced > best + self.tolerance) {
+          found = Some((col, reduced));
+        }
+      }
+    }
+    found.map(|(col, _)| col)
+  }
+
+  // the ratio test on the entering column, the same rules as `Matrix`
+  fn leaving(&self, column: &[f64]) -> Result<Option<usize>, SolveError> {
+    let mut pivot: Option<(usize, f64)> = None;
+    for (row, (&a, &b)) in column.iter().zip(&self.values).enumerate() {
+      // the values are computed again and can be a rounding below zero
+      if a > self.tolerance && b >= -self.tolerance {
+        let ratio = b.max(0.0) / a;
+        pivot = match pivot {
+          Some((_, val)) if ratio < val => Some((row, ratio)),
+          Some((current, val)) if ratio == val && self.bland && self.basis[row] < self.basis[current] => Some((row, ratio)),
+          None => Some((row, ratio)),
+          _ => pivot,
+        };
+      }
+    }
+    if pivot.is_none() && column.iter().all(|&a| a <= self.tolerance) {
+      return Err(SolveError::Unbounded);
+    }
+    Ok(pivot.map(|(row, _)| row))
+  }
+
+  fn pivot(&mut self, pivot_row: usize, col: usize, column: &[f64]) {
+    debug!("Pivoting on {:?}", (pivot_row, col));
+    let pivot_val = column[pivot_row];
+    self.inverse[pivot_row].iter_mut().for_each(|v| *v /= pivot_val);
+    self.values[pivot_row] /= pivot_val;
+    let pivot_inverse = self.inverse[pivot_row].clone();
+    for (row, &ratio) in column.iter().enumerate() {
+      if row != pivot_row && ratio != 0.0 {
+        self.inverse[row].iter_mut().zip(&pivot_inverse).for_each(|(v, p)| *v -= ratio * p);
+        self.values[row] -= ratio * self.values[pivot_row];
+      }
+    }
+    self.basis[pivot_row] = col;
+    self.pivots_since_inversion += 1;
+    if self.pivots_since_inversion >= REINVERT_INTERVAL {
+      self.reinvert();
+    }
+  }
+
+  // inverts the basic columns from scratch with Gauss-Jordan, the inverse is kept when they look singular
+  fn reinvert(&mut self) {
+    self.pivots_since_inversion = 0;
+    let num_rows = self.basis.len();
+    // the basic columns side by side, row major, next to the identity
+    let mut basis = vec![vec![0.0; num_rows]; num_rows];
+    for (position, &col) in self.basis.iter().enumerate() {
+      for &(row, value) in &self.columns[col] {
+        basis[row][position] = value;
+      }
+    }
+    let mut inverse: Vec<Vec<f64>> =
+      (0..num_rows).map(|row| (0..num_rows).map(|i| if i == row { 1.0 } else { 0.0 }).collect()).collect();
+    for position in 0..num_rows {
+      let Some(pivot) = (position..num_rows).max_by(|&a, &b| basis[a][position].abs().total_cmp(&basis[b][position].abs()))
+      else {
+        return;
+      };
+      if basis[pivot][position].abs() < SINGULAR {
+        warn!("The basis looks singular, keeping the updated inverse");
+        return;
+      }
+      basis.swap(position, pivot);
+      inverse.swap(position, pivot);
+      let pivot_val = basis[position][position];
+      basis[position].iter_mut().for_each(|v| *v /= pivot_val);
+      inverse[position].iter_mut().for_each(|v| *v /= pivot_val);
+      let (pivot_basis, pivot_inverse) = (basis[position].clone(), inverse[position].clone());
+      for row in (0..num_rows).filter(|&row| row != position) {
+        let ratio = basis[row][position];
+        if ratio != 0.0 {
+          basis[row].iter_mut().zip(&pivot_basis).for_each(|(v, p)| *v -= ratio * p);
+          inverse[row].iter_mut().zip(&pivot_inverse).for_each(|(v, p)| *v -= ratio * p);
+        }
+      }
+    }
+    self.values = inverse.iter().map(|inverse| inverse.iter().zip(&self.limits).map(|(a, b)| a * b).sum()).collect();
+    self.inverse = inverse;
+  }
+
+  /// Pivots until the objective of the current phase can not improve, returns the number of pivots made
+  pub fn solve(&mut self) -> Result<usize, SolveError> {
+    if let Some((row, col)) = self.not_finite {
+      return Err(SolveError::NotFinite { row, col });
+    }
+    self.bland = false;
+    let mut stalled = 0;
+    let started = log_enabled!(Level::Info).then(Instant::now);
+    let watch_from = self.iteration_limit - self.iteration_limit / 10;
+    let mut seen_bases: HashSet<Vec<usize>> = HashSet::new();
+    for iteration in 0..self.iteration_limit {
+      if iteration >= watch_from {
+        if !self.cycled && seen_bases.contains(&self.basis) {
+          warn!("Basis {:?} repeats after {} iterations, the pivoting cycles", self.basis, iteration);
+          self.cycled = true;
+        }
+        seen_bases.insert(self.basis.clone());
+      }
+      let before = self.current_objective();
+      let solved = match self.step() {
+        StepResult::Pivoted(_) => {
+          if self.current_objective() < before {
+            stalled = 0;
+          } else {
+            stalled += 1;
+            if !self.bland && stalled >= self.stall_limit {
+              debug!("No improvement after {stalled} pivots, switching to Bland's rule");
+              self.bland = true;
+              self.cycled = true;
+            }
+          }
+          continue;
+        }
+        StepResult::Unbounded => return Err(SolveError::Unbounded),
+        StepResult::Optimal => true,
+        StepResult::Infeasible => false,
+      };
+      self.reinvert();
+      if let Some(started) = started {
+        info!(
+          "Phase {:?} {} after {} pivots in {:?}, objective {}",
+          self.phase,
+          if solved { "solved" } else { "infeasible" },
+          iteration,
+          started.elapsed(),
+          self.current_objective()
+        );
+      }
+      return if solved { Ok(iteration) } else { Err(SolveError::Infeasible) };
+    }
+    Err(SolveError::IterationLimit(self.iteration_limit))
+  }
+
+  /// Makes one pivot of the current phase, or tells why there is none to make, like `Matrix::step`
+  pub fn step(&mut self) -> StepResult {
+    let Some(col) = self.entering() else {
+      return if self.is_feasible() { StepResult::Optimal } else { StepResult::Infeasible };
+    };
+    let column = self.column(col);
+    match self.leaving(&column) {
+      Ok(Some(row)) => {
+        self.pivot(row, col, &column);
+        self.iterations += 1;
+        StepResult::Pivoted((row, col))
+      }
+      Ok(None) if self.is_feasible() => StepResult::Optimal,
+      Ok(None) => StepResult::Infeasible,
+      Err(_) => StepResult::Unbounded,
+    }
+  }
+
+  // phase one is done when the intermediate objective reached zero
+  fn is_feasible(&self) -> bool {
+    self.phase == Phase::Two || self.current_objective().abs() < self.tolerance
+  }
+
+  /// Runs both phases and reports how many pivots they took
+  pub fn solve_with_stats(&mut self) -> Result<SolveStats, SolveError> {
+    let phase_one_iterations = self.solve()?;
+    self.phase_two();
+    let phase_two_iterations = self.solve()?;
+    Ok(SolveStats { iterations: phase_one_iterations + phase_two_iterations, phase_one_iterations, cycled: self.cycled })
+  }
+
+  /// The constraint rows found redundant when switching to phase two
+  pub fn redundant_rows(&self) -> &[usize] {
+    &self.redundant
+  }
+
+  /// The basic column of every constraint row, in row order
+  pub fn get_basis(&self) -> Vec<usize> {
+    self.basis.clone()
+  }
+
+  /// The values of the variables, like `Matrix::get_solution`
+  pub fn get_solution(&self) -> Vec<f64> {
+    let mut solution = vec![0.0; self.variables];
+    for (&col, &value) in self.basis.iter().zip(&self.values) {
+      if col < self.variables {
+        solution[col] = value;
+      }
+    }
+    solution
+  }
+
+  /// The values of the slack/surplus variables, like `Matrix::get_slacks`
+  pub fn get_slacks(&self) -> Vec<f64> {
+    let slacks = self.variables..self.columns.len() - self.artificials;
+    let mut values = vec![0.0; slacks.len()];
+    for (&col, &value) in self.basis.iter().zip(&self.values) {
+      if slacks.contains(&col) {
+        values[col - slacks.start] = value;
+      }
+    }
+    values
+  }
+
+  /// Value of the objective function at the optimum, like `Matrix::get_objective_value`
+  pub fn get_objective_value(&self) -> f64 {
+    self.objective[self.columns.len()]
+      - self.basis.iter().zip(&self.values).map(|(&col, value)| self.objective[col] * value).sum::<f64>()
+  }
+
+  /// The state of the solver like `Matrix::to_debug_json`, with the inverse of the basis and the basic values
+  /// in place of the tableau
+  pub fn to_debug_json(&self) -> serde_json::Value {
+    serde_json::json!({
+      "inverse": self.inverse,
+      "values": self.values,
+      "variables": self.variables,
+      "artificials": self.artificials,
+      "phase": format!("{:?}", self.phase),
+      "basis": self.get_basis(),
+      "solution": self.get_solution(),
+      "objective_value": self.get_objective_value(),
+    })
+  }
+}
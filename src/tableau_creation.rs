@@ -1,33 +1,155 @@
 use log::debug;
+use std::cmp::Ordering;
 
-use crate::data::{Config, Data};
+use crate::{
+  data::{Battery, Config, Data, Objective},
+  dual_simplex::Matrix,
+};
 
 /// Creates the tableau for the dual simplex minimization algorithm
+/// The variables are the charge of the battery for the underload intervals followed, when energy can be sold,
+/// by the energy exported from the battery and the energy it gives to the consumers for every interval, when
+/// minimising the peak, by the peak power taken from the grid and, when it has a penalty, by the shortfall of the final charge.
 /// The tableau is a matrix with the following structure:
 /// 1. loading constraints for max battery charge and max power
-/// 2. loading constraints for the battery capacity
-/// 3. constraints for the battery discharge, needs to compensate for the overload
-/// 4. loading constraints for the final battery value
-/// 5. price optimization
-/// 6. intermediate goal (required because 5. has artificial variables)
+/// 2. loading constraints for the battery capacity, which can change from one interval to the next
+/// 3. constraints for the battery discharge, needs to compensate for the overload and keep the minimum charge.
+///    With exports or a minimum charge this is needed for every interval
+/// 4. loading constraints for the final battery value, the shortfall makes up for what is missing
+/// 5. limit of the export, the max battery discharge left after the overload or else the max battery charge,
+///    and the battery gives the consumers at most what they take from the grid
+/// 6. the grid power of every interval is below the peak, only when minimising the peak or paying for it
+/// 7. the grid power of every interval is at least the minimum grid power, only when there is one
+/// 8. the energy taken from the grid over all the intervals is at most the daily import quota, when there is one
+/// 9. the charge of an interval with a forced charge is at least the forced charge, 1. keeps it at most that
+/// 10. the charges of two consecutive underload intervals differ by at most the ramp, when there is one
+/// 11. price or peak optimization
+/// 12. intermediate goal (required because 11. has artificial variables)
+///
+/// A forced discharge is planned like an overload, the grid limit of its interval is what it leaves of the consumption.
+///
+/// The energy model is the same in every block: of the energy taken from the grid to charge the battery
+/// `charge_efficiency` is stored, the overloads and the exports take from the battery what they deliver
+/// divided by `discharge_efficiency`. The deprecated round trip `battery_efficiency` is the charge efficiency
+/// with lossless discharging, the loss is never counted twice.
+///
+/// When energy can be sold, a feed-in price or a sell price in the prices, the market is two sided: the energy
+/// taken from the grid costs the buy price and the energy sold earns the sell price of its interval. What the
+/// battery gives the consumers outside an overload saves the buy price.
 ///
 pub fn build_tableau(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, usize) {
-  // the battery capacity is per hour so it will become per quarter by multiplying by 4
-  let b0 = config.battery_initial_charge * 4.0; // instead of MWh we have MW15minutes
-  let b_max = config.battery_capacity * 4.0;
-  let b_final = config.battery_final_charge * 4.0;
-  debug!("b0: {b0}");
+  let (tableau, variables, artificials, _) = build_tableau_with_kinds(data, config);
+  (tableau, variables, artificials)
+}
+
+/// The block of the tableau a constraint row comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConstraintKind {
+  MaxCharge,
+  Capacity,
+  Discharge,
+  FinalCharge,
+  ExportLimit,
+  Peak,
+  MinGrid,
+  ImportQuota,
+  /// the battery gives the consumers at most what they take, only when energy can be sold
+  OwnUse,
+  /// the batteries together cover the overload, only with several batteries
+  Overload,
+  /// a battery discharges at most at its rate, only with several batteries or the self consumption
+  DischargeRate,
+  /// the grid gives at most its limit, only with the self consumption
+  GridLimit,
+  /// what the grid gives and takes, the battery and the site add up, only with the self consumption
+  Balance,
+  /// the battery charges at least its forced charge, the max charge keeps it from charging more
+  ForcedCharge,
+  /// the charge changes at most by max_ramp_per_interval from the interval before, two rows for every pair
+  Ramp,
+}
+
+/// The kind of a constraint row and the interval it belongs to
+pub type Row = (ConstraintKind, usize);
+
+/// Same as `build_tableau`, also returns the kind of every constraint row, in row order
+pub fn build_tableau_with_kinds(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, usize, Vec<ConstraintKind>) {
+  let (tableau, variables, artificials, rows) = build_tableau_with_rows(data, config);
+  (tableau, variables, artificials, rows.into_iter().map(|(kind, _)| kind).collect())
+}
+
+/// Same as `build_tableau_with_kinds`, with the interval of every constraint row next to its kind.
+/// The rows over the whole plan, the final charge and the import quota, are in the last interval
+pub fn build_tableau_with_rows(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, usize, Vec<Row>) {
+  if !config.batteries.is_empty() {
+    return build_batteries_tableau(data, config, &config.batteries, false);
+  }
+  if !config.efficiency_segments.is_empty() {
+    return build_batteries_tableau(data, config, &config.segment_batteries(), true);
+  }
+  if config.objective == Objective::MaxSelfConsumption {
+    return build_self_consumption_tableau(data, config);
+  }
+  let with_reserve = data.iter().any(|d| d.min_charge(config) > 0.0);
+  let limits = constraint_limits(data, config);
+  // the battery holds less than it gets when charging and gives less than it holds when discharging
+  let charge_efficiency = config.charge_efficiency();
+  let discharge_efficiency = config.discharge_efficiency();
+  // the battery loses a part of its charge every interval, what was stored earlier is worth less
+  let decay = config.decay_per_interval();
+  let fade = |intervals: usize| decay.powi(intervals as i32);
 
-  let count_vars = data.iter().filter(|d| d.power <= config.max_consumption).count();
+  let count_vars = data.iter().filter(|d| !d.is_overload(config)).count();
   let count_over = data.len() - count_vars;
+  let count_export = count_exports(data, config);
+  let with_peak = config.objective == Objective::MinimisePeak || config.demand_charge_per_kw > 0.0;
+  let count_peak = if with_peak { data.len() } else { 0 };
+  let count_min_grid = if config.min_grid_power > 0.0 { data.len() } else { 0 };
+  let count_quota = usize::from(config.daily_import_quota_wh.is_some());
+  let count_forced = data.iter().filter(|d| !d.is_overload(config) && d.forced_charge.is_some()).count();
+  let ramps = ramp_pairs(data, config);
+  let count_ramp = 2 * ramps.len();
+  // the discharge to the consumers follows the exports, one for every interval as well
+  let own_use = count_vars + count_export;
+  // the peak is the last variable
+  let peak = own_use + count_export;
+  // the shortfall of the final charge is the last variable, when it has a penalty
+  let shortfall = peak + usize::from(count_peak > 0);
+  let num_vars = shortfall + usize::from(config.final_charge_penalty.is_some());
+  let count_discharge = if count_export > 0 || with_reserve { data.len() } else { count_over };
+  // the interval of every x
+  let x_intervals: Vec<usize> = data.iter().enumerate().filter(|(_, d)| !d.is_overload(config)).map(|(i, _)| i).collect();
   // we have two criteria, optimisation and feasibility
-  let rows = 2 * count_vars + count_over + 1 + 2;
+  let rows = 2 * count_vars
+    + count_discharge
+    + 1
+    + 2 * count_export
+    + count_peak
+    + count_min_grid
+    + count_quota
+    + count_forced
+    + count_ramp
+    + 2;
   // we get an s per equation. For each underload interval 2 equations (max power and max battery)
   // for each overload 1 equation (need enough juice in the battery)
   // one equation for final value of the battery
-  let num_s = 2 * count_vars + count_over + 1;
-  let num_max_a = count_over + 1;
-  let cols = count_vars + num_s + num_max_a + 1;
+  // for each export 1 equation for its limit and 1 for the discharge to the consumers
+  // for each interval 1 equation for the peak, when minimising it
+  // for each interval 1 equation for the minimum grid power, when there is one
+  // one equation for the import quota, when there is one
+  // for each forced charge 1 equation for its minimum
+  // for each pair of consecutive charges 2 equations for the ramp, up and down
+  let num_s = 2 * count_vars
+    + count_discharge
+    + 1
+    + 2 * count_export
+    + count_peak
+    + count_min_grid
+    + count_quota
+    + count_forced
+    + count_ramp;
+  let num_max_a = count_vars + count_discharge + 1 + count_peak + count_min_grid + count_quota + count_forced;
+  let cols = num_vars + num_s + num_max_a + 1;
   debug!("rows: {}, cols: {}", cols, rows);
   let negate = |v: &mut [f64]| {
     for z in v.iter_mut() {
@@ -36,14 +158,21 @@ pub fn build_tableau(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, u
       }
     }
   };
+  // the rows with an artificial variable are added to the intermediate goal
+  let add_to = |intermediate: &mut [f64], equation: &[f64]| {
+    for (t, v) in intermediate.iter_mut().zip(equation) {
+      *t += v;
+    }
+  };
   let mut result: Vec<Vec<f64>> = Vec::with_capacity(rows);
+  let mut kinds: Vec<Row> = Vec::with_capacity(rows - 2);
   // equations for limiting the charge
   let mut line_count = 0;
   let mut x_vs_interval_offset = 0;
-  let mut a_offset = count_vars + num_s;
+  let mut a_offset = num_vars + num_s;
   // equation for max power charge.
   for (i, d) in data.iter().enumerate() {
-    if d.power >= config.max_consumption {
+    if d.is_overload(config) {
       x_vs_interval_offset += 1;
       continue;
     }
@@ -51,35 +180,40 @@ pub fn build_tableau(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, u
     // the x
     equation[i - x_vs_interval_offset] = 1.0;
     // the s
-    equation[count_vars + line_count] = 1.0;
+    equation[num_vars + line_count] = 1.0;
     line_count += 1;
     // the limit
-    equation[cols - 1] = config.battery_max_charge.min(config.max_consumption - d.power);
+    equation[cols - 1] = limits[result.len()];
     result.push(equation);
+    kinds.push((ConstraintKind::MaxCharge, i));
   }
   let mut intermediate: Vec<f64> = vec![0.0; cols];
   // equations for the limit of the battery capacity
   let mut x_vs_interval_offset = 0;
-  let mut discharge = 0.0;
   for (i, d) in data.iter().enumerate() {
-    if d.power >= config.max_consumption {
+    if d.is_overload(config) {
       x_vs_interval_offset += 1;
-      discharge += d.power - config.max_consumption;
       continue;
     }
     let mut equation: Vec<f64> = vec![0.0; cols];
     // the x
     #[allow(clippy::needless_range_loop)]
     for col in 0..i - x_vs_interval_offset + 1 {
-      equation[col] = config.battery_efficiency;
+      equation[col] = charge_efficiency * fade(i - x_intervals[col]);
+    }
+    // the exports and the discharges to the consumers so far
+    for j in 0..(i + 1).min(count_export) {
+      equation[count_vars + j] = -fade(i - j) / discharge_efficiency;
+      equation[own_use + j] = -fade(i - j) / discharge_efficiency;
     }
     // the s
-    equation[count_vars + line_count] = 1.0;
+    equation[num_vars + line_count] = 1.0;
     // the limit
-    let limit = b_max + discharge - b0;
+    let limit = limits[result.len()];
     equation[cols - 1] = limit;
-    if limit < 0.0 {
+    if is_negated(ConstraintKind::Capacity, limit) {
       negate(&mut equation);
+      add_to(&mut intermediate, &equation);
       // set the a
       debug!("a_offset: {}", a_offset);
       equation[a_offset] = 1.0;
@@ -87,83 +221,250 @@ pub fn build_tableau(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, u
     }
     line_count += 1;
     result.push(equation);
+    kinds.push((ConstraintKind::Capacity, i));
   }
 
   // equations for discharging
   // we'll build the intermediate goal at the same time as it is a running sum
   let mut x_vs_interval_offset = 0;
-  let mut discharge = 0.0;
   for (i, d) in data.iter().enumerate() {
-    if d.power >= config.max_consumption {
+    let overload = d.is_overload(config);
+    if overload {
       x_vs_interval_offset += 1;
-      discharge += d.power - config.max_consumption;
-      let limit = discharge - b0;
+    }
+    if overload || count_discharge == data.len() {
+      let limit = limits[result.len()];
       let mut equation: Vec<f64> = vec![0.0; cols];
 
-      // the x
-      for j in 0..i - x_vs_interval_offset + 1 {
-        equation[j] = config.battery_efficiency;
-        if limit >= 0.0 {
-          intermediate[j] += equation[j];
-        };
+      // the x, the charges before this interval, none when every interval so far was an overload
+      #[allow(clippy::needless_range_loop)]
+      for j in 0..i + 1 - x_vs_interval_offset {
+        equation[j] = charge_efficiency * fade(i - x_intervals[j]);
+      }
+      // the exports and the discharges to the consumers so far
+      for j in 0..(i + 1).min(count_export) {
+        equation[count_vars + j] = -fade(i - j) / discharge_efficiency;
+        equation[own_use + j] = -fade(i - j) / discharge_efficiency;
       }
       // the s
-      equation[count_vars + line_count] = -1.0;
+      equation[num_vars + line_count] = -1.0;
       // the limit
       equation[cols - 1] = limit;
-      if limit < 0.0 {
+      if is_negated(ConstraintKind::Discharge, limit) {
         negate(&mut equation);
       } else {
+        add_to(&mut intermediate, &equation);
         // set the a
         equation[a_offset] = 1.0;
         a_offset += 1;
-        intermediate[count_vars + line_count] = -1.0;
-        intermediate[cols - 1] += limit;
       }
       line_count += 1;
       result.push(equation);
+      kinds.push((ConstraintKind::Discharge, i));
     }
   }
 
   // equation for the final battery value
-  // b0 + sum(efficiency * xi) - sum(overload) >= b_final
-  let limit = b_final - b0 + discharge;
+  // b0 + sum(charge_efficiency * xi) - sum(overload + exports) / discharge_efficiency >= b_final
+  // every term faded by the self discharge until the end
+  let last = data.len().saturating_sub(1);
+  let limit = limits[result.len()];
   let mut equation: Vec<f64> = vec![0.0; cols];
+  #[allow(clippy::needless_range_loop)]
   for i in 0..count_vars {
-    equation[i] = config.battery_efficiency;
-    if limit >= 0.0 {
-      intermediate[i] += config.battery_efficiency;
-    }
+    equation[i] = charge_efficiency * fade(last - x_intervals[i]);
   }
-  if limit >= 0.0 {
+  for j in 0..count_export {
+    equation[count_vars + j] = -fade(last - j) / discharge_efficiency;
+    equation[own_use + j] = -fade(last - j) / discharge_efficiency;
+  }
+  if config.final_charge_penalty.is_some() {
+    equation[shortfall] = 1.0;
+  }
+  if !is_negated(ConstraintKind::FinalCharge, limit) {
     // the s
-    equation[count_vars + line_count] = -1.0;
-    intermediate[count_vars + line_count] = -1.0;
+    equation[num_vars + line_count] = -1.0;
+    // the limit
+    equation[cols - 1] = limit;
+    add_to(&mut intermediate, &equation);
     // the a
     equation[a_offset] = 1.0;
     a_offset += 1;
-    // the limit
-    equation[cols - 1] = limit;
-    intermediate[cols - 1] += limit;
   } else {
     negate(&mut equation);
     // the s
-    equation[count_vars + line_count] = 1.0;
+    equation[num_vars + line_count] = 1.0;
     // the limit
     equation[cols - 1] = -limit;
   }
+  line_count += 1;
   result.push(equation);
+  kinds.push((ConstraintKind::FinalCharge, last));
+
+  // equations for the export limit, the discharge to the consumers comes from the same battery
+  for j in 0..count_export {
+    let mut equation: Vec<f64> = vec![0.0; cols];
+    // the e and the u
+    equation[count_vars + j] = 1.0;
+    equation[own_use + j] = 1.0;
+    // the s
+    equation[num_vars + line_count] = 1.0;
+    line_count += 1;
+    // the limit
+    equation[cols - 1] = limits[result.len()];
+    result.push(equation);
+    kinds.push((ConstraintKind::ExportLimit, j));
+  }
 
-  // price, the optimization function
+  // equations for the discharge to the consumers, at most what they take from the grid, the overload is covered already
+  for j in 0..count_export {
+    let mut equation: Vec<f64> = vec![0.0; cols];
+    // the u
+    equation[own_use + j] = 1.0;
+    // the s
+    equation[num_vars + line_count] = 1.0;
+    line_count += 1;
+    // the limit
+    equation[cols - 1] = limits[result.len()];
+    result.push(equation);
+    kinds.push((ConstraintKind::OwnUse, j));
+  }
+
+  // equations for the peak, the grid power of every interval is at most the peak
+  // underload: power + x <= peak, overload: the battery covers everything above the limit
+  let mut x_vs_interval_offset = 0;
+  for (i, d) in data.iter().enumerate().take(count_peak) {
+    let mut equation: Vec<f64> = vec![0.0; cols];
+    equation[peak] = 1.0;
+    if d.is_overload(config) {
+      x_vs_interval_offset += 1;
+    } else {
+      equation[i - x_vs_interval_offset] = -1.0;
+    }
+    equation[cols - 1] = limits[result.len()];
+    // the s
+    equation[num_vars + line_count] = -1.0;
+    line_count += 1;
+    add_to(&mut intermediate, &equation);
+    // the a
+    equation[a_offset] = 1.0;
+    a_offset += 1;
+    result.push(equation);
+    kinds.push((ConstraintKind::Peak, i));
+  }
+
+  // equations for the minimum grid power
+  // underload: power + x - e - u >= min_grid_power, overload: the grid gives the limit, less the exports
+  let mut x_vs_interval_offset = 0;
+  for (i, d) in data.iter().enumerate().take(count_min_grid) {
+    let mut equation: Vec<f64> = vec![0.0; cols];
+    if d.is_overload(config) {
+      x_vs_interval_offset += 1;
+    } else {
+      equation[i - x_vs_interval_offset] = 1.0;
+    }
+    if count_export > 0 {
+      equation[count_vars + i] = -1.0;
+      equation[own_use + i] = -1.0;
+    }
+    let limit = limits[result.len()];
+    if !is_negated(ConstraintKind::MinGrid, limit) {
+      // the s
+      equation[num_vars + line_count] = -1.0;
+      // the limit
+      equation[cols - 1] = limit;
+      add_to(&mut intermediate, &equation);
+      // the a
+      equation[a_offset] = 1.0;
+      a_offset += 1;
+    } else {
+      negate(&mut equation);
+      // the s
+      equation[num_vars + line_count] = 1.0;
+      // the limit
+      equation[cols - 1] = -limit;
+    }
+    line_count += 1;
+    result.push(equation);
+    kinds.push((ConstraintKind::MinGrid, i));
+  }
+
+  // equation for the import quota, what the grid gives over all the intervals
+  // sum(min(power, limit)) + sum(x) - sum(e) - sum(u) <= quota, the overloads are covered by the battery
+  if count_quota > 0 {
+    let mut equation: Vec<f64> = vec![0.0; cols];
+    for x in equation.iter_mut().take(count_vars) {
+      *x = 1.0;
+    }
+    for e in equation.iter_mut().skip(count_vars).take(2 * count_export) {
+      *e = -1.0;
+    }
+    let limit = limits[result.len()];
+    if !is_negated(ConstraintKind::ImportQuota, limit) {
+      // the s
+      equation[num_vars + line_count] = 1.0;
+      // the limit
+      equation[cols - 1] = limit;
+    } else {
+      negate(&mut equation);
+      // the s
+      equation[num_vars + line_count] = -1.0;
+      // the limit
+      equation[cols - 1] = -limit;
+      add_to(&mut intermediate, &equation);
+      // the a
+      equation[a_offset] = 1.0;
+      a_offset += 1;
+    }
+    line_count += 1;
+    result.push(equation);
+    kinds.push((ConstraintKind::ImportQuota, data.len().saturating_sub(1)));
+  }
+
+  // equations for the forced charges, x >= forced, the max charge row already keeps x at most the forced charge
   let mut x_vs_interval_offset = 0;
-  let mut equation: Vec<f64> = vec![0.0; cols];
   for (i, d) in data.iter().enumerate() {
-    if d.power >= config.max_consumption {
+    if d.is_overload(config) {
       x_vs_interval_offset += 1;
       continue;
     }
-    equation[i - x_vs_interval_offset] = -d.price;
+    if d.forced_charge.is_none() {
+      continue;
+    }
+    let mut equation: Vec<f64> = vec![0.0; cols];
+    equation[i - x_vs_interval_offset] = 1.0;
+    // the s
+    equation[num_vars + line_count] = -1.0;
+    line_count += 1;
+    // the limit
+    equation[cols - 1] = limits[result.len()];
+    add_to(&mut intermediate, &equation);
+    // the a
+    equation[a_offset] = 1.0;
+    a_offset += 1;
+    result.push(equation);
+    kinds.push((ConstraintKind::ForcedCharge, i));
+  }
+
+  // equations for the ramp, x_k - x_k-1 <= ramp and x_k-1 - x_k <= ramp, the limit is never negative
+  for &k in &ramps {
+    for direction in [1.0, -1.0] {
+      let mut equation: Vec<f64> = vec![0.0; cols];
+      equation[k] = direction;
+      equation[k - 1] = -direction;
+      // the s
+      equation[num_vars + line_count] = 1.0;
+      line_count += 1;
+      // the limit
+      equation[cols - 1] = limits[result.len()];
+      result.push(equation);
+      kinds.push((ConstraintKind::Ramp, x_intervals[k]));
+    }
   }
+
+  // the optimization function
+  let mut equation: Vec<f64> = vec![0.0; cols];
+  equation[..num_vars].copy_from_slice(&objective(data, config));
   result.push(equation);
   result.push(intermediate);
 
@@ -172,7 +473,513 @@ pub fn build_tableau(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, u
     r[a_offset] = r[cols - 1];
     r.truncate(a_offset + 1);
   }
-  (result, count_vars, a_offset - count_vars - num_s)
+  (result, num_vars, a_offset - num_vars - num_s, kinds)
+}
+
+// The coefficients of the variables in the objective row of the single battery, the other columns are zero
+fn objective(data: &[Data], config: &Config) -> Vec<f64> {
+  let count_vars = data.iter().filter(|d| !d.is_overload(config)).count();
+  let count_export = count_exports(data, config);
+  let with_peak = config.objective == Objective::MinimisePeak || config.demand_charge_per_kw > 0.0;
+  let own_use = count_vars + count_export;
+  let peak = own_use + count_export;
+  let shortfall = peak + usize::from(with_peak);
+  let mut equation: Vec<f64> = vec![0.0; shortfall + usize::from(config.final_charge_penalty.is_some())];
+  match config.objective {
+    Objective::MinimiseCost => {
+      let mut x_vs_interval_offset = 0;
+      for (i, d) in data.iter().enumerate() {
+        if d.is_overload(config) {
+          x_vs_interval_offset += 1;
+          continue;
+        }
+        // charging wears the battery, that costs on top of the price
+        equation[i - x_vs_interval_offset] = -(config.grid_price(d.price) + config.cycle_cost_per_kwh);
+      }
+      // the exports earn the sell price, a negative cost, the discharge to the consumers saves the buy price
+      for (j, d) in data.iter().enumerate().take(count_export) {
+        equation[count_vars + j] = d.sell_price(config).unwrap_or(0.0);
+        equation[own_use + j] = config.grid_price(d.price);
+      }
+      // the costs are price times power per interval, the demand charge is paid once for the peak.
+      // Everything gets divided by the intervals in an hour later, the demand charge must not be.
+      if with_peak {
+        equation[peak] = -config.demand_charge_per_kw * config.intervals_per_hour;
+      }
+    }
+    Objective::MinimisePeak => equation[peak] = -1.0,
+    // planned by build_self_consumption_tableau
+    Objective::MaxSelfConsumption => (),
+  }
+  // whatever is optimised, missing the final charge costs the penalty
+  if let Some(final_charge_penalty) = config.final_charge_penalty {
+    equation[shortfall] = -final_charge_penalty;
+  }
+  equation
+}
+
+// The limit of every constraint row of the single battery, in row order, as it is before the row is negated.
+// The limits are what changes with the consumption, the prices aside, the coefficients follow the battery.
+fn constraint_limits(data: &[Data], config: &Config) -> Vec<f64> {
+  let iph = config.intervals_per_hour;
+  // the battery capacity is per hour so it will become per interval by multiplying by the intervals in an hour
+  let b0 = config.battery_initial_charge * iph; // instead of MWh we have MW-intervals
+  let b_final = config.battery_final_charge * iph;
+  let with_reserve = data.iter().any(|d| d.min_charge(config) > 0.0);
+  debug!("b0: {b0}");
+  let discharge_efficiency = config.discharge_efficiency();
+  let decay = config.decay_per_interval();
+  let fade = |intervals: usize| decay.powi(intervals as i32);
+  let count_export = count_exports(data, config);
+  let overload = |d: &Data| (d.power - d.max_consumption(config)) / discharge_efficiency;
+  let mut limits = Vec::new();
+
+  // the max charge, nothing when charging is not allowed and at most the forced charge
+  for d in data.iter().filter(|d| !d.is_overload(config)) {
+    let max_charge = config.battery_max_charge.min(d.max_consumption(config) - d.power);
+    limits.push(if d.allow_charge { max_charge.min(d.forced_charge.unwrap_or(f64::INFINITY)) } else { 0.0 });
+  }
+  // the capacity, what the overloads so far took out of the battery can be charged again
+  let mut discharge = 0.0;
+  for (i, d) in data.iter().enumerate() {
+    discharge *= decay;
+    if d.is_overload(config) {
+      discharge += overload(d);
+    } else {
+      limits.push(d.battery_capacity(config) * iph + discharge - b0 * fade(i + 1));
+    }
+  }
+  // the discharge, for the overloads or for every interval with exports or a minimum charge
+  let mut discharge = 0.0;
+  for (i, d) in data.iter().enumerate() {
+    discharge *= decay;
+    if d.is_overload(config) {
+      discharge += overload(d);
+    }
+    if d.is_overload(config) || count_export > 0 || with_reserve {
+      limits.push(discharge - b0 * fade(i + 1) + d.min_charge(config) * iph);
+    }
+  }
+  // the final charge
+  limits.push(b_final - b0 * fade(data.len()) + discharge);
+  // the export limit, no export when discharging is not allowed or forced. With a discharge limit an overload comes first
+  for d in data.iter().take(count_export) {
+    limits.push(match config.battery_max_discharge {
+      _ if !d.allow_discharge || d.forced_discharge.is_some() => 0.0,
+      Some(max_discharge) => max_discharge - (d.power - d.max_consumption(config)).max(0.0),
+      None => config.battery_max_charge,
+    });
+  }
+  // the discharge to the consumers, a forced discharge is all of it
+  for d in data.iter().take(count_export) {
+    limits.push(if d.forced_discharge.is_some() { 0.0 } else { d.power.min(d.max_consumption(config)) });
+  }
+  // the peak, the battery covers everything above the limit
+  if config.objective == Objective::MinimisePeak || config.demand_charge_per_kw > 0.0 {
+    limits.extend(data.iter().map(|d| d.power.min(d.max_consumption(config))));
+  }
+  // the minimum grid power
+  if config.min_grid_power > 0.0 {
+    limits.extend(data.iter().map(|d| config.min_grid_power - d.power.min(d.max_consumption(config))));
+  }
+  // the import quota
+  if let Some(quota) = config.daily_import_quota_wh {
+    let consumption: f64 = data.iter().map(|d| d.power.min(d.max_consumption(config))).sum();
+    limits.push(quota * iph - consumption);
+  }
+  // the forced charges
+  limits.extend(data.iter().filter(|d| !d.is_overload(config)).filter_map(|d| d.forced_charge));
+  // the ramps, up and down
+  if let Some(ramp) = config.max_ramp_per_interval {
+    limits.extend(ramp_pairs(data, config).iter().flat_map(|_| [ramp, ramp]));
+  }
+  limits
+}
+
+// the charges with a ramp from the one before, by their index among the charges, only when the ramp is limited.
+// The interval before has to be an underload as well, an overload in between breaks the ramp
+fn ramp_pairs(data: &[Data], config: &Config) -> Vec<usize> {
+  if config.max_ramp_per_interval.is_none() {
+    return Vec::new();
+  }
+  let x_intervals: Vec<usize> = data.iter().enumerate().filter(|(_, d)| !d.is_overload(config)).map(|(i, _)| i).collect();
+  (1..x_intervals.len()).filter(|&k| x_intervals[k] == x_intervals[k - 1] + 1).collect()
+}
+
+// a row is negated when its limit is negative, with its slack and artificial turned around. The rows that are
+// never negated have a limit that can not be negative
+fn is_negated(kind: ConstraintKind, limit: f64) -> bool {
+  match kind {
+    ConstraintKind::Capacity | ConstraintKind::Discharge | ConstraintKind::FinalCharge | ConstraintKind::ImportQuota => {
+      limit < 0.0
+    }
+    // the minimum has an artificial only when the grid has to give more than the consumption
+    ConstraintKind::MinGrid => limit <= 0.0,
+    _ => false,
+  }
+}
+
+/// The tableau of a single battery built once and filled again for inputs with the same structure, the re-plans
+/// where only the consumption, the prices or the charges changed. The coefficients are kept, only the limits and
+/// the objective are written again.
+pub struct TableauTemplate {
+  tableau: Vec<Vec<f64>>,
+  variables: usize,
+  artificials: usize,
+  kinds: Vec<ConstraintKind>,
+  structure: Structure,
+}
+
+// what the coefficients depend on, inputs with the same structure have the same tableau but for the limits and the objective
+#[derive(Debug, PartialEq)]
+struct Structure {
+  overloads: Vec<bool>,
+  negated: Vec<bool>,
+  exports: bool,
+  charge_efficiency: f64,
+  discharge_efficiency: f64,
+  decay: f64,
+  objective: Objective,
+  with_peak: bool,
+  with_reserve: bool,
+  with_min_grid: bool,
+  with_quota: bool,
+  with_penalty: bool,
+  forced_charges: Vec<bool>,
+  with_ramp: bool,
+}
+
+impl Structure {
+  fn of(data: &[Data], config: &Config, kinds: &[ConstraintKind], limits: &[f64]) -> Self {
+    Structure {
+      overloads: data.iter().map(|d| d.is_overload(config)).collect(),
+      negated: kinds.iter().zip(limits).map(|(&kind, &limit)| is_negated(kind, limit)).collect(),
+      exports: count_exports(data, config) > 0,
+      charge_efficiency: config.charge_efficiency(),
+      discharge_efficiency: config.discharge_efficiency(),
+      decay: config.decay_per_interval(),
+      objective: config.objective,
+      with_peak: config.demand_charge_per_kw > 0.0,
+      with_reserve: data.iter().any(|d| d.min_charge(config) > 0.0),
+      with_min_grid: config.min_grid_power > 0.0,
+      with_quota: config.daily_import_quota_wh.is_some(),
+      with_penalty: config.final_charge_penalty.is_some(),
+      forced_charges: data.iter().map(|d| d.forced_charge.is_some()).collect(),
+      with_ramp: config.max_ramp_per_interval.is_some(),
+    }
+  }
+}
+
+impl TableauTemplate {
+  pub fn new(data: &[Data], config: &Config) -> Self {
+    let (tableau, variables, artificials, kinds) = build_tableau_with_kinds(data, config);
+    let structure = Structure::of(data, config, &kinds, &constraint_limits(data, config));
+    TableauTemplate { tableau, variables, artificials, kinds, structure }
+  }
+
+  /// The tableau for new inputs, the same as `build_tableau` would make. None when the structure changed,
+  /// an interval is overloaded now or a limit changed its sign, a new template is needed then.
+  /// Several batteries, an efficiency curve and the self consumption always need a new tableau.
+  pub fn fill(&self, data: &[Data], config: &Config) -> Option<Matrix> {
+    if !config.batteries.is_empty()
+      || !config.efficiency_segments.is_empty()
+      || config.objective == Objective::MaxSelfConsumption
+      || data.len() != self.structure.overloads.len()
+    {
+      return None;
+    }
+    let limits = constraint_limits(data, config);
+    if limits.len() != self.kinds.len() || Structure::of(data, config, &self.kinds, &limits) != self.structure {
+      return None;
+    }
+    let mut tableau = self.tableau.clone();
+    let rhs = tableau[0].len() - 1;
+    let artificials = rhs - self.artificials..rhs;
+    // the intermediate goal is the sum of the rows with an artificial
+    let mut intermediate = 0.0;
+    for ((row, limit), negated) in tableau.iter_mut().zip(limits).zip(&self.structure.negated) {
+      row[rhs] = if *negated { -limit } else { limit };
+      if row[artificials.clone()].iter().any(|&a| a != 0.0) {
+        intermediate += row[rhs];
+      }
+    }
+    let constraints = self.kinds.len();
+    tableau[constraints][..self.variables].copy_from_slice(&objective(data, config));
+    tableau[constraints + 1][rhs] = intermediate;
+    Some(Matrix::new(tableau, self.variables, self.artificials))
+  }
+}
+
+/// Every interval has an export and a discharge to the consumers when energy can be sold, none otherwise
+pub fn count_exports(data: &[Data], config: &Config) -> usize {
+  if data.iter().any(|d| d.sell_price(config).is_some()) {
+    data.len()
+  } else {
+    0
+  }
+}
+
+/// The variables of battery `b` in the tableau of several batteries, one for every interval:
+/// the charge in an underload interval, the discharge in an overload interval
+pub fn battery_columns(data: &[Data], b: usize) -> std::ops::Range<usize> {
+  b * data.len()..(b + 1) * data.len()
+}
+
+/// The tableau for several batteries. Every battery charges in the underload intervals and discharges in the
+/// overload intervals, what it discharges is now a variable as the batteries share the overloads:
+/// 1. for every underload interval the charge of every battery is below its rate and
+///    the batteries together charge below what the grid has left
+/// 2. for every overload interval the discharge of every battery is below its rate, when it has one, and
+///    the batteries together cover the overload, an equality
+/// 3. for every battery, interval by interval, it holds at most its capacity after charging and
+///    at least nothing after discharging, then it holds its final charge at the end
+/// 4. the cost of the charging, the price and the wear of the battery
+/// 5. intermediate goal
+///
+/// The constraints are collected first, the slacks and the artificials are added once their number is known.
+///
+/// The segments of an efficiency curve are `pooled` batteries, the parts of a single battery. They share its
+/// rates and its final charge: one row limits their charge together, one row holds their final charge, and the
+/// overload rows already cover the discharge rate, `check_overloads` rejects an overload above it.
+fn build_batteries_tableau(
+  data: &[Data],
+  config: &Config,
+  batteries: &[Battery],
+  pooled: bool,
+) -> (Vec<Vec<f64>>, usize, usize, Vec<Row>) {
+  let iph = config.intervals_per_hour;
+  let overload = |d: &Data| (d.power - d.max_consumption(config)).max(0.0);
+  // one variable per battery and interval, the charge for an underload and the discharge for an overload
+  let num_vars = batteries.len() * data.len();
+  let column = |b: usize, i: usize| battery_columns(data, b).start + i;
+
+  // the left hand side, the sign of the constraint and the limit
+  let mut constraints: Vec<(Vec<f64>, Ordering, f64, Row)> = Vec::new();
+  let mut constraint = |kind, interval, ordering, limit, terms: &[(usize, f64)]| {
+    let mut equation = vec![0.0; num_vars];
+    for &(col, coefficient) in terms {
+      equation[col] = coefficient;
+    }
+    constraints.push((equation, ordering, limit, (kind, interval)));
+  };
+  for (i, d) in data.iter().enumerate().filter(|(_, d)| overload(d) == 0.0) {
+    let mut limit = d.max_consumption(config) - d.power;
+    if pooled {
+      limit = limit.min(if d.allow_charge { config.battery_max_charge } else { 0.0 });
+    } else {
+      for (b, battery) in batteries.iter().enumerate() {
+        let limit = if d.allow_charge { battery.max_charge } else { 0.0 };
+        constraint(ConstraintKind::MaxCharge, i, Ordering::Less, limit, &[(column(b, i), 1.0)]);
+      }
+    }
+    let terms: Vec<_> = (0..batteries.len()).map(|b| (column(b, i), 1.0)).collect();
+    constraint(ConstraintKind::MaxCharge, i, Ordering::Less, limit.min(d.forced_charge.unwrap_or(f64::INFINITY)), &terms);
+    if let Some(forced) = d.forced_charge {
+      constraint(ConstraintKind::ForcedCharge, i, Ordering::Greater, forced, &terms);
+    }
+  }
+  for (i, d) in data.iter().enumerate().filter(|(_, d)| overload(d) > 0.0) {
+    for (b, battery) in batteries.iter().enumerate().filter(|_| !pooled) {
+      match battery.max_discharge {
+        _ if !d.allow_discharge => constraint(ConstraintKind::DischargeRate, i, Ordering::Less, 0.0, &[(column(b, i), 1.0)]),
+        Some(max_discharge) => {
+          constraint(ConstraintKind::DischargeRate, i, Ordering::Less, max_discharge, &[(column(b, i), 1.0)])
+        }
+        None => (),
+      }
+    }
+    let terms: Vec<_> = (0..batteries.len()).map(|b| (column(b, i), 1.0)).collect();
+    constraint(ConstraintKind::Overload, i, Ordering::Equal, overload(d), &terms);
+  }
+  // the charge of a battery after interval i, less its initial charge
+  let stored = |b: usize, i: usize| -> Vec<(usize, f64)> {
+    let battery = &batteries[b];
+    data[..=i]
+      .iter()
+      .enumerate()
+      .map(|(j, d)| {
+        if overload(d) > 0.0 {
+          (column(b, j), -1.0 / battery.discharge_efficiency)
+        } else {
+          (column(b, j), battery.charge_efficiency)
+        }
+      })
+      .collect()
+  };
+  let last = data.len().saturating_sub(1);
+  for (b, battery) in batteries.iter().enumerate() {
+    for (i, d) in data.iter().enumerate() {
+      if overload(d) == 0.0 {
+        let limit = (battery.capacity - battery.initial_charge) * iph;
+        constraint(ConstraintKind::Capacity, i, Ordering::Less, limit, &stored(b, i));
+      } else {
+        constraint(ConstraintKind::Discharge, i, Ordering::Greater, -battery.initial_charge * iph, &stored(b, i));
+      }
+    }
+    if !pooled {
+      let limit = (battery.final_charge - battery.initial_charge) * iph;
+      constraint(ConstraintKind::FinalCharge, last, Ordering::Greater, limit, &stored(b, last));
+    }
+  }
+  if pooled {
+    let limit = (config.battery_final_charge - config.battery_initial_charge) * iph;
+    let terms: Vec<_> = (0..batteries.len()).flat_map(|b| stored(b, last)).collect();
+    constraint(ConstraintKind::FinalCharge, last, Ordering::Greater, limit, &terms);
+  }
+
+  // the cost of the charging, the discharges are free
+  let mut objective: Vec<f64> = vec![0.0; num_vars];
+  for (i, d) in data.iter().enumerate().filter(|(_, d)| overload(d) == 0.0) {
+    for (b, battery) in batteries.iter().enumerate() {
+      objective[column(b, i)] = -(config.grid_price(d.price) + battery.cycle_cost_per_kwh);
+    }
+  }
+  assemble(num_vars, constraints, objective)
+}
+
+/// A block of variables of the self consumption tableau, one variable for every interval
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+  Charge,
+  Discharge,
+  Import,
+  Export,
+}
+
+/// The variables of a flow in the tableau of the self consumption, in W
+pub fn flow_columns(data: &[Data], flow: Flow) -> std::ops::Range<usize> {
+  let block = flow as usize;
+  block * data.len()..(block + 1) * data.len()
+}
+
+/// The tableau of the max_self_consumption objective, a single battery at a site with its own generation.
+/// Every interval has a variable for each `Flow`, the charge and the discharge of the battery, the import from
+/// the grid and the export to it:
+/// 1. for every interval the charge and the discharge are below their rates, the import below the grid limit
+///    and the import less the export is the consumption less the generation, with the battery, an equality
+/// 2. for every interval the battery holds at most its capacity and at least its minimum charge,
+///    then it holds its final charge at the end
+/// 3. the import and the export, the less energy goes through the grid the more of the generation is used on site
+/// 4. intermediate goal
+fn build_self_consumption_tableau(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, usize, Vec<Row>) {
+  let iph = config.intervals_per_hour;
+  let b0 = config.battery_initial_charge * iph;
+  let decay = config.decay_per_interval();
+  let fade = |intervals: usize| decay.powi(intervals as i32);
+  let num_vars = 4 * data.len();
+  let column = |flow: Flow, i: usize| flow_columns(data, flow).start + i;
+
+  let mut constraints: Vec<(Vec<f64>, Ordering, f64, Row)> = Vec::new();
+  let mut constraint = |kind, interval, ordering, limit, terms: &[(usize, f64)]| {
+    let mut equation = vec![0.0; num_vars];
+    for &(col, coefficient) in terms {
+      equation[col] = coefficient;
+    }
+    constraints.push((equation, ordering, limit, (kind, interval)));
+  };
+  for (i, d) in data.iter().enumerate() {
+    let max_charge = if d.allow_charge { config.battery_max_charge } else { 0.0 };
+    constraint(ConstraintKind::MaxCharge, i, Ordering::Less, max_charge, &[(column(Flow::Charge, i), 1.0)]);
+    match config.battery_max_discharge {
+      _ if !d.allow_discharge => {
+        constraint(ConstraintKind::DischargeRate, i, Ordering::Less, 0.0, &[(column(Flow::Discharge, i), 1.0)])
+      }
+      Some(max_discharge) => {
+        constraint(ConstraintKind::DischargeRate, i, Ordering::Less, max_discharge, &[(column(Flow::Discharge, i), 1.0)])
+      }
+      None => (),
+    }
+    constraint(ConstraintKind::GridLimit, i, Ordering::Less, d.max_consumption(config), &[(column(Flow::Import, i), 1.0)]);
+    let terms = [
+      (column(Flow::Import, i), 1.0),
+      (column(Flow::Export, i), -1.0),
+      (column(Flow::Charge, i), -1.0),
+      (column(Flow::Discharge, i), 1.0),
+    ];
+    constraint(ConstraintKind::Balance, i, Ordering::Equal, d.net_power(), &terms);
+  }
+  // the charge of the battery after interval i, less its initial charge
+  let (charge_efficiency, discharge_efficiency) = (config.charge_efficiency(), config.discharge_efficiency());
+  let stored = |i: usize| -> Vec<(usize, f64)> {
+    (0..=i)
+      .flat_map(|j| {
+        [
+          (column(Flow::Charge, j), charge_efficiency * fade(i - j)),
+          (column(Flow::Discharge, j), -fade(i - j) / discharge_efficiency),
+        ]
+      })
+      .collect()
+  };
+  for (i, d) in data.iter().enumerate() {
+    constraint(ConstraintKind::Capacity, i, Ordering::Less, d.battery_capacity(config) * iph - b0 * fade(i + 1), &stored(i));
+    constraint(ConstraintKind::Discharge, i, Ordering::Greater, d.min_charge(config) * iph - b0 * fade(i + 1), &stored(i));
+  }
+  let last = data.len().saturating_sub(1);
+  constraint(
+    ConstraintKind::FinalCharge,
+    last,
+    Ordering::Greater,
+    config.battery_final_charge * iph - b0 * fade(data.len()),
+    &stored(last),
+  );
+
+  let mut objective = vec![0.0; num_vars];
+  for flow in [Flow::Import, Flow::Export] {
+    objective[flow_columns(data, flow)].fill(-1.0);
+  }
+  assemble(num_vars, constraints, objective)
+}
+
+// The tableau of collected constraints, each with its left hand side over the variables, its sign and its limit.
+// `objective` is the objective row over the variables, the negated costs.
+fn assemble(
+  num_vars: usize,
+  constraints: Vec<(Vec<f64>, Ordering, f64, Row)>,
+  objective: Vec<f64>,
+) -> (Vec<Vec<f64>>, usize, usize, Vec<Row>) {
+  // with a positive limit a <= gets a slack, a >= a negative slack and an artificial, an = only an artificial
+  let constraints: Vec<_> = constraints
+    .into_iter()
+    .map(|(equation, ordering, limit, kind)| {
+      if limit < 0.0 {
+        (equation.iter().map(|&v| if v != 0.0 { -v } else { 0.0 }).collect(), ordering.reverse(), -limit, kind)
+      } else {
+        (equation, ordering, limit, kind)
+      }
+    })
+    .collect();
+  let num_s = constraints.iter().filter(|(_, ordering, _, _)| *ordering != Ordering::Equal).count();
+  let num_a = constraints.iter().filter(|(_, ordering, _, _)| *ordering != Ordering::Less).count();
+  let cols = num_vars + num_s + num_a + 1;
+  let mut result: Vec<Vec<f64>> = Vec::with_capacity(constraints.len() + 2);
+  let mut kinds = Vec::with_capacity(constraints.len());
+  let mut intermediate: Vec<f64> = vec![0.0; cols];
+  let (mut s_offset, mut a_offset) = (num_vars, num_vars + num_s);
+  for (coefficients, ordering, limit, kind) in constraints {
+    let mut equation: Vec<f64> = vec![0.0; cols];
+    equation[..num_vars].copy_from_slice(&coefficients);
+    equation[cols - 1] = limit;
+    if ordering != Ordering::Equal {
+      // the s
+      equation[s_offset] = if ordering == Ordering::Less { 1.0 } else { -1.0 };
+      s_offset += 1;
+    }
+    if ordering != Ordering::Less {
+      for (t, v) in intermediate.iter_mut().zip(&equation) {
+        *t += v;
+      }
+      // the a
+      equation[a_offset] = 1.0;
+      a_offset += 1;
+    }
+    result.push(equation);
+    kinds.push(kind);
+  }
+  let mut equation: Vec<f64> = vec![0.0; cols];
+  equation[..num_vars].copy_from_slice(&objective);
+  result.push(equation);
+  result.push(intermediate);
+  (result, num_vars, num_a, kinds)
 }
 
 // test module
@@ -180,7 +987,7 @@ pub fn build_tableau(data: &[Data], config: &Config) -> (Vec<Vec<f64>>, usize, u
 mod tests {
 
   use super::*;
-  use crate::tests::init;
+  use crate::tests::{config, init};
   use chrono::Utc;
   use log::info;
 
@@ -190,19 +997,97 @@ mod tests {
     let start = Utc::now();
     let end = Utc::now();
     let data = vec![
-      Data { start, end, power: 0.0, price: 1.0 },
-      Data { start, end, power: 3.0, price: 2.0 },
-      Data { start, end, power: 1.0, price: 2.0 },
-      Data { start, end, power: 3.0, price: 1.0 },
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 1.0, ..Default::default() },
+    ];
+    let config = Config { battery_initial_charge: 1.5 / 4.0, battery_efficiency: 0.9, ..config() };
+    let (result, v, a) = build_tableau(&data, &config);
+    for r in result.iter() {
+      info!("{:?}", r);
+    }
+    assert_eq!(v, 2);
+    assert_eq!(a, 2);
+    assert_eq!(
+      result,
+      [
+        //x1  x2   s1   s2   s3   s4   s5   s6   s7   a1   a2   limit
+        [1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.5], // cap on charge x1
+        [0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0], // cap on charge x2
+        [0.9, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.5], // max battery x1
+        [0.9, 0.9, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.5], // max battery x2
+        // b0 + e*x1 >= o1 -> 1.5 +0.9 *x1 >= 1 -> 0.9 * x1 >= -0.5
+        // -> -0.9 * x1 < 0.5 -> -0.9 *x1 + s5 = 0.5
+        [-0.9, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.5], // enough power o1
+        [0.9, 0.9, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0, 0.5], // enough power o2
+        [0.9, 0.9, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.5], // final battery                                                    //
+        [-1.0, -2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0], // total price
+        [1.8, 1.8, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0, -1.0, 0.0, 0.0, 1.0]  // intermediate
+      ]
+    );
+  }
+
+  #[test]
+  fn template_fills_like_a_new_tableau() {
+    init();
+    let start = Utc::now();
+    let end = Utc::now();
+    let mut data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, sell_price: Some(1.5), ..Default::default() },
+      Data { start, end, power: 3.0, price: 1.0, ..Default::default() },
     ];
-    let config = Config {
-      max_consumption: 2.0,
-      battery_capacity: 2.0 / 4.0,
-      battery_max_charge: 1.5,
+    let mut config = Config {
       battery_initial_charge: 1.5 / 4.0,
       battery_efficiency: 0.9,
-      battery_final_charge: 0.0,
+      final_charge_penalty: Some(3.0),
+      feed_in_price: Some(0.5),
+      self_discharge_per_hour: 0.01,
+      min_charge: 0.05,
+      cycle_cost_per_kwh: 0.1,
+      demand_charge_per_kw: 0.2,
+      min_grid_power: 0.5,
+      daily_import_quota_wh: Some(10.0),
+      ..config()
+    };
+    let template = TableauTemplate::new(&data, &config);
+    let fresh = |data: &[Data], config: &Config| {
+      let (tableau, variables, artificials) = build_tableau(data, config);
+      Matrix::new(tableau, variables, artificials)
     };
+    assert_eq!(template.fill(&data, &config), Some(fresh(&data, &config)));
+
+    // the next plan, other prices, a bit more consumption and what is left in the battery now
+    for (d, price) in data.iter_mut().zip([1.5, 0.5, 3.0, 2.5]) {
+      d.price = price;
+      d.power += 0.1;
+    }
+    config.battery_initial_charge = 1.4 / 4.0;
+    config.feed_in_price = Some(0.4);
+    config.grid_price_markup = 0.2;
+    assert_eq!(template.fill(&data, &config), Some(fresh(&data, &config)));
+
+    // a new overload changes the structure
+    data[2].power = 2.5;
+    assert_eq!(template.fill(&data, &config), None);
+  }
+
+  #[test]
+  fn test_build_tableau_hourly_intervals() {
+    init();
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 1.0, ..Default::default() },
+    ];
+    let config =
+      Config { battery_capacity: 2.0, battery_initial_charge: 1.5, battery_efficiency: 0.9, intervals_per_hour: 1.0, ..config() };
+    // with hourly intervals the battery energy is the same as the power over one interval
     let (result, v, a) = build_tableau(&data, &config);
     for r in result.iter() {
       info!("{:?}", r);
@@ -227,4 +1112,31 @@ mod tests {
       ]
     );
   }
+
+  #[test]
+  fn test_build_tableau_with_min_charge() {
+    init();
+    let start = Utc::now();
+    let end = Utc::now();
+    let data = vec![
+      Data { start, end, power: 0.0, price: 1.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 1.0, price: 2.0, ..Default::default() },
+      Data { start, end, power: 3.0, price: 1.0, ..Default::default() },
+    ];
+    let config = Config { battery_initial_charge: 1.5 / 4.0, battery_efficiency: 0.9, min_charge: 0.25 / 4.0, ..config() };
+    let (result, v, a) = build_tableau(&data, &config);
+    for r in result.iter() {
+      info!("{:?}", r);
+    }
+    // 2 max charge, 2 capacity, 4 minimum charge, 1 final, the price and the intermediate goal
+    assert_eq!(result.len(), 11);
+    assert_eq!(v, 2);
+    // b0 + e*(x1 + x2) >= o1 + o2 + min and the final charge need artificials
+    assert_eq!(a, 2);
+    // x, 9 s, a and the limit
+    assert!(result.iter().all(|r| r.len() == 2 + 9 + 2 + 1));
+    // b0 + e*x1 >= min -> -0.9 * x1 + s5 = 1.5 - 0.25
+    assert_eq!(result[4], [-0.9, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.25]);
+  }
 }
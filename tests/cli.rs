@@ -0,0 +1,299 @@
+use assert_cmd::Command;
+use battery_optimisation::data::Out;
+
+fn command(args: &[&str]) -> Command {
+  let mut command = Command::cargo_bin("battery-optimisation").unwrap();
+  command.args(args).env_remove("RUST_LOG");
+  command
+}
+
+// the exit code and the JSON error written to stderr
+fn run(args: &[&str]) -> (Option<i32>, serde_json::Value) {
+  let output = command(args).output().unwrap();
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  let error = serde_json::from_str(stderr.trim()).unwrap_or(serde_json::Value::Null);
+  (output.status.code(), error)
+}
+
+#[test]
+fn missing_file() {
+  let (code, error) = run(&["-c", "does-not-exist.json"]);
+  assert_eq!(code, Some(3));
+  assert_eq!(error["kind"], "io");
+  assert_eq!(error["code"], 3);
+}
+
+#[test]
+fn bad_file() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path().join("consumption.json");
+  std::fs::write(&path, "{ not json").unwrap();
+  let (code, error) = run(&["-c", path.to_str().unwrap()]);
+  assert_eq!(code, Some(4));
+  assert_eq!(error["kind"], "parse");
+  assert!(error["error"].as_str().unwrap().contains("consumption.json"));
+}
+
+#[test]
+fn invalid_config() {
+  let (code, error) = run(&["--set", "battery_capacity=-1"]);
+  assert_eq!(code, Some(5));
+  assert_eq!(error["kind"], "validation");
+}
+
+#[test]
+fn log_levels() {
+  let stderr = |command: &mut Command| String::from_utf8(command.output().unwrap().stderr).unwrap();
+  assert!(stderr(&mut command(&["-v"])).contains("INFO"));
+  assert!(stderr(command(&["-q"]).env("RUST_LOG", "info")).is_empty());
+  assert!(stderr(&mut command(&[])).is_empty());
+}
+
+#[test]
+fn solve_is_the_default() {
+  let stdout = |args: &[&str]| String::from_utf8(command(args).output().unwrap().stdout).unwrap();
+  let plan: serde_json::Value = serde_json::from_str(&stdout(&["solve"])).unwrap();
+  assert_eq!(plan["planning"].as_array().unwrap().len(), 96);
+  assert_eq!(stdout(&["solve"]), stdout(&[]));
+}
+
+#[test]
+fn validate() {
+  let assert = command(&["validate"]).assert().success();
+  assert!(String::from_utf8_lossy(&assert.get_output().stdout).starts_with("96 intervals from"));
+  let (code, error) = run(&["validate", "--set", "battery_capacity=-1"]);
+  assert_eq!(code, Some(5));
+  assert_eq!(error["kind"], "validation");
+}
+
+#[test]
+fn config_from_environment() {
+  let dir = tempfile::tempdir().unwrap();
+  let missing = dir.path().join("config.toml");
+  let validate = |vars: &[(&str, &str)], args: &[&str]| {
+    let mut command = command(&["validate", "-i", missing.to_str().unwrap()]);
+    command.args(args).envs(vars.iter().copied());
+    command.assert()
+  };
+  // the toml file is missing, the configuration comes from the environment and --set wins over it
+  let vars = [("MAX_CONSUMPTION", "7850000"), ("BATTERY_CAPACITY", "500000"), ("BATTERY_MAX_CHARGE", "400000")];
+  validate(&vars, &[]).success();
+  validate(&vars, &["--set", "battery_capacity=-1"]).code(5);
+  // nothing in the environment either
+  validate(&[], &[]).code(3);
+}
+
+#[test]
+fn max_switches() {
+  let switches = |args: &[&str]| {
+    let output = command(args).assert().success().get_output().clone();
+    let out: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    out["summary"]["mode_switches"].as_u64().unwrap()
+  };
+  let count = switches(&[]);
+  assert!(count > 0);
+  assert_eq!(switches(&["--max-switches", &count.to_string()]), count);
+  let (code, error) = run(&["--max-switches", &(count - 1).to_string()]);
+  assert_eq!(code, Some(6));
+  assert_eq!(error["kind"], "infeasible");
+}
+
+#[test]
+fn energy_unit() {
+  let out = |args: &[&str]| -> serde_json::Value {
+    let output = command(args).assert().success().get_output().clone();
+    serde_json::from_slice(&output.stdout).unwrap()
+  };
+  let wh = out(&[]);
+  let kwh = out(&["--energy-unit", "kwh"]);
+  assert_eq!(kwh["summary"]["energy_unit"], "kWh");
+  let close = |wh: &serde_json::Value, kwh: &serde_json::Value| {
+    let (wh, kwh) = (wh.as_f64().unwrap(), kwh.as_f64().unwrap());
+    assert!((wh / 1000.0 - kwh).abs() < 1e-9, "{wh} Wh is not {kwh} kWh");
+  };
+  for field in ["total_charged_wh", "total_discharged_wh", "final_charge_shortfall_wh"] {
+    close(&wh["summary"][field], &kwh["summary"][field]);
+  }
+  assert_eq!(wh["summary"]["optimised_cost"], kwh["summary"]["optimised_cost"]);
+  let plans = wh["planning"].as_array().unwrap().iter().zip(kwh["planning"].as_array().unwrap());
+  for (wh, kwh) in plans {
+    for field in
+      ["energy_from_battery_wh", "energy_to_battery_wh", "energy_exported_wh", "energy_imported_wh", "battery_charge_wh"]
+    {
+      close(&wh[field], &kwh[field]);
+    }
+  }
+  // the unit of the config converts the same
+  assert_eq!(out(&["--set", "energy_unit=kWh"]), kwh);
+
+  // the table too, the unit in the headers
+  let table = |args: &[&str]| {
+    let output = command(&[&["--summary"], args].concat()).assert().success().get_output().clone();
+    String::from_utf8(output.stdout).unwrap()
+  };
+  let totals =
+    |table: &str| -> Vec<f64> { table.lines().last().unwrap().split_whitespace().skip(1).map(|v| v.parse().unwrap()).collect() };
+  let (wh, kwh) = (table(&[]), table(&["--energy-unit", "kwh"]));
+  assert!(wh.lines().next().unwrap().contains("charge_wh"));
+  assert!(kwh.lines().next().unwrap().contains("charge_kwh"));
+  for (wh, kwh) in totals(&wh).into_iter().zip(totals(&kwh)) {
+    assert!((wh / 1000.0 - kwh).abs() < 0.01, "{wh} Wh is not {kwh} kWh");
+  }
+}
+
+#[test]
+fn explain() {
+  let stdout = |args: &[&str]| {
+    let output = command(args).assert().success().get_output().clone();
+    String::from_utf8(output.stdout).unwrap()
+  };
+  assert!(stdout(&["explain"]).starts_with("There is a plan"));
+  // without a grid the battery has to cover the whole consumption
+  let explanation = stdout(&["explain", "--set", "max_consumption=0"]);
+  assert!(explanation.starts_with("There is no plan, the FinalCharge constraint"), "{explanation}");
+}
+
+#[test]
+fn same_plan_on_every_run() {
+  // flat prices, the battery can charge in any of the first three intervals for the overload of the last,
+  // every choice costs the same and the tie breaking picks the same one every time
+  let interval = |i: i64| (minutes_after_midnight(i * 15), minutes_after_midnight(i * 15 + 15));
+  let consumption: Vec<_> = (0..4)
+    .map(|i| {
+      let power = if i == 3 { 3.0 } else { 0.0 };
+      serde_json::json!({ "start": interval(i).0, "end": interval(i).1, "consumption_average_power_interval": power })
+    })
+    .collect();
+  let prices: Vec<_> =
+    (0..4).map(|i| serde_json::json!({ "start": interval(i).0, "end": interval(i).1, "market_price_per_kwh": 1.0 })).collect();
+  let config = serde_json::json!({
+    "max_consumption": 2.0,
+    "battery_capacity": 1.0,
+    "battery_max_charge": 1.5,
+    "battery_initial_charge": 0.0,
+    "battery_efficiency": 1.0,
+    "battery_final_charge": 0.0
+  });
+  let input = serde_json::json!({ "consumption": consumption, "prices": prices, "config": config }).to_string();
+  let plan = || {
+    let assert = command(&["--batch"]).write_stdin(input.as_str()).assert().success();
+    assert.get_output().stdout.clone()
+  };
+  let first = plan();
+  assert!(!first.is_empty());
+  (0..5).for_each(|_| assert_eq!(plan(), first));
+}
+
+// the time the given minutes after midnight of 2024-01-01
+fn minutes_after_midnight(minutes: i64) -> String {
+  format!("2024-01-01T{:02}:{:02}:00Z", minutes / 60, minutes % 60)
+}
+
+// two hours of quarters with an overload in the expensive second hour, the files as the binary reads them
+fn write_fixtures(dir: &std::path::Path, battery_capacity: f64) -> [String; 3] {
+  let forecasts: Vec<_> = (0..8)
+    .map(|i| {
+      serde_json::json!({
+        "start": minutes_after_midnight(i * 15),
+        "end": minutes_after_midnight(i * 15 + 15),
+        "consumption_average_power_interval": if i == 6 { 3000.0 } else { 1000.0 },
+      })
+    })
+    .collect();
+  let prices: Vec<_> = (0..2)
+    .map(|i| {
+      serde_json::json!({
+        "start": minutes_after_midnight(i * 60),
+        "end": minutes_after_midnight(i * 60 + 60),
+        "market_price_per_kwh": 0.1 * (i + 1) as f64,
+      })
+    })
+    .collect();
+  let config = format!(
+    r#"max_consumption = 2000
+battery_capacity = {battery_capacity}
+battery_max_charge = 1000
+battery_initial_charge = 0
+battery_final_charge = 0
+"#
+  );
+  let files = [
+    ("consumption.json", serde_json::json!({ "forecasts": forecasts }).to_string()),
+    ("prices.json", serde_json::json!({ "prices": prices }).to_string()),
+    ("config.toml", config),
+  ];
+  files.map(|(name, content)| {
+    let path = dir.join(name);
+    std::fs::write(&path, content).unwrap();
+    path.to_str().unwrap().to_string()
+  })
+}
+
+#[test]
+fn plan_from_fixture_files() {
+  let dir = tempfile::tempdir().unwrap();
+  let [consumption, prices, config] = write_fixtures(dir.path(), 1000.0);
+  let output = command(&["-c", &consumption, "-p", &prices, "-i", &config]).assert().success().get_output().clone();
+  let out: Out = serde_json::from_slice(&output.stdout).unwrap();
+  assert_eq!(out.planning.len(), 8);
+  // the overload of 1000 W is covered by the battery, charged in the cheap first hour
+  assert!((out.planning[6].energy_from_battery_wh - 250.0).abs() < 1e-6);
+  assert!(out.planning[..4].iter().map(|p| p.energy_to_battery_wh).sum::<f64>() > 250.0 - 1e-6);
+  for plan in &out.planning {
+    assert!(plan.battery_charge_wh > -1e-6 && plan.battery_charge_wh < 1000.0 + 1e-6);
+    assert!(plan.energy_imported_wh <= 2000.0 / 4.0 + 1e-6);
+  }
+  assert!(out.summary.savings > 0.0);
+}
+
+#[test]
+fn debug_dump() {
+  let dir = tempfile::tempdir().unwrap();
+  let [consumption, prices, config] = write_fixtures(dir.path(), 1000.0);
+  let path = dir.path().join("dump.json");
+  command(&["-c", &consumption, "-p", &prices, "-i", &config, "--debug-dump", path.to_str().unwrap()]).assert().success();
+  let dump: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+  // the solver of the plan at its optimum, with the config of the run
+  assert_eq!(dump["phase"], "Two");
+  assert_eq!(dump["solution"].as_array().unwrap().len(), dump["variables"].as_u64().unwrap() as usize);
+  assert_eq!(dump["config"]["battery_capacity"], 1000.0);
+}
+
+#[test]
+fn infeasible_fixture() {
+  let dir = tempfile::tempdir().unwrap();
+  // the battery can not hold the 250 Wh of the overload
+  let [consumption, prices, config] = write_fixtures(dir.path(), 100.0);
+  let assert = command(&["-c", &consumption, "-p", &prices, "-i", &config]).assert().code(6).stdout("");
+  let error: serde_json::Value = serde_json::from_slice(&assert.get_output().stderr).unwrap();
+  assert_eq!(error["kind"], "infeasible");
+}
+
+#[test]
+fn timezone() {
+  let output = command(&["--timezone", "Europe/Paris", "--start", "2022-12-13T12:00:00", "--end", "2022-12-13T13:00:00"])
+    .assert()
+    .success()
+    .get_output()
+    .clone();
+  let out: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+  let planning = out["planning"].as_array().unwrap();
+  // noon in Paris is 11:00 UTC in the winter, the plan is given back in Paris
+  assert_eq!(planning.len(), 4);
+  assert_eq!(planning[0]["start"], "2022-12-13T12:00:00+01:00");
+  assert_eq!(planning[3]["end"], "2022-12-13T13:00:00+01:00");
+
+  let (code, _) = run(&["--timezone", "Europe/Nowhere"]);
+  assert_eq!(code, Some(2));
+}
+
+#[test]
+fn explain_units() {
+  let output = command(&["--explain-units"]).assert().success().get_output().clone();
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  let lines: Vec<&str> = stdout.lines().collect();
+  assert!(lines[0].starts_with("# 4 intervals per hour"), "{}", lines[0]);
+  // the header and a line for every interval, none of them is out of balance
+  assert_eq!(lines.len(), 2 + 96);
+  assert!(lines[2..].iter().all(|line| line.ends_with(" 0.00") || line.ends_with("-0.00")), "{stdout}");
+}